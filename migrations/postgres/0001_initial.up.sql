@@ -0,0 +1,142 @@
+-- Schema as of the introduction of versioned migrations. IF NOT EXISTS lets
+-- databases created by the old startup code adopt it (see migrate::adopt_legacy).
+-- Integers are BIGINT and timestamps TEXT because sqlx::Any decodes them into
+-- i64 and String.
+
+CREATE TABLE IF NOT EXISTS users (
+    id TEXT PRIMARY KEY,
+    username TEXT NOT NULL UNIQUE,
+    password TEXT NOT NULL,
+    role TEXT NOT NULL DEFAULT 'user',
+    created_at TEXT DEFAULT CAST(CURRENT_TIMESTAMP AS TEXT),
+    email TEXT,
+    email_verified BIGINT NOT NULL DEFAULT 0,
+    totp_secret TEXT,
+    totp_enabled BIGINT NOT NULL DEFAULT 0,
+    totp_last_step BIGINT NOT NULL DEFAULT 0,
+    banned BIGINT NOT NULL DEFAULT 0,
+    ban_reason TEXT NOT NULL DEFAULT '',
+    suspended_until TEXT NOT NULL DEFAULT '',
+    suspension_reason TEXT NOT NULL DEFAULT ''
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_users_email ON users (email);
+
+CREATE TABLE IF NOT EXISTS anime_series (
+    id TEXT PRIMARY KEY,
+    title TEXT NOT NULL,
+    description TEXT,
+    content_type TEXT NOT NULL,
+    status TEXT NOT NULL,
+    schedule_day TEXT,
+    thumbnail_url TEXT,
+    created_at TEXT DEFAULT CAST(CURRENT_TIMESTAMP AS TEXT),
+    rating REAL DEFAULT 0.0
+);
+
+CREATE TABLE IF NOT EXISTS episodes (
+    id TEXT PRIMARY KEY,
+    series_id TEXT NOT NULL,
+    title TEXT NOT NULL,
+    episode_number BIGINT NOT NULL,
+    video_path TEXT NOT NULL,
+    created_at TEXT DEFAULT CAST(CURRENT_TIMESTAMP AS TEXT),
+    FOREIGN KEY(series_id) REFERENCES anime_series(id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS genres (
+    id BIGSERIAL PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE IF NOT EXISTS anime_genres (
+    anime_id TEXT NOT NULL,
+    genre_id BIGINT NOT NULL,
+    PRIMARY KEY (anime_id, genre_id),
+    FOREIGN KEY(anime_id) REFERENCES anime_series(id) ON DELETE CASCADE,
+    FOREIGN KEY(genre_id) REFERENCES genres(id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS roles (
+    name TEXT PRIMARY KEY,
+    description TEXT,
+    builtin BIGINT NOT NULL DEFAULT 0,
+    created_at TEXT DEFAULT CAST(CURRENT_TIMESTAMP AS TEXT)
+);
+
+CREATE TABLE IF NOT EXISTS permissions (
+    name TEXT PRIMARY KEY,
+    description TEXT
+);
+
+CREATE TABLE IF NOT EXISTS role_permissions (
+    role_name TEXT NOT NULL,
+    permission_name TEXT NOT NULL,
+    PRIMARY KEY (role_name, permission_name),
+    FOREIGN KEY(role_name) REFERENCES roles(name) ON DELETE CASCADE,
+    FOREIGN KEY(permission_name) REFERENCES permissions(name) ON DELETE CASCADE
+);
+
+-- Two-factor recovery codes, bcrypt hashed, deleted when used
+CREATE TABLE IF NOT EXISTS recovery_codes (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    code_hash TEXT NOT NULL,
+    created_at TEXT DEFAULT CAST(CURRENT_TIMESTAMP AS TEXT),
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+-- Personal API keys, see services::api_keys
+CREATE TABLE IF NOT EXISTS api_keys (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    prefix TEXT NOT NULL UNIQUE,
+    key_hash TEXT NOT NULL,
+    scopes TEXT NOT NULL DEFAULT '',
+    mfa BIGINT NOT NULL DEFAULT 0,
+    expires_at TEXT NOT NULL,
+    last_used_at TEXT NOT NULL DEFAULT '',
+    created_at TEXT NOT NULL DEFAULT CAST(CURRENT_TIMESTAMP AS TEXT),
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+-- External OIDC identities linked to local users, see services::oidc
+CREATE TABLE IF NOT EXISTS user_identities (
+    provider TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    email TEXT NOT NULL DEFAULT '',
+    created_at TEXT NOT NULL DEFAULT CAST(CURRENT_TIMESTAMP AS TEXT),
+    PRIMARY KEY (provider, subject),
+    UNIQUE (user_id, provider),
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+-- Display name, avatar and preferences edited through /api/me
+CREATE TABLE IF NOT EXISTS user_profiles (
+    user_id TEXT PRIMARY KEY,
+    display_name TEXT NOT NULL DEFAULT '',
+    avatar_url TEXT NOT NULL DEFAULT '',
+    preferred_language TEXT NOT NULL DEFAULT 'en',
+    content_preferences TEXT NOT NULL DEFAULT '{}',
+    updated_at TEXT NOT NULL DEFAULT CAST(CURRENT_TIMESTAMP AS TEXT),
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+-- Administrative actions, see services::audit. No foreign keys: entries
+-- outlive the users and content they mention.
+CREATE TABLE IF NOT EXISTS audit_log (
+    id TEXT PRIMARY KEY,
+    actor_id TEXT NOT NULL,
+    action TEXT NOT NULL,
+    target_type TEXT NOT NULL,
+    target_id TEXT NOT NULL,
+    before_json TEXT NOT NULL DEFAULT '',
+    after_json TEXT NOT NULL DEFAULT '',
+    ip TEXT NOT NULL DEFAULT '',
+    created_at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_audit_log_created_at ON audit_log (created_at);
+CREATE INDEX IF NOT EXISTS idx_audit_log_target ON audit_log (target_type, target_id);
@@ -0,0 +1,6 @@
+DROP INDEX IF EXISTS idx_episodes_series_created_at;
+DROP INDEX IF EXISTS idx_anime_genres_genre;
+DROP INDEX IF EXISTS idx_anime_series_title;
+DROP INDEX IF EXISTS idx_anime_series_content_type;
+
+ALTER TABLE anime_series DROP COLUMN year;
@@ -0,0 +1,13 @@
+DROP TABLE IF EXISTS audit_log;
+DROP TABLE IF EXISTS user_profiles;
+DROP TABLE IF EXISTS user_identities;
+DROP TABLE IF EXISTS api_keys;
+DROP TABLE IF EXISTS recovery_codes;
+DROP TABLE IF EXISTS role_permissions;
+DROP TABLE IF EXISTS permissions;
+DROP TABLE IF EXISTS roles;
+DROP TABLE IF EXISTS anime_genres;
+DROP TABLE IF EXISTS genres;
+DROP TABLE IF EXISTS episodes;
+DROP TABLE IF EXISTS anime_series;
+DROP TABLE IF EXISTS users;
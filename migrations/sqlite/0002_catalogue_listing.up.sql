@@ -0,0 +1,8 @@
+-- Release year for the catalogue filters, plus indexes for listing by type,
+-- genre and latest episode
+ALTER TABLE anime_series ADD COLUMN year INTEGER;
+
+CREATE INDEX IF NOT EXISTS idx_anime_series_content_type ON anime_series (content_type);
+CREATE INDEX IF NOT EXISTS idx_anime_series_title ON anime_series (LOWER(title), id);
+CREATE INDEX IF NOT EXISTS idx_anime_genres_genre ON anime_genres (genre_id, anime_id);
+CREATE INDEX IF NOT EXISTS idx_episodes_series_created_at ON episodes (series_id, created_at);
@@ -1,37 +1,349 @@
+use actix_web::cookie::{time, Cookie, SameSite};
 use actix_web::dev::ServiceRequest;
-use jsonwebtoken::{encode, decode, Header, Validation, EncodingKey, DecodingKey};
+use actix_web::http::header::HeaderMap;
+use actix_web::HttpMessage;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use jsonwebtoken::{encode, decode, decode_header, Algorithm, Header, Validation, EncodingKey, DecodingKey};
 use chrono::{Utc, Duration};
+use rand::Rng;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use uuid::Uuid;
+use crate::config::JwtConfig;
 use crate::models::TokenClaims;
 
-const SECRET: &[u8] = b"super_secret_key_change_me"; // Env var in prod
+// Permission names, stored in the `permissions` table and carried in TokenClaims
+pub const CONTENT_WRITE: &str = "content.write";
+pub const EPISODES_WRITE: &str = "episodes.write";
+pub const METRICS_READ: &str = "metrics.read";
+pub const USERS_READ: &str = "users.read";
+pub const USERS_DELETE: &str = "users.delete";
+pub const USERS_MANAGE: &str = "users.manage";
+pub const ROLES_MANAGE: &str = "roles.manage";
+pub const AUDIT_READ: &str = "audit.read";
 
-pub fn create_jwt(id: &str, role: &str) -> Result<String, jsonwebtoken::errors::Error> {
-    let expiration = Utc::now()
-        .checked_add_signed(Duration::hours(24))
+// Who may call /api/register, auth.registration_mode in Config.
+// Superusers are never created through registration, see `cli::create_superuser`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegistrationMode {
+    Open,
+    InviteOnly,
+    Closed,
+}
+
+// Two-factor policy, from the [auth] section of Config. `require_for_admin`
+// (default true) makes RequirePermission refuse tokens that were issued without
+// a second factor, which covers every route under routes::admin. `issuer` is the
+// name shown in authenticator apps.
+#[derive(Debug, Clone)]
+pub struct MfaPolicy {
+    pub require_for_admin: bool,
+    pub issuer: String,
+}
+
+// Cookie session mode for browser clients. The access and refresh tokens go
+// in HttpOnly cookies; CSRF_COOKIE is readable by the page, which echoes it in
+// CSRF_HEADER on state-changing requests (double submit). A cross-site page can
+// make the browser send the cookies but cannot read the CSRF value.
+pub const ACCESS_COOKIE: &str = "access_token";
+pub const REFRESH_COOKIE: &str = "refresh_token";
+pub const CSRF_COOKIE: &str = "csrf_token";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+// Ties an OIDC sign-in to the browser that started it, see handlers::oidc
+pub const OIDC_FLOW_COOKIE: &str = "oidc_flow";
+
+// The [cookies] section of Config: secure (COOKIE_SECURE, default true; false
+// only for plain-http development), same_site (COOKIE_SAMESITE=strict|lax,
+// default strict) and domain (COOKIE_DOMAIN, default: host only).
+#[derive(Debug, Clone)]
+pub struct CookieSettings {
+    pub secure: bool,
+    pub same_site: SameSite,
+    pub domain: Option<String>,
+}
+
+impl Default for CookieSettings {
+    fn default() -> Self {
+        CookieSettings { secure: true, same_site: SameSite::Strict, domain: None }
+    }
+}
+
+impl CookieSettings {
+    fn build(&self, name: &'static str, value: String, path: &'static str, http_only: bool, max_age_seconds: i64) -> Cookie<'static> {
+        let mut cookie = Cookie::build(name, value)
+            .path(path)
+            .http_only(http_only)
+            .secure(self.secure)
+            .same_site(self.same_site)
+            .max_age(time::Duration::seconds(max_age_seconds))
+            .finish();
+        if let Some(domain) = &self.domain {
+            cookie.set_domain(domain.clone());
+        }
+        cookie
+    }
+
+    // Access, refresh and CSRF cookies for a new or refreshed session. The CSRF
+    // cookie lives as long as the refresh token so its value stays stable.
+    pub fn session_cookies(&self, access_token: &str, refresh_token: &str, csrf_token: &str, settings: &TokenSettings) -> Vec<Cookie<'static>> {
+        let refresh_ttl_seconds = settings.refresh_ttl_seconds as i64;
+        vec![
+            self.build(ACCESS_COOKIE, access_token.to_string(), "/", true, settings.access_ttl_seconds),
+            // Only the refresh endpoint ever needs to see it
+            self.build(REFRESH_COOKIE, refresh_token.to_string(), "/api/refresh", true, refresh_ttl_seconds),
+            self.build(CSRF_COOKIE, csrf_token.to_string(), "/", false, refresh_ttl_seconds),
+        ]
+    }
+
+    // Lax whatever the configured policy: the callback arrives as a top-level
+    // navigation from the provider's site, which Strict cookies do not follow
+    pub fn oidc_flow_cookie(&self, value: &str, max_age_seconds: i64) -> Cookie<'static> {
+        let mut cookie = self.build(OIDC_FLOW_COOKIE, value.to_string(), "/api/oauth", true, max_age_seconds);
+        cookie.set_same_site(SameSite::Lax);
+        cookie
+    }
+
+    pub fn cleared_cookies(&self) -> Vec<Cookie<'static>> {
+        vec![
+            self.build(ACCESS_COOKIE, String::new(), "/", true, 0),
+            self.build(REFRESH_COOKIE, String::new(), "/api/refresh", true, 0),
+            self.build(CSRF_COOKIE, String::new(), "/", false, 0),
+        ]
+    }
+}
+
+// Double-submit check: the header must repeat the CSRF cookie.
+// Takes the request's CSRF cookie (`req.cookie(CSRF_COOKIE)`) and headers.
+pub fn csrf_matches(cookie: Option<Cookie<'_>>, headers: &HeaderMap) -> bool {
+    let cookie = match cookie {
+        Some(c) if !c.value().is_empty() => c.value().to_string(),
+        _ => return false,
+    };
+    let header = headers.get(CSRF_HEADER).and_then(|h| h.to_str().ok()).unwrap_or("");
+    secrets_match(&cookie, header)
+}
+
+// Length leaks nothing (the secrets have a fixed length); the bytes are
+// compared without an early exit.
+pub fn secrets_match(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Token lifetimes and the claims every issued token must carry.
+#[derive(Debug, Clone)]
+pub struct TokenSettings {
+    pub access_ttl_seconds: i64,
+    pub refresh_ttl_seconds: usize,
+    pub issuer: String,
+    pub audience: String,
+    // Clock skew tolerated when checking exp/nbf
+    pub leeway_seconds: u64,
+}
+
+impl Default for TokenSettings {
+    fn default() -> Self {
+        TokenSettings {
+            access_ttl_seconds: 15 * 60,
+            refresh_ttl_seconds: 7 * 24 * 3600,
+            issuer: "belajar-actix".to_string(),
+            audience: "belajar-actix".to_string(),
+            leeway_seconds: 30,
+        }
+    }
+}
+
+// Signing key plus every key still accepted for verification, indexed by `kid`.
+// Rotation: make the new key active and keep the old one verify-only until
+// tokens signed with it have expired.
+pub struct Keyring {
+    algorithm: Algorithm,
+    active_kid: String,
+    encoding: EncodingKey,
+    decoding: HashMap<String, DecodingKey>,
+    // Public keys only; HMAC secrets are never published
+    jwks: Vec<Value>,
+    pub settings: TokenSettings,
+}
+
+impl Keyring {
+    pub fn hs256(kid: &str, secret: &[u8]) -> Self {
+        let mut decoding = HashMap::new();
+        decoding.insert(kid.to_string(), DecodingKey::from_secret(secret));
+        Keyring {
+            algorithm: Algorithm::HS256,
+            active_kid: kid.to_string(),
+            encoding: EncodingKey::from_secret(secret),
+            decoding,
+            jwks: vec![],
+            settings: TokenSettings::default(),
+        }
+    }
+
+    pub fn ed25519(kid: &str, private_pem: &[u8], public_pem: &[u8]) -> Result<Self, String> {
+        let encoding = EncodingKey::from_ed_pem(private_pem)
+            .map_err(|e| format!("Invalid Ed25519 private key '{}': {}", kid, e))?;
+        let mut keyring = Keyring {
+            algorithm: Algorithm::EdDSA,
+            active_kid: kid.to_string(),
+            encoding,
+            decoding: HashMap::new(),
+            jwks: vec![],
+            settings: TokenSettings::default(),
+        };
+        keyring.add_verify_key(kid, public_pem)?;
+        Ok(keyring)
+    }
+
+    pub fn with_settings(mut self, settings: TokenSettings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    // Adds a verify-only key of the same algorithm (a rotated-out key).
+    pub fn add_verify_key(&mut self, kid: &str, key: &[u8]) -> Result<(), String> {
+        let decoding = match self.algorithm {
+            Algorithm::EdDSA => {
+                let x = ed25519_public_key(key)
+                    .ok_or_else(|| format!("Invalid Ed25519 public key '{}'", kid))?;
+                self.jwks.push(json!({
+                    "kty": "OKP",
+                    "crv": "Ed25519",
+                    "use": "sig",
+                    "alg": "EdDSA",
+                    "kid": kid,
+                    "x": URL_SAFE_NO_PAD.encode(x),
+                }));
+                DecodingKey::from_ed_pem(key)
+                    .map_err(|e| format!("Invalid Ed25519 public key '{}': {}", kid, e))?
+            }
+            _ => DecodingKey::from_secret(key),
+        };
+        self.decoding.insert(kid.to_string(), decoding);
+        Ok(())
+    }
+
+    // HS256: jwt.secret, with jwt.previous_secrets accepted for verification.
+    // EdDSA: jwt.keys_dir containing <kid>.pem (private) and <kid>.pub.pem
+    // (public); jwt.kid selects the signing key, every *.pub.pem is accepted
+    // and published. Only EdDSA can fail here, on a missing or invalid key file.
+    pub fn load(jwt: &JwtConfig) -> Result<Self, String> {
+        Ok(Keyring::keys(jwt)?.with_settings(jwt.tokens.clone()))
+    }
+
+    fn keys(jwt: &JwtConfig) -> Result<Self, String> {
+        let kid = &jwt.kid;
+        match jwt.algorithm.as_str() {
+            "HS256" => {
+                let mut keyring = match &jwt.secret {
+                    Some(secret) => Keyring::hs256(kid, secret.as_bytes()),
+                    None => {
+                        println!("JWT_SECRET not set, using a random secret. Tokens will not survive a restart.");
+                        let secret: [u8; 32] = rand::thread_rng().gen();
+                        Keyring::hs256(kid, &secret)
+                    }
+                };
+                for (old_kid, secret) in &jwt.previous_secrets {
+                    keyring.add_verify_key(old_kid, secret.as_bytes())?;
+                }
+                Ok(keyring)
+            }
+            "EdDSA" => {
+                let dir = &jwt.keys_dir;
+                let read = |path: String| std::fs::read(&path).map_err(|e| format!("Failed to read {}: {}", path, e));
+
+                let private_pem = read(format!("{}/{}.pem", dir, kid))?;
+                let public_pem = read(format!("{}/{}.pub.pem", dir, kid))?;
+                let mut keyring = Keyring::ed25519(kid, &private_pem, &public_pem)?;
+
+                let entries = std::fs::read_dir(dir).map_err(|e| format!("Failed to read {}: {}", dir, e))?;
+                for entry in entries.flatten() {
+                    let file_name = entry.file_name().to_string_lossy().to_string();
+                    if let Some(old_kid) = file_name.strip_suffix(".pub.pem") {
+                        if old_kid != kid.as_str() {
+                            keyring.add_verify_key(old_kid, &read(entry.path().display().to_string())?)?;
+                        }
+                    }
+                }
+                Ok(keyring)
+            }
+            other => Err(format!("Unsupported JWT_ALGORITHM '{}' (expected HS256 or EdDSA)", other)),
+        }
+    }
+
+    pub fn jwks(&self) -> Value {
+        json!({ "keys": self.jwks })
+    }
+}
+
+// Raw 32-byte key from an Ed25519 SubjectPublicKeyInfo PEM (12-byte fixed DER prefix).
+fn ed25519_public_key(pem: &[u8]) -> Option<Vec<u8>> {
+    let text = std::str::from_utf8(pem).ok()?;
+    let body: String = text.lines().filter(|l| !l.starts_with("-----")).collect();
+    let der = STANDARD.decode(body.trim()).ok()?;
+    if der.len() != 44 {
+        return None;
+    }
+    Some(der[12..].to_vec())
+}
+
+// `mfa` records that the session was opened with a second factor.
+pub fn create_jwt(keys: &Keyring, id: &str, role: &str, permissions: &[String], session_id: Option<&str>, mfa: bool) -> Result<String, jsonwebtoken::errors::Error> {
+    let now = Utc::now();
+    let expiration = now
+        .checked_add_signed(Duration::seconds(keys.settings.access_ttl_seconds))
         .expect("valid timestamp")
         .timestamp();
 
     let claims = TokenClaims {
         sub: id.to_owned(),
         role: role.to_owned(),
+        permissions: permissions.to_vec(),
+        sid: session_id.map(str::to_owned),
+        mfa,
+        api_key: None,
+        jti: Uuid::new_v4().to_string(),
+        iss: keys.settings.issuer.clone(),
+        aud: keys.settings.audience.clone(),
+        iat: now.timestamp() as usize,
+        iat_ms: now.timestamp_millis(),
+        nbf: now.timestamp() as usize,
         exp: expiration as usize,
     };
 
-    encode(&Header::default(), &claims, &EncodingKey::from_secret(SECRET))
+    let mut header = Header::new(keys.algorithm);
+    header.kid = Some(keys.active_kid.clone());
+
+    encode(&header, &claims, &keys.encoding)
 }
 
-pub fn validate_jwt(token: &str) -> Result<TokenClaims, jsonwebtoken::errors::Error> {
+pub fn validate_jwt(keys: &Keyring, token: &str) -> Result<TokenClaims, jsonwebtoken::errors::Error> {
+    let header = decode_header(token)?;
+    // Tokens issued before kid support carry no kid; try the active key
+    let kid = header.kid.unwrap_or_else(|| keys.active_kid.clone());
+    let key = keys.decoding.get(&kid)
+        .ok_or(jsonwebtoken::errors::ErrorKind::InvalidToken)?;
+
+    let mut validation = Validation::new(keys.algorithm);
+    validation.set_issuer(&[&keys.settings.issuer]);
+    validation.set_audience(&[&keys.settings.audience]);
+    validation.set_required_spec_claims(&["exp", "nbf", "iss", "aud"]);
+    validation.validate_nbf = true;
+    validation.leeway = keys.settings.leeway_seconds;
+
     let token_data = decode::<TokenClaims>(
         token,
-        &DecodingKey::from_secret(SECRET),
-        &Validation::default(),
+        key,
+        &validation,
     )?;
     Ok(token_data.claims)
 }
 
-// Helper to extract role from request extensions (set by middleware if we had one, or manual check)
-pub fn check_role(req: &ServiceRequest, required_role: &str) -> bool {
-    // For simplicity in this demo, we'll implement auth checking directly in handlers or a simple extractor
-    // But this function exists for architectural completeness
-    true
+// Checks the claims inserted by JwtAuth for the given permission.
+// Requests without claims (not authenticated) never pass.
+pub fn check_permission(req: &ServiceRequest, permission: &str) -> bool {
+    req.extensions()
+        .get::<TokenClaims>()
+        .map(|claims| claims.permissions.iter().any(|p| p == permission))
+        .unwrap_or(false)
 }
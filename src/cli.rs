@@ -0,0 +1,129 @@
+use bcrypt::{hash, DEFAULT_COST};
+use crate::db::AppState;
+use std::env;
+use std::io::{BufRead, Write};
+use uuid::Uuid;
+use crate::config::{AuthConfig, Config};
+use crate::{db, migrate};
+use crate::validation::{ValidationErrors, validate_username_format, validate_password, is_breached_password};
+
+const USAGE: &str = "Usage:
+  belajar-actix                                    start the API server
+  belajar-actix create-superuser --username NAME   create a superuser account
+      password from --password, SUPERUSER_PASSWORD or prompted on stdin
+  belajar-actix migrate status                     list migrations and whether they are applied
+  belajar-actix migrate up [--to N]                apply pending migrations, up to version N
+  belajar-actix migrate down --to N                revert migrations newer than version N";
+
+// Runs a subcommand instead of the server. Returns an error message to print.
+pub async fn run(args: &[String]) -> Result<(), String> {
+    match args[0].as_str() {
+        "create-superuser" => {
+            let username = flag(args, "--username").ok_or(format!("--username is required\n{}", USAGE))?;
+            let password = match flag(args, "--password").or_else(|| env::var("SUPERUSER_PASSWORD").ok()) {
+                Some(p) => p,
+                None => prompt("Password: ")?,
+            };
+
+            let config = Config::load()?;
+            let (pool, status) = db::init_db(&config.database).await;
+            let db = AppState::new(pool, status.kind);
+            let id = create_superuser(&db, &config.auth, &username, &password).await?;
+            println!("Superuser '{}' created with id {}", username, id);
+            Ok(())
+        }
+        "migrate" => migrate_command(&args[1..]).await,
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        other => Err(format!("Unknown command '{}'\n{}", other, USAGE)),
+    }
+}
+
+async fn migrate_command(args: &[String]) -> Result<(), String> {
+    let target = match flag(args, "--to") {
+        Some(n) => Some(n.parse::<i64>().map_err(|_| format!("--to expects a version number, got '{}'", n))?),
+        None => None,
+    };
+
+    // Never migrate a fallback database in place of the configured one
+    let settings = db::DbSettings { fallback_url: None, ..Config::load()?.database };
+    let (db, status) = db::connect(&settings).await?;
+    let kind = status.kind;
+    match args.first().map(String::as_str) {
+        Some("status") => {
+            for (migration, applied_at) in migrate::status(&db, kind).await? {
+                let state = applied_at.map(|at| format!("applied {}", at)).unwrap_or("pending".to_string());
+                println!("{:<32} {}", migration.name, state);
+            }
+            Ok(())
+        }
+        Some("up") => {
+            let applied = migrate::up(&db, kind, target).await?;
+            println!("Applied {} migration(s)", applied.len());
+            Ok(())
+        }
+        Some("down") => {
+            let target = target.ok_or(format!("migrate down requires --to N (0 reverts everything)\n{}", USAGE))?;
+            let reverted = migrate::down(&db, kind, target).await?;
+            println!("Reverted {} migration(s)", reverted.len());
+            Ok(())
+        }
+        _ => Err(format!("Expected status, up or down\n{}", USAGE)),
+    }
+}
+
+fn flag(args: &[String], name: &str) -> Option<String> {
+    args.iter()
+        .position(|a| a == name)
+        .and_then(|i| args.get(i + 1))
+        .cloned()
+}
+
+fn prompt(label: &str) -> Result<String, String> {
+    print!("{}", label);
+    std::io::stdout().flush().map_err(|e| e.to_string())?;
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line).map_err(|e| e.to_string())?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+// Superusers are only created here, never through /api/register. Reserved
+// names such as "admin" are allowed because the operator is trusted.
+pub async fn create_superuser(db: &AppState, auth: &AuthConfig, username: &str, password: &str) -> Result<String, String> {
+    let mut errors = ValidationErrors::default();
+    validate_username_format(username, &mut errors);
+    validate_password(password, username, &mut errors);
+    if is_breached_password(auth, password).await {
+        errors.add("password", "appears in a known data breach");
+    }
+    if !errors.is_empty() {
+        let messages: Vec<String> = errors.fields().iter()
+            .flat_map(|(field, msgs)| msgs.iter().map(move |m| format!("{} {}", field, m)))
+            .collect();
+        return Err(messages.join("\n"));
+    }
+
+    let taken: (i64,) = sqlx::query_as(&db.sql("SELECT COUNT(*) FROM users WHERE LOWER(username) = LOWER(?)"))
+        .bind(username)
+        .fetch_one(&db.pool)
+        .await
+        .map_err(|e| e.to_string())?;
+    if taken.0 > 0 {
+        return Err(format!("User '{}' already exists", username));
+    }
+
+    let hashed_password = hash(password, DEFAULT_COST).map_err(|e| e.to_string())?;
+    let id = Uuid::new_v4().to_string();
+
+    sqlx::query(&db.sql("INSERT INTO users (id, username, password, role) VALUES (?, ?, ?, 'superuser')"))
+        .bind(&id)
+        .bind(username)
+        .bind(&hashed_password)
+        .execute(&db.pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(id)
+}
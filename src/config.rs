@@ -0,0 +1,623 @@
+use actix_web::cookie::SameSite;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::path::Path;
+use std::str::FromStr;
+use crate::auth::{CookieSettings, MfaPolicy, RegistrationMode, TokenSettings};
+use crate::db::{DbSettings, DEFAULT_SQLITE_URL};
+use crate::services::mailer::{MailSettings, MailTransport, SmtpSettings, SmtpTls};
+use crate::services::oidc::OidcProvider;
+
+// Startup settings, loaded once by `Config::load` and handed to init_db,
+// init_redis, Keyring, the mailer, RateLimit and the handlers. Sources, later
+// ones winning:
+//   1. built-in defaults
+//   2. `<CONFIG_DIR>/default.toml` (CONFIG_DIR defaults to `config`)
+//   3. `<CONFIG_DIR>/<profile>.toml`, profile from APP_ENV=dev|test|prod (default dev)
+//   4. environment variables, named next to each field below
+// Both files are optional. Unknown keys are an error, so typos do not go unnoticed.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub profile: Profile,
+    pub server: ServerConfig,
+    pub database: DbSettings,
+    pub redis: RedisConfig,
+    pub rate_limit: RateLimitConfig,
+    pub jwt: JwtConfig,
+    pub cors: CorsConfig,
+    pub auth: AuthConfig,
+    pub cookies: CookieSettings,
+    pub mail: MailSettings,
+    pub oidc: Vec<OidcProvider>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Profile {
+    Dev,
+    Test,
+    Prod,
+}
+
+impl Profile {
+    fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "dev" | "development" => Ok(Profile::Dev),
+            "test" => Ok(Profile::Test),
+            "prod" | "production" => Ok(Profile::Prod),
+            other => Err(format!("Unsupported APP_ENV '{}' (expected dev, test or prod)", other)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Profile::Dev => "dev",
+            Profile::Test => "test",
+            Profile::Prod => "prod",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub port: u16,          // PORT, default 8080
+    pub upload_dir: String, // UPLOAD_DIR, default `uploads`; served under /uploads
+    pub base_url: String,   // APP_BASE_URL, default http://localhost:8080; the web frontend emails link to
+}
+
+#[derive(Debug, Clone)]
+pub struct RedisConfig {
+    pub url: String, // REDIS_URL, default redis://127.0.0.1/
+}
+
+// Requests per client IP per window
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    pub requests: u64,    // RATE_LIMIT_REQUESTS, default 100
+    pub window_secs: u64, // RATE_LIMIT_WINDOW_SECS, default 60
+}
+
+// Signing keys, read by Keyring::load, and the claims and lifetimes of issued
+// tokens. Lifetimes are ACCESS_TOKEN_TTL_SECONDS and REFRESH_TOKEN_TTL_SECONDS,
+// the claims JWT_ISSUER, JWT_AUDIENCE and JWT_LEEWAY_SECONDS (see TokenSettings
+// for the defaults).
+#[derive(Debug, Clone)]
+pub struct JwtConfig {
+    pub algorithm: String, // JWT_ALGORITHM=HS256|EdDSA, default HS256
+    pub kid: String,       // JWT_KID, default `default`; the signing key
+    // HS256: JWT_SECRET; random per process when unset, required in prod
+    pub secret: Option<String>,
+    // HS256: verify-only (kid, secret) pairs, JWT_PREVIOUS_SECRETS="kid:secret,kid:secret"
+    pub previous_secrets: Vec<(String, String)>,
+    // EdDSA: JWT_KEYS_DIR, default `keys`, holding <kid>.pem and <kid>.pub.pem
+    pub keys_dir: String,
+    pub tokens: TokenSettings,
+}
+
+// Browser origins allowed to call the API with credentials (cookies or the
+// Authorization header). Empty means same-origin only.
+#[derive(Debug, Clone, Default)]
+pub struct CorsConfig {
+    pub allowed_origins: Vec<String>, // CORS_ALLOWED_ORIGINS, comma separated, e.g. https://app.example.com
+}
+
+#[derive(Debug, Clone)]
+pub struct AuthConfig {
+    pub registration: RegistrationMode, // REGISTRATION_MODE=open|invite|closed, default open
+    // REQUIRE_ADMIN_2FA (default true) and TOTP_ISSUER (default "Anime Streaming")
+    pub mfa: MfaPolicy,
+    // BREACHED_PASSWORDS_DIR, see validation::is_breached_password; unset skips the check
+    pub breached_passwords_dir: Option<String>,
+}
+
+// The file layout, every key optional. [database] keys map to DbSettings:
+// url (DATABASE_URL), strict (DB_STRICT, default true in prod), max_connections
+// (DB_MAX_CONNECTIONS, default 5), connect_attempts (DB_CONNECT_ATTEMPTS,
+// default 5), connect_timeout_secs (DB_CONNECT_TIMEOUT_SECS, default 5),
+// retry_delay_ms (DB_RETRY_DELAY_MS, default 500), fallback = "none" | "sqlite"
+// (DB_FALLBACK, default sqlite, none in strict mode) and fallback_url (DB_FALLBACK_URL).
+// [cookies] holds secure (COOKIE_SECURE), same_site (COOKIE_SAMESITE) and domain
+// (COOKIE_DOMAIN), see auth::CookieSettings. [mail] holds backend = "file" |
+// "smtp" (MAILER), from (MAIL_FROM), dir (MAIL_DIR) and a [mail.smtp] table with
+// host, port, username, password and tls (SMTP_*), see mailer::MailSettings.
+// [oidc.providers.<name>] holds issuer, client_id, client_secret, redirect_uri
+// and scopes, overridden by OIDC_<NAME>_ISSUER and so on; OIDC_PROVIDERS, when
+// set, names the providers to enable.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawConfig {
+    server: RawServer,
+    database: RawDatabase,
+    redis: RawRedis,
+    rate_limit: RawRateLimit,
+    jwt: RawJwt,
+    cors: RawCors,
+    auth: RawAuth,
+    cookies: RawCookies,
+    mail: RawMail,
+    oidc: RawOidc,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawServer {
+    port: Option<u16>,
+    upload_dir: Option<String>,
+    base_url: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawDatabase {
+    url: Option<String>,
+    strict: Option<bool>,
+    max_connections: Option<u32>,
+    connect_attempts: Option<u32>,
+    connect_timeout_secs: Option<u64>,
+    retry_delay_ms: Option<u64>,
+    fallback: Option<String>,
+    fallback_url: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawRedis {
+    url: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawRateLimit {
+    requests: Option<u64>,
+    window_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawJwt {
+    algorithm: Option<String>,
+    kid: Option<String>,
+    secret: Option<String>,
+    previous_secrets: Option<Vec<String>>, // "kid:secret" entries
+    keys_dir: Option<String>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    leeway_seconds: Option<u64>,
+    access_ttl_seconds: Option<i64>,
+    refresh_ttl_seconds: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawCors {
+    allowed_origins: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawAuth {
+    registration_mode: Option<String>,
+    require_admin_2fa: Option<bool>,
+    totp_issuer: Option<String>,
+    breached_passwords_dir: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawCookies {
+    secure: Option<bool>,
+    same_site: Option<String>,
+    domain: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawMail {
+    backend: Option<String>,
+    from: Option<String>,
+    dir: Option<String>,
+    smtp: RawSmtp,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawSmtp {
+    host: Option<String>,
+    port: Option<u16>,
+    username: Option<String>,
+    password: Option<String>,
+    tls: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawOidc {
+    providers: BTreeMap<String, RawOidcProvider>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawOidcProvider {
+    issuer: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    redirect_uri: Option<String>,
+    scopes: Option<String>,
+}
+
+impl Config {
+    pub fn load() -> Result<Self, String> {
+        // An empty variable counts as unset, like DATABASE_URL= in .env.example
+        let lookup = |name: &str| env::var(name).ok().filter(|v| !v.is_empty());
+        let dir = lookup("CONFIG_DIR").unwrap_or_else(|| "config".to_string());
+        Config::from_sources(Path::new(&dir), &lookup)
+    }
+
+    // `env` stands in for the process environment so tests need not mutate it
+    pub fn from_sources(dir: &Path, env: &dyn Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let profile = Profile::parse(env("APP_ENV").as_deref().unwrap_or("dev"))?;
+
+        let mut merged = toml::Table::new();
+        for name in ["default", profile.name()] {
+            let path = dir.join(format!("{}.toml", name));
+            let text = match std::fs::read_to_string(&path) {
+                Ok(text) => text,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+            };
+            // Checked on its own first so errors point at the right file and line
+            toml::from_str::<RawConfig>(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+            merge(&mut merged, text.parse().map_err(|e| format!("{}: {}", path.display(), e))?);
+        }
+
+        let mut raw: RawConfig = toml::Value::Table(merged).try_into().map_err(|e: toml::de::Error| e.to_string())?;
+        raw.apply_env(env)?;
+        raw.resolve(profile)
+    }
+}
+
+// Profile values replace defaults key by key, tables are merged recursively
+fn merge(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => merge(base, overlay),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn set<T: FromStr>(slot: &mut Option<T>, env: &dyn Fn(&str) -> Option<String>, name: &str) -> Result<(), String> {
+    if let Some(v) = env(name) {
+        *slot = Some(v.parse().map_err(|_| format!("{} has an invalid value '{}'", name, v))?);
+    }
+    Ok(())
+}
+
+// Also accepts 1 and 0, as the variables always have
+fn set_bool(slot: &mut Option<bool>, env: &dyn Fn(&str) -> Option<String>, name: &str) -> Result<(), String> {
+    match env(name).as_deref() {
+        None => {}
+        Some("true") | Some("1") => *slot = Some(true),
+        Some("false") | Some("0") => *slot = Some(false),
+        Some(other) => return Err(format!("Unsupported {} '{}' (expected true or false)", name, other)),
+    }
+    Ok(())
+}
+
+// Comma separated, blanks dropped
+fn set_list(slot: &mut Option<Vec<String>>, env: &dyn Fn(&str) -> Option<String>, name: &str) {
+    if let Some(v) = env(name) {
+        *slot = Some(v.split(',').map(str::trim).filter(|e| !e.is_empty()).map(str::to_string).collect());
+    }
+}
+
+// An absolute http(s) URL, as the links and redirects built from it need
+fn is_http_url(url: &str) -> bool {
+    reqwest::Url::parse(url).is_ok_and(|u| matches!(u.scheme(), "http" | "https"))
+}
+
+impl RawConfig {
+    fn apply_env(&mut self, env: &dyn Fn(&str) -> Option<String>) -> Result<(), String> {
+        set(&mut self.server.port, env, "PORT")?;
+        set(&mut self.server.upload_dir, env, "UPLOAD_DIR")?;
+        set(&mut self.server.base_url, env, "APP_BASE_URL")?;
+
+        let db = &mut self.database;
+        set(&mut db.url, env, "DATABASE_URL")?;
+        set_bool(&mut db.strict, env, "DB_STRICT")?;
+        set(&mut db.max_connections, env, "DB_MAX_CONNECTIONS")?;
+        set(&mut db.connect_attempts, env, "DB_CONNECT_ATTEMPTS")?;
+        set(&mut db.connect_timeout_secs, env, "DB_CONNECT_TIMEOUT_SECS")?;
+        set(&mut db.retry_delay_ms, env, "DB_RETRY_DELAY_MS")?;
+        set(&mut db.fallback, env, "DB_FALLBACK")?;
+        set(&mut db.fallback_url, env, "DB_FALLBACK_URL")?;
+
+        set(&mut self.redis.url, env, "REDIS_URL")?;
+        set(&mut self.rate_limit.requests, env, "RATE_LIMIT_REQUESTS")?;
+        set(&mut self.rate_limit.window_secs, env, "RATE_LIMIT_WINDOW_SECS")?;
+
+        let jwt = &mut self.jwt;
+        set(&mut jwt.algorithm, env, "JWT_ALGORITHM")?;
+        set(&mut jwt.kid, env, "JWT_KID")?;
+        set(&mut jwt.secret, env, "JWT_SECRET")?;
+        set_list(&mut jwt.previous_secrets, env, "JWT_PREVIOUS_SECRETS");
+        set(&mut jwt.keys_dir, env, "JWT_KEYS_DIR")?;
+        set(&mut jwt.issuer, env, "JWT_ISSUER")?;
+        set(&mut jwt.audience, env, "JWT_AUDIENCE")?;
+        set(&mut jwt.leeway_seconds, env, "JWT_LEEWAY_SECONDS")?;
+        set(&mut jwt.access_ttl_seconds, env, "ACCESS_TOKEN_TTL_SECONDS")?;
+        set(&mut jwt.refresh_ttl_seconds, env, "REFRESH_TOKEN_TTL_SECONDS")?;
+
+        set_list(&mut self.cors.allowed_origins, env, "CORS_ALLOWED_ORIGINS");
+
+        let auth = &mut self.auth;
+        set(&mut auth.registration_mode, env, "REGISTRATION_MODE")?;
+        set_bool(&mut auth.require_admin_2fa, env, "REQUIRE_ADMIN_2FA")?;
+        set(&mut auth.totp_issuer, env, "TOTP_ISSUER")?;
+        set(&mut auth.breached_passwords_dir, env, "BREACHED_PASSWORDS_DIR")?;
+
+        set_bool(&mut self.cookies.secure, env, "COOKIE_SECURE")?;
+        set(&mut self.cookies.same_site, env, "COOKIE_SAMESITE")?;
+        set(&mut self.cookies.domain, env, "COOKIE_DOMAIN")?;
+
+        let mail = &mut self.mail;
+        set(&mut mail.backend, env, "MAILER")?;
+        set(&mut mail.from, env, "MAIL_FROM")?;
+        set(&mut mail.dir, env, "MAIL_DIR")?;
+        set(&mut mail.smtp.host, env, "SMTP_HOST")?;
+        set(&mut mail.smtp.port, env, "SMTP_PORT")?;
+        set(&mut mail.smtp.username, env, "SMTP_USERNAME")?;
+        set(&mut mail.smtp.password, env, "SMTP_PASSWORD")?;
+        set(&mut mail.smtp.tls, env, "SMTP_TLS")?;
+
+        let providers = &mut self.oidc.providers;
+        if let Some(names) = env("OIDC_PROVIDERS") {
+            let names: Vec<String> = names.split(',').map(str::trim).filter(|n| !n.is_empty()).map(str::to_lowercase).collect();
+            providers.retain(|name, _| names.contains(name));
+            for name in names {
+                providers.entry(name).or_default();
+            }
+        }
+        for (name, provider) in providers.iter_mut() {
+            let var = |suffix: &str| format!("OIDC_{}_{}", name.to_uppercase(), suffix);
+            set(&mut provider.issuer, env, &var("ISSUER"))?;
+            set(&mut provider.client_id, env, &var("CLIENT_ID"))?;
+            set(&mut provider.client_secret, env, &var("CLIENT_SECRET"))?;
+            set(&mut provider.redirect_uri, env, &var("REDIRECT_URI"))?;
+            set(&mut provider.scopes, env, &var("SCOPES"))?;
+        }
+        Ok(())
+    }
+
+    // Fills in defaults and reports every problem at once, one per line
+    fn resolve(self, profile: Profile) -> Result<Config, String> {
+        let mut errors = vec![];
+
+        let server = ServerConfig {
+            port: self.server.port.unwrap_or(8080),
+            upload_dir: self.server.upload_dir.unwrap_or_else(|| "uploads".to_string()),
+            base_url: self.server.base_url.unwrap_or_else(|| "http://localhost:8080".to_string()),
+        };
+        if server.port == 0 {
+            errors.push("server.port (PORT) must be greater than zero".to_string());
+        }
+        if server.upload_dir.trim().is_empty() {
+            errors.push("server.upload_dir (UPLOAD_DIR) must not be empty".to_string());
+        }
+        if !is_http_url(&server.base_url) {
+            errors.push(format!("server.base_url (APP_BASE_URL) '{}' is not an http(s) URL", server.base_url));
+        }
+
+        let db = self.database;
+        let strict = db.strict.unwrap_or(profile == Profile::Prod);
+        // Strict mode never boots against a database nobody configured
+        let url = db.url.unwrap_or_else(|| {
+            if strict {
+                errors.push("database.url (DATABASE_URL) must be set when database.strict is enabled".to_string());
+            }
+            DEFAULT_SQLITE_URL.to_string()
+        });
+        let fallback_url = db.fallback_url.unwrap_or_else(|| DEFAULT_SQLITE_URL.to_string());
+        let fallback_url = match db.fallback.as_deref() {
+            None => (!strict).then_some(fallback_url),
+            Some("none") => None,
+            Some("sqlite") if strict => {
+                errors.push("database.fallback = \"sqlite\" (DB_FALLBACK) cannot be combined with database.strict".to_string());
+                None
+            }
+            Some("sqlite") => Some(fallback_url),
+            Some(other) => {
+                errors.push(format!("Unsupported database.fallback '{}' (DB_FALLBACK, expected none or sqlite)", other));
+                None
+            }
+        };
+        let database = DbSettings {
+            url,
+            strict,
+            max_connections: db.max_connections.unwrap_or(5),
+            attempts: db.connect_attempts.unwrap_or(5),
+            connect_timeout_secs: db.connect_timeout_secs.unwrap_or(5),
+            retry_delay_ms: db.retry_delay_ms.unwrap_or(500),
+            fallback_url,
+        };
+        if database.max_connections == 0 {
+            errors.push("database.max_connections (DB_MAX_CONNECTIONS) must be greater than zero".to_string());
+        }
+        if database.attempts == 0 || database.connect_timeout_secs == 0 {
+            errors.push("database.connect_attempts and database.connect_timeout_secs must be greater than zero".to_string());
+        }
+
+        let redis = RedisConfig { url: self.redis.url.unwrap_or_else(|| "redis://127.0.0.1/".to_string()) };
+        if let Err(e) = redis::Client::open(redis.url.as_str()) {
+            errors.push(format!("Invalid redis.url (REDIS_URL) '{}': {}", redis.url, e));
+        }
+
+        let rate_limit = RateLimitConfig {
+            requests: self.rate_limit.requests.unwrap_or(100),
+            window_secs: self.rate_limit.window_secs.unwrap_or(60),
+        };
+        if rate_limit.requests == 0 || rate_limit.window_secs == 0 {
+            errors.push("rate_limit.requests and rate_limit.window_secs must be greater than zero".to_string());
+        }
+
+        let raw_jwt = self.jwt;
+        let token_defaults = TokenSettings::default();
+        let mut previous_secrets = vec![];
+        for entry in raw_jwt.previous_secrets.unwrap_or_default() {
+            match entry.trim().split_once(':') {
+                Some((kid, secret)) if !kid.is_empty() && !secret.is_empty() => previous_secrets.push((kid.to_string(), secret.to_string())),
+                _ => errors.push(format!("jwt.previous_secrets (JWT_PREVIOUS_SECRETS) entries must be kid:secret, got '{}'", entry)),
+            }
+        }
+        let jwt = JwtConfig {
+            algorithm: raw_jwt.algorithm.unwrap_or_else(|| "HS256".to_string()),
+            kid: raw_jwt.kid.unwrap_or_else(|| "default".to_string()),
+            secret: raw_jwt.secret.filter(|s| !s.is_empty()),
+            previous_secrets,
+            keys_dir: raw_jwt.keys_dir.unwrap_or_else(|| "keys".to_string()),
+            tokens: TokenSettings {
+                access_ttl_seconds: raw_jwt.access_ttl_seconds.unwrap_or(token_defaults.access_ttl_seconds),
+                refresh_ttl_seconds: raw_jwt.refresh_ttl_seconds.unwrap_or(token_defaults.refresh_ttl_seconds),
+                issuer: raw_jwt.issuer.unwrap_or(token_defaults.issuer),
+                audience: raw_jwt.audience.unwrap_or(token_defaults.audience),
+                leeway_seconds: raw_jwt.leeway_seconds.unwrap_or(token_defaults.leeway_seconds),
+            },
+        };
+        if !matches!(jwt.algorithm.as_str(), "HS256" | "EdDSA") {
+            errors.push(format!("Unsupported jwt.algorithm '{}' (JWT_ALGORITHM, expected HS256 or EdDSA)", jwt.algorithm));
+        }
+        if jwt.kid.is_empty() {
+            errors.push("jwt.kid (JWT_KID) must not be empty".to_string());
+        }
+        // A random secret would sign everyone out on every deploy
+        if profile == Profile::Prod && jwt.algorithm == "HS256" && jwt.secret.is_none() {
+            errors.push("jwt.secret (JWT_SECRET) must be set in the prod profile".to_string());
+        }
+        if jwt.tokens.access_ttl_seconds <= 0 || jwt.tokens.refresh_ttl_seconds == 0 {
+            errors.push("jwt.access_ttl_seconds and jwt.refresh_ttl_seconds (ACCESS_TOKEN_TTL_SECONDS, REFRESH_TOKEN_TTL_SECONDS) must be greater than zero".to_string());
+        }
+
+        let cors = CorsConfig { allowed_origins: self.cors.allowed_origins.unwrap_or_default() };
+        // Exact origins only: a wildcard would hand every site the user's session
+        for origin in &cors.allowed_origins {
+            let valid = reqwest::Url::parse(origin)
+                .is_ok_and(|u| matches!(u.scheme(), "http" | "https") && u.origin().ascii_serialization() == *origin);
+            if !valid {
+                errors.push(format!("cors.allowed_origins (CORS_ALLOWED_ORIGINS) entry '{}' is not an origin like https://app.example.com", origin));
+            }
+        }
+
+        let registration = match self.auth.registration_mode.as_deref().unwrap_or("open") {
+            "open" => RegistrationMode::Open,
+            "invite" => RegistrationMode::InviteOnly,
+            "closed" => RegistrationMode::Closed,
+            other => {
+                errors.push(format!("Unsupported auth.registration_mode '{}' (REGISTRATION_MODE, expected open, invite or closed)", other));
+                RegistrationMode::Closed
+            }
+        };
+        let auth = AuthConfig {
+            registration,
+            mfa: MfaPolicy {
+                require_for_admin: self.auth.require_admin_2fa.unwrap_or(true),
+                issuer: self.auth.totp_issuer.unwrap_or_else(|| "Anime Streaming".to_string()),
+            },
+            breached_passwords_dir: self.auth.breached_passwords_dir.filter(|d| !d.is_empty()),
+        };
+
+        let same_site = match self.cookies.same_site.as_deref().unwrap_or("strict") {
+            "strict" => SameSite::Strict,
+            "lax" => SameSite::Lax,
+            other => {
+                errors.push(format!("Unsupported cookies.same_site '{}' (COOKIE_SAMESITE, expected strict or lax)", other));
+                SameSite::Strict
+            }
+        };
+        let cookies = CookieSettings {
+            secure: self.cookies.secure.unwrap_or(true),
+            same_site,
+            domain: self.cookies.domain.filter(|d| !d.is_empty()),
+        };
+        // Plain-http cookies are for local development only
+        if profile == Profile::Prod && !cookies.secure {
+            errors.push("cookies.secure (COOKIE_SECURE) cannot be false in the prod profile".to_string());
+        }
+
+        let raw_mail = self.mail;
+        let transport = match raw_mail.backend.as_deref().unwrap_or("file") {
+            "file" => MailTransport::File { dir: raw_mail.dir.unwrap_or_else(|| "mail".to_string()) },
+            "smtp" => {
+                let smtp = raw_mail.smtp;
+                let tls = match smtp.tls.as_deref().unwrap_or("starttls") {
+                    "starttls" => SmtpTls::StartTls,
+                    "tls" => SmtpTls::Tls,
+                    "none" => SmtpTls::None,
+                    other => {
+                        errors.push(format!("Unsupported mail.smtp.tls '{}' (SMTP_TLS, expected starttls, tls or none)", other));
+                        SmtpTls::StartTls
+                    }
+                };
+                // Credentials are sent as a pair or not at all
+                let credentials = match (smtp.username, smtp.password) {
+                    (Some(user), Some(pass)) => Some((user, pass)),
+                    (None, None) => None,
+                    _ => {
+                        errors.push("mail.smtp.username and mail.smtp.password (SMTP_USERNAME, SMTP_PASSWORD) must be set together".to_string());
+                        None
+                    }
+                };
+                MailTransport::Smtp(SmtpSettings {
+                    host: smtp.host.unwrap_or_else(|| "localhost".to_string()),
+                    port: smtp.port,
+                    credentials,
+                    tls,
+                })
+            }
+            other => {
+                errors.push(format!("Unsupported mail.backend '{}' (MAILER, expected smtp or file)", other));
+                MailTransport::File { dir: String::new() }
+            }
+        };
+        let mail = MailSettings {
+            from: raw_mail.from.unwrap_or_else(|| "Anime Streaming <no-reply@localhost>".to_string()),
+            transport,
+        };
+        if let Err(e) = mail.from.parse::<lettre::message::Mailbox>() {
+            errors.push(format!("Invalid mail.from (MAIL_FROM) '{}': {}", mail.from, e));
+        }
+
+        let mut oidc = vec![];
+        for (name, raw) in self.oidc.providers {
+            let upper = name.to_uppercase();
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_') {
+                errors.push(format!("OIDC provider name '{}' must be lowercase letters, digits or underscores", name));
+                continue;
+            }
+            let mut required = |value: Option<String>, key: &str, suffix: &str| value.filter(|v| !v.is_empty()).unwrap_or_else(|| {
+                errors.push(format!("oidc.providers.{}.{} (OIDC_{}_{}) must be set", name, key, upper, suffix));
+                String::new()
+            });
+            let provider = OidcProvider {
+                issuer: required(raw.issuer, "issuer", "ISSUER"),
+                client_id: required(raw.client_id, "client_id", "CLIENT_ID"),
+                client_secret: required(raw.client_secret, "client_secret", "CLIENT_SECRET"),
+                redirect_uri: required(raw.redirect_uri, "redirect_uri", "REDIRECT_URI"),
+                scopes: raw.scopes.unwrap_or_else(|| "openid email profile".to_string()),
+                name,
+            };
+            for (key, url) in [("issuer", &provider.issuer), ("redirect_uri", &provider.redirect_uri)] {
+                if !url.is_empty() && !is_http_url(url) {
+                    errors.push(format!("oidc.providers.{}.{} '{}' is not an http(s) URL", provider.name, key, url));
+                }
+            }
+            oidc.push(provider);
+        }
+
+        if !errors.is_empty() {
+            return Err(errors.join("\n"));
+        }
+        Ok(Config { profile, server, database, redis, rate_limit, jwt, cors, auth, cookies, mail, oidc })
+    }
+}
@@ -1,136 +1,192 @@
 use sqlx::any::AnyPoolOptions;
 use sqlx::{AnyPool};
-use std::env;
+use std::time::Duration;
+use std::borrow::Cow;
+use crate::{migrate, query};
+use crate::repositories::Repositories;
+use crate::auth::{CONTENT_WRITE, EPISODES_WRITE, METRICS_READ, USERS_READ, USERS_DELETE, USERS_MANAGE, ROLES_MANAGE, AUDIT_READ};
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum DbKind {
     Sqlite,
     Postgres,
 }
 
+// Shared with every handler. Write SQL with `?` placeholders and pass it
+// through `sql`, which renders it for `kind` (see query.rs). The catalogue
+// and admin handlers go through `repos` instead.
 #[derive(Clone)]
 pub struct AppState {
-    pub db: AnyPool,
+    pub pool: AnyPool,
+    pub kind: DbKind,
+    pub repos: Repositories,
 }
 
-pub async fn init_db() -> AnyPool {
-    // Install default drivers for AnyPool
-    sqlx::any::install_default_drivers();
+impl AppState {
+    pub fn new(pool: AnyPool, kind: DbKind) -> Self {
+        let repos = Repositories::sql(&pool, kind);
+        AppState { pool, kind, repos }
+    }
+
+    // Repositories::memory in handler tests
+    #[cfg(test)]
+    pub fn with_repos(self, repos: Repositories) -> Self {
+        AppState { repos, ..self }
+    }
+
+    pub fn sql<'a>(&self, sql: &'a str) -> Cow<'a, str> {
+        query::render(self.kind, sql)
+    }
+}
+
+// How startup reaches the database, the [database] section of Config (see
+// config.rs for the keys, defaults and environment overrides).
+#[derive(Debug, Clone)]
+pub struct DbSettings {
+    pub url: String,
+    pub strict: bool,
+    pub max_connections: u32,
+    pub attempts: u32,
+    pub connect_timeout_secs: u64,
+    pub retry_delay_ms: u64,
+    pub fallback_url: Option<String>, // used only when the primary stays unreachable
+}
+
+pub const DEFAULT_SQLITE_URL: &str = "sqlite:anime.db";
+const MAX_RETRY_DELAY_MS: u64 = 30_000;
+
+// The database startup settled on, reported by /api/health
+#[derive(Debug, Clone)]
+pub struct DbStatus {
+    pub kind: DbKind,
+    pub url: String, // password redacted
+    pub strict: bool,
+    pub fallback: bool, // running on DB_FALLBACK_URL because the primary was unreachable
+}
 
-    let database_url = env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite:anime.db".to_string());
-    let mut kind = DbKind::Sqlite;
-
-    // Check if it looks like postgres
-    if database_url.starts_with("postgres") {
-        kind = DbKind::Postgres;
-    } else {
-        let path = database_url.replace("sqlite:", "");
-        if !std::path::Path::new(&path).exists() && path != ":memory:" {
-             println!("Creating SQLite database file: {}", path);
-             std::fs::File::create(&path).expect("Failed to create database file");
+impl DbKind {
+    pub fn from_url(url: &str) -> Self {
+        if url.starts_with("postgres") { DbKind::Postgres } else { DbKind::Sqlite }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DbKind::Sqlite => "sqlite",
+            DbKind::Postgres => "postgres",
         }
     }
+}
 
-    println!("Connecting to database: {}", database_url);
-
-    let pool_result = AnyPoolOptions::new()
-        .max_connections(5)
-        .connect(&database_url)
-        .await;
-
-    // Fallback to SQLite if connection failed
-    let pool = match pool_result {
-        Ok(p) => p,
-        Err(e) => {
-            println!("Failed to connect to provided URL: {}. Error: {}", database_url, e);
-            println!("Falling back to local SQLite: sqlite:anime.db");
-            kind = DbKind::Sqlite;
-            if !std::path::Path::new("anime.db").exists() {
-                 std::fs::File::create("anime.db").expect("Failed to create database file");
-            }
-            AnyPoolOptions::new()
-                .max_connections(5)
-                .connect("sqlite:anime.db")
-                .await
-                .expect("Failed to connect to fallback SQLite")
+// Connects and brings the schema up to date. Connection and migration
+// failures abort startup rather than serving requests against the wrong or a
+// half-migrated database.
+pub async fn init_db(settings: &DbSettings) -> (AnyPool, DbStatus) {
+    let (pool, status) = connect(settings).await.unwrap_or_else(|e| {
+        eprintln!("Database unavailable: {}", e);
+        std::process::exit(1);
+    });
+
+    println!(
+        "Database: {} at {}{}{}",
+        status.kind.name(),
+        status.url,
+        if status.strict { " (strict)" } else { "" },
+        if status.fallback { " -- FALLBACK, the configured database is unreachable" } else { "" },
+    );
+
+    if let Err(e) = run_migrations(&pool, status.kind).await {
+        eprintln!("Database migration failed: {}", e);
+        std::process::exit(1);
+    }
+
+    (pool, status)
+}
+
+// Connects without touching the schema, retrying DATABASE_URL with backoff and
+// then trying the fallback, if the policy allows one.
+pub async fn connect(settings: &DbSettings) -> Result<(AnyPool, DbStatus), String> {
+    // Install default drivers for AnyPool
+    sqlx::any::install_default_drivers();
+
+    let primary = connect_with_retry(&settings.url, settings).await;
+    let (pool, url, fallback) = match (primary, &settings.fallback_url) {
+        (Ok(pool), _) => (pool, &settings.url, false),
+        (Err(e), None) => return Err(e),
+        (Err(e), Some(fallback_url)) => {
+            eprintln!("WARNING: {}. Falling back to {}", e, redact_url(fallback_url));
+            let single = DbSettings { attempts: 1, ..settings.clone() };
+            (connect_with_retry(fallback_url, &single).await?, fallback_url, true)
         }
     };
 
-    run_migrations(&pool, kind).await;
+    let status = DbStatus {
+        kind: DbKind::from_url(url),
+        url: redact_url(url),
+        strict: settings.strict,
+        fallback,
+    };
+    Ok((pool, status))
+}
 
-    pool
+async fn connect_with_retry(url: &str, settings: &DbSettings) -> Result<AnyPool, String> {
+    let mut delay = settings.retry_delay_ms;
+    let mut attempt = 1;
+    loop {
+        match open(url, settings).await {
+            Ok(pool) => return Ok(pool),
+            Err(e) if attempt >= settings.attempts => {
+                return Err(format!("Cannot connect to {} after {} attempt(s): {}", redact_url(url), attempt, e));
+            }
+            Err(e) => {
+                eprintln!(
+                    "Database connection attempt {}/{} to {} failed: {}. Retrying in {} ms",
+                    attempt, settings.attempts, redact_url(url), e, delay
+                );
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                delay = (delay * 2).min(MAX_RETRY_DELAY_MS);
+                attempt += 1;
+            }
+        }
+    }
 }
 
-async fn run_migrations(pool: &AnyPool, kind: DbKind) {
-    // Users
-    let users_query = r#"
-        CREATE TABLE IF NOT EXISTS users (
-            id TEXT PRIMARY KEY,
-            username TEXT NOT NULL UNIQUE,
-            password TEXT NOT NULL,
-            role TEXT NOT NULL DEFAULT 'user',
-            created_at DATETIME DEFAULT CURRENT_TIMESTAMP
-        );
-    "#;
-
-    // Anime Series
-    let anime_query = r#"
-        CREATE TABLE IF NOT EXISTS anime_series (
-            id TEXT PRIMARY KEY,
-            title TEXT NOT NULL,
-            description TEXT,
-            content_type TEXT NOT NULL,
-            status TEXT NOT NULL,
-            schedule_day TEXT,
-            thumbnail_url TEXT,
-            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-            rating REAL DEFAULT 0.0
-        );
-    "#;
-
-    // Episodes
-    let ep_query = r#"
-        CREATE TABLE IF NOT EXISTS episodes (
-            id TEXT PRIMARY KEY,
-            series_id TEXT NOT NULL,
-            title TEXT NOT NULL,
-            episode_number INTEGER NOT NULL,
-            video_path TEXT NOT NULL,
-            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-            FOREIGN KEY(series_id) REFERENCES anime_series(id) ON DELETE CASCADE
-        );
-    "#;
-
-    // Genres
-    let genre_query = format!(r#"
-        CREATE TABLE IF NOT EXISTS genres (
-            id {id_type} PRIMARY KEY {pg_suffix},
-            name TEXT NOT NULL UNIQUE
-        );
-        "#,
-        id_type = if kind == DbKind::Postgres { "SERIAL" } else { "INTEGER" },
-        pg_suffix = if kind == DbKind::Sqlite { "AUTOINCREMENT" } else { "" }
-    );
+async fn open(url: &str, settings: &DbSettings) -> Result<AnyPool, String> {
+    if DbKind::from_url(url) == DbKind::Sqlite {
+        let path = url.trim_start_matches("sqlite:");
+        if !std::path::Path::new(path).exists() && !path.contains(":memory:") {
+            println!("Creating SQLite database file: {}", path);
+            std::fs::File::create(path).map_err(|e| format!("Failed to create {}: {}", path, e))?;
+        }
+    }
 
-    // Anime Genres Junction
-    let ag_query = r#"
-        CREATE TABLE IF NOT EXISTS anime_genres (
-            anime_id TEXT NOT NULL,
-            genre_id INTEGER NOT NULL,
-            PRIMARY KEY (anime_id, genre_id),
-            FOREIGN KEY(anime_id) REFERENCES anime_series(id) ON DELETE CASCADE,
-            FOREIGN KEY(genre_id) REFERENCES genres(id) ON DELETE CASCADE
-        );
-    "#;
-
-    let queries = vec![users_query, anime_query, ep_query, &genre_query, ag_query];
-
-    for query in queries {
-        if let Err(e) = sqlx::query(query).execute(pool).await {
-            println!("Migration Warning/Error: {}", e);
+    AnyPoolOptions::new()
+        .max_connections(settings.max_connections)
+        .acquire_timeout(Duration::from_secs(settings.connect_timeout_secs))
+        .connect(url)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+// For logs and reports: keeps the host and database, hides the password
+fn redact_url(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(mut parsed) if parsed.password().is_some() => {
+            let _ = parsed.set_password(Some("***"));
+            parsed.to_string()
         }
+        _ => url.to_string(),
     }
+}
 
+// Applies pending migrations (see migrate.rs) and seeds reference data
+pub async fn run_migrations(pool: &AnyPool, kind: DbKind) -> Result<(), String> {
+    migrate::up(pool, kind, None).await?;
+    seed(&AppState::new(pool.clone(), kind)).await.map_err(|e| format!("Seeding failed: {}", e))
+}
+
+// Idempotent, so it runs on every start: new permissions reach existing roles.
+// ON CONFLICT DO NOTHING works on both SQLite and Postgres.
+async fn seed(db: &AppState) -> Result<(), sqlx::Error> {
     // Seed Genres
     let genres = vec![
         "Action", "Adventure", "Comedy", "Drama", "Fantasy",
@@ -139,14 +195,54 @@ async fn run_migrations(pool: &AnyPool, kind: DbKind) {
     ];
 
     for g in genres {
-        let insert_query = match kind {
-            DbKind::Sqlite => "INSERT OR IGNORE INTO genres (name) VALUES (?)",
-            DbKind::Postgres => "INSERT INTO genres (name) VALUES (?) ON CONFLICT (name) DO NOTHING",
-        };
-
-        let _ = sqlx::query(insert_query)
+        sqlx::query(&db.sql("INSERT INTO genres (name) VALUES (?) ON CONFLICT (name) DO NOTHING"))
             .bind(g)
-            .execute(pool)
-            .await;
+            .execute(&db.pool)
+            .await?;
+    }
+
+    // Seed Permissions
+    let permissions = vec![
+        (CONTENT_WRITE, "Create, update and delete series"),
+        (EPISODES_WRITE, "Upload videos and create episodes"),
+        (METRICS_READ, "Read system metrics"),
+        (USERS_READ, "List users"),
+        (USERS_DELETE, "Delete users"),
+        (USERS_MANAGE, "Unlock and moderate user accounts"),
+        (ROLES_MANAGE, "Manage roles and assign them to users"),
+        (AUDIT_READ, "Read and export the audit log"),
+    ];
+
+    for (name, description) in &permissions {
+        sqlx::query(&db.sql("INSERT INTO permissions (name, description) VALUES (?, ?) ON CONFLICT (name) DO NOTHING"))
+            .bind(*name)
+            .bind(*description)
+            .execute(&db.pool)
+            .await?;
+    }
+
+    // Seed built-in Roles
+    let all: Vec<&str> = permissions.iter().map(|(name, _)| *name).collect();
+    let roles: Vec<(&str, &str, Vec<&str>)> = vec![
+        ("user", "Regular viewer", vec![]),
+        ("admin", "Content administrator", vec![CONTENT_WRITE, EPISODES_WRITE, METRICS_READ, USERS_READ, USERS_MANAGE]),
+        ("superuser", "Full access", all),
+    ];
+
+    for (name, description, perms) in roles {
+        sqlx::query(&db.sql("INSERT INTO roles (name, description, builtin) VALUES (?, ?, 1) ON CONFLICT (name) DO NOTHING"))
+            .bind(name)
+            .bind(description)
+            .execute(&db.pool)
+            .await?;
+
+        for perm in perms {
+            sqlx::query(&db.sql("INSERT INTO role_permissions (role_name, permission_name) VALUES (?, ?) ON CONFLICT DO NOTHING"))
+                .bind(name)
+                .bind(perm)
+                .execute(&db.pool)
+                .await?;
+        }
     }
+    Ok(())
 }
@@ -0,0 +1,184 @@
+use actix_multipart::MultipartError;
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, HttpResponse, ResponseError};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::fmt;
+use uuid::Uuid;
+use crate::validation::ValidationErrors;
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+// Every failed request answers with an RFC 7807 problem document:
+// {"type": "about:blank", "title": "Not Found", "status": 404, "detail": "..."}
+// plus extension members (`fields` for validation, `correlation_id` for
+// internal errors, anything added with `with`).
+#[derive(Debug)]
+pub enum AppError {
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    Conflict(String),
+    Validation(ValidationErrors),
+    TooManyRequests { detail: String, retry_after: Option<u64> },
+    Unavailable(String),
+    BadGateway(String), // An upstream service such as an identity provider failed
+    // The cause is logged under the id; clients only get the id
+    Internal { correlation_id: String },
+    Extended(Box<AppError>, Map<String, Value>),
+}
+
+impl AppError {
+    pub fn internal(cause: impl fmt::Display) -> Self {
+        let correlation_id = Uuid::new_v4().to_string();
+        log::error!("[{}] {}", correlation_id, cause);
+        AppError::Internal { correlation_id }
+    }
+
+    // `store` names what Redis holds for the request, e.g. "Invite store"
+    pub fn unavailable(store: &str, cause: impl fmt::Display) -> Self {
+        log::error!("Redis error: {}", cause);
+        AppError::Unavailable(format!("{} unavailable", store))
+    }
+
+    // Adds an extension member, e.g. the permission a request was missing
+    pub fn with(self, key: &str, value: impl Serialize) -> Self {
+        let (inner, mut extra) = match self {
+            AppError::Extended(inner, extra) => (inner, extra),
+            other => (Box::new(other), Map::new()),
+        };
+        extra.insert(key.to_string(), json!(value));
+        AppError::Extended(inner, extra)
+    }
+
+    fn detail(&self) -> &str {
+        match self {
+            AppError::BadRequest(d)
+            | AppError::Unauthorized(d)
+            | AppError::Forbidden(d)
+            | AppError::NotFound(d)
+            | AppError::Conflict(d)
+            | AppError::Unavailable(d)
+            | AppError::BadGateway(d)
+            | AppError::TooManyRequests { detail: d, .. } => d,
+            AppError::Validation(_) => "Validation failed",
+            AppError::Internal { .. } => "Internal server error",
+            AppError::Extended(inner, _) => inner.detail(),
+        }
+    }
+
+    fn problem(&self) -> Value {
+        let status = self.status_code();
+        let mut body = json!({
+            "type": "about:blank",
+            "title": status.canonical_reason().unwrap_or("Error"),
+            "status": status.as_u16(),
+            "detail": self.detail(),
+        });
+        match self {
+            AppError::Validation(errors) => body["fields"] = json!(errors.fields()),
+            AppError::Internal { correlation_id } => body["correlation_id"] = json!(correlation_id),
+            AppError::Extended(inner, extra) => {
+                body = inner.problem();
+                for (k, v) in extra {
+                    body[k] = v.clone();
+                }
+            }
+            _ => {}
+        }
+        body
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.status_code(), self.detail())
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::BadGateway(_) => StatusCode::BAD_GATEWAY,
+            AppError::Internal { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::Extended(inner, _) => inner.status_code(),
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut res = HttpResponse::build(self.status_code());
+        res.content_type(PROBLEM_JSON);
+        if let AppError::TooManyRequests { retry_after: Some(secs), .. } = self {
+            res.insert_header((header::RETRY_AFTER, secs.to_string()));
+        }
+        res.body(self.problem().to_string())
+    }
+}
+
+impl From<ValidationErrors> for AppError {
+    fn from(errors: ValidationErrors) -> Self {
+        AppError::Validation(errors)
+    }
+}
+
+impl From<sqlx::Error> for AppError {
+    fn from(e: sqlx::Error) -> Self {
+        match &e {
+            sqlx::Error::RowNotFound => AppError::NotFound("Not found".to_string()),
+            sqlx::Error::Database(d) if d.is_unique_violation() => AppError::Conflict("Already exists".to_string()),
+            _ => AppError::internal(e),
+        }
+    }
+}
+
+impl From<redis::RedisError> for AppError {
+    fn from(e: redis::RedisError) -> Self {
+        AppError::unavailable("Session store", e)
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for AppError {
+    fn from(e: jsonwebtoken::errors::Error) -> Self {
+        use jsonwebtoken::errors::ErrorKind;
+        match e.kind() {
+            // Our own keys or claims are broken, not the client's token
+            ErrorKind::InvalidEcdsaKey | ErrorKind::InvalidRsaKey(_) | ErrorKind::RsaFailedSigning
+            | ErrorKind::InvalidKeyFormat | ErrorKind::Json(_) | ErrorKind::Utf8(_) | ErrorKind::Crypto(_) => AppError::internal(e),
+            ErrorKind::ExpiredSignature => AppError::Unauthorized("Token expired".to_string()),
+            _ => AppError::Unauthorized("Invalid token".to_string()),
+        }
+    }
+}
+
+impl From<MultipartError> for AppError {
+    fn from(e: MultipartError) -> Self {
+        AppError::BadRequest(format!("Invalid upload: {}", e))
+    }
+}
+
+impl From<std::io::Error> for AppError {
+    fn from(e: std::io::Error) -> Self {
+        AppError::internal(e)
+    }
+}
+
+// Malformed bodies, query strings and paths, and unknown routes, answer with
+// the same problem documents as the handlers.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.app_data(web::JsonConfig::default().error_handler(|e, _| AppError::BadRequest(e.to_string()).into()))
+        .app_data(web::QueryConfig::default().error_handler(|e, _| AppError::BadRequest(e.to_string()).into()))
+        .app_data(web::PathConfig::default().error_handler(|e, _| AppError::BadRequest(e.to_string()).into()));
+}
+
+pub async fn not_found() -> Result<HttpResponse, AppError> {
+    Err(AppError::NotFound("No such route".to_string()))
+}
@@ -0,0 +1,176 @@
+use actix_web::{web, HttpResponse, HttpRequest};
+use crate::db::AppState;
+use crate::error::AppError;
+use crate::handlers::common::claims;
+use bcrypt::{hash, DEFAULT_COST};
+use crate::auth::Keyring;
+use crate::config::Config;
+use crate::models::user::{User, ForgotPasswordRequest, ResetPasswordRequest, VerifyEmailRequest, USER_COLUMNS};
+use crate::services::lockout;
+use crate::services::mailer::{Mailer, OutgoingEmail};
+use crate::services::redis::{SessionStore, create_one_time_token, consume_one_time_token, peek_one_time_token, revoke_user};
+use crate::validation::{ValidationErrors, validate_password_field, is_breached_password};
+use serde_json::json;
+
+const RESET_TTL_SECONDS: u64 = 3600;
+const VERIFY_TTL_SECONDS: u64 = 24 * 3600;
+
+// Links in emails point at the web frontend (server.base_url), which posts
+// the token back to the API
+fn app_url(base_url: &str, path: &str, token: &str) -> String {
+    format!("{}/{}?token={}", base_url.trim_end_matches('/'), path, token)
+}
+
+// Verification tokens are bound to the address they were sent to, so changing
+// the email invalidates links sent to the old one.
+pub async fn send_verification_email(redis: &dyn SessionStore, mailer: &dyn Mailer, base_url: &str, user_id: &str, email: &str) -> Result<(), String> {
+    let token = create_one_time_token(redis, "verify", &format!("{}|{}", user_id, email), VERIFY_TTL_SECONDS)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    mailer.send(&OutgoingEmail {
+        to: email.to_string(),
+        subject: "Confirm your email address".to_string(),
+        body: format!(
+            "Confirm your email address by opening this link within 24 hours:\n\n{}\n\nIf you did not create an account, ignore this email.",
+            app_url(base_url, "verify-email", &token)
+        ),
+    }).await
+}
+
+// Always answers the same way so the endpoint cannot be used to discover accounts
+pub async fn forgot_password(
+    db: web::Data<AppState>,
+    redis: web::Data<dyn SessionStore>,
+    mailer: web::Data<dyn Mailer>,
+    config: web::Data<Config>,
+    req: web::Json<ForgotPasswordRequest>,
+) -> Result<HttpResponse, AppError> {
+    let user: Option<User> = sqlx::query_as(&db.sql(&format!("SELECT {} FROM users WHERE LOWER(email) = LOWER(?) AND email_verified = 1", USER_COLUMNS)))
+        .bind(req.email.trim())
+        .fetch_optional(&db.pool)
+        .await?;
+
+    if let Some(u) = user {
+        // Sent in the background so response time does not reveal whether the account exists
+        tokio::spawn(async move {
+            let token = match create_one_time_token(redis.get_ref(), "reset", &u.id, RESET_TTL_SECONDS).await {
+                Ok(t) => t,
+                Err(e) => return log::error!("Redis error: {}", e),
+            };
+            let email = OutgoingEmail {
+                to: u.email.clone().unwrap_or_default(),
+                subject: "Reset your password".to_string(),
+                body: format!(
+                    "Someone asked to reset the password of {}. Open this link within an hour to choose a new one:\n\n{}\n\nIf it was not you, ignore this email.",
+                    u.username,
+                    app_url(&config.server.base_url, "reset-password", &token)
+                ),
+            };
+            if let Err(e) = mailer.send(&email).await {
+                log::error!("Failed to send reset email: {}", e);
+            }
+        });
+    }
+
+    Ok(HttpResponse::Ok().json(json!({"message": "If the address belongs to a verified account, a reset link has been sent"})))
+}
+
+pub async fn reset_password(
+    db: web::Data<AppState>,
+    redis: web::Data<dyn SessionStore>,
+    keys: web::Data<Keyring>,
+    config: web::Data<Config>,
+    req: web::Json<ResetPasswordRequest>,
+) -> Result<HttpResponse, AppError> {
+    let invalid = || AppError::BadRequest("Invalid or expired reset token".to_string());
+    // Peek first: the password rules need the username, and a rejected
+    // password should leave the link usable for another try
+    let user_id = peek_one_time_token(redis.get_ref(), "reset", &req.token).await
+        .map_err(|e| AppError::unavailable("Token store", e))?
+        .ok_or_else(invalid)?;
+
+    let user: User = sqlx::query_as(&db.sql(&format!("SELECT {} FROM users WHERE id = ?", USER_COLUMNS)))
+        .bind(&user_id)
+        .fetch_optional(&db.pool)
+        .await?
+        .ok_or_else(invalid)?;
+
+    let mut errors = ValidationErrors::default();
+    validate_password_field("new_password", &req.new_password, &user.username, &mut errors);
+    if is_breached_password(&config.auth, &req.new_password).await {
+        errors.add("new_password", "appears in a known data breach");
+    }
+    if !errors.is_empty() {
+        return Err(errors.into());
+    }
+
+    // Single use: of two concurrent resets only one gets here
+    let consumed = consume_one_time_token(redis.get_ref(), "reset", &req.token).await
+        .map_err(|e| AppError::unavailable("Token store", e))?;
+    if consumed.as_deref() != Some(user.id.as_str()) {
+        return Err(invalid());
+    }
+
+    let hashed_password = hash(&req.new_password, DEFAULT_COST).map_err(|e| AppError::internal(format!("bcrypt error: {}", e)))?;
+
+    sqlx::query(&db.sql("UPDATE users SET password = ? WHERE id = ?"))
+        .bind(&hashed_password)
+        .bind(&user.id)
+        .execute(&db.pool)
+        .await?;
+
+    // Whoever knew the old password is logged out everywhere
+    if let Err(e) = revoke_user(redis.get_ref(), &user.id, keys.settings.access_ttl_seconds).await {
+        log::error!("Failed to revoke tokens of user {}: {}", user.id, e);
+    }
+    let _ = lockout::unlock(redis.get_ref(), &user.username).await;
+
+    Ok(HttpResponse::Ok().json(json!({"message": "Password updated"})))
+}
+
+pub async fn verify_email(
+    db: web::Data<AppState>,
+    redis: web::Data<dyn SessionStore>,
+    req: web::Json<VerifyEmailRequest>,
+) -> Result<HttpResponse, AppError> {
+    let value = consume_one_time_token(redis.get_ref(), "verify", &req.token).await
+        .map_err(|e| AppError::unavailable("Token store", e))?
+        .ok_or_else(|| AppError::BadRequest("Invalid or expired verification token".to_string()))?;
+    let (user_id, email) = value.split_once('|').unwrap_or((&value, ""));
+
+    let result = sqlx::query(&db.sql("UPDATE users SET email_verified = 1 WHERE id = ? AND email = ?"))
+        .bind(user_id)
+        .bind(email)
+        .execute(&db.pool)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(AppError::BadRequest("Email address has changed, request a new link".to_string()));
+    }
+    Ok(HttpResponse::Ok().json(json!({"message": "Email verified"})))
+}
+
+pub async fn resend_verification(
+    db: web::Data<AppState>,
+    redis: web::Data<dyn SessionStore>,
+    mailer: web::Data<dyn Mailer>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let user_id = claims(&req)?.sub;
+
+    let user: Option<User> = sqlx::query_as(&db.sql(&format!("SELECT {} FROM users WHERE id = ?", USER_COLUMNS)))
+        .bind(&user_id)
+        .fetch_optional(&db.pool)
+        .await?;
+
+    let (id, email) = match user {
+        None => return Err(AppError::NotFound("User not found".to_string())),
+        Some(u) if u.email_verified != 0 => return Err(AppError::BadRequest("Email already verified".to_string())),
+        Some(User { email: None, .. }) => return Err(AppError::BadRequest("No email address on the account".to_string())),
+        Some(User { id, email: Some(email), .. }) => (id, email),
+    };
+    send_verification_email(redis.get_ref(), mailer.get_ref(), &config.server.base_url, &id, &email).await.map_err(AppError::internal)?;
+    Ok(HttpResponse::Ok().json(json!({"message": "Verification email sent"})))
+}
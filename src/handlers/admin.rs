@@ -1,183 +1,114 @@
-use actix_web::{web, HttpResponse, Responder};
-use sqlx::AnyPool;
-use uuid::Uuid;
+use actix_web::{web, HttpRequest, HttpResponse};
+use sqlx::{Any, AnyConnection, Transaction};
+use crate::config::Config;
+use crate::db::AppState;
+use chrono::{DateTime, Utc};
+use crate::error::AppError;
 use crate::models::content::{CreateAnimeRequest, CreateEpisodeRequest, UpdateAnimeRequest};
-use crate::models::user::User;
+use crate::models::user::{BanUserRequest, SuspendUserRequest, UserListQuery};
+use crate::models::role::{AssignRoleRequest, CreateRoleRequest, Permission, Role};
+use crate::auth::Keyring;
+use crate::handlers::common::claims;
+use crate::models::TokenClaims;
+use crate::repositories::{UserChange, UserFilter, UserStatus, UserWrite};
+use crate::services::api_keys::TIMESTAMP_FORMAT;
+use crate::services::rbac::{permissions_for_role, SUPERUSER_ROLE};
+use crate::services::redis::{SessionStore, revoke_user, revoke_all_access_tokens, create_invite as store_invite};
+use crate::services::audit::{self, Actor};
+use crate::services::lockout;
 use crate::services::video::save_video;
 use actix_multipart::Multipart;
 use sys_info;
-use serde_json::json;
+use serde_json::{json, Value};
 
-pub async fn create_anime(
-    pool: web::Data<AnyPool>,
-    req: web::Json<CreateAnimeRequest>,
-) -> impl Responder {
-    let id = Uuid::new_v4().to_string();
+// Writes the audit entry inside the transaction of the change it describes
+#[allow(clippy::too_many_arguments)]
+async fn audit_in(
+    tx: &mut Transaction<'_, Any>,
+    db: &AppState,
+    actor: &Actor,
+    action: &str,
+    target_type: &str,
+    target_id: &str,
+    before: Option<Value>,
+    after: Option<Value>,
+) -> Result<(), sqlx::Error> {
+    audit::record(&mut *tx, db.kind, actor, action, target_type, target_id, before, after).await
+}
 
-    // Transaction
-    let mut tx = match pool.begin().await {
-        Ok(t) => t,
-        Err(e) => return HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+async fn role_snapshot(conn: &mut AnyConnection, db: &AppState, name: &str) -> Result<Option<Value>, sqlx::Error> {
+    let mut snapshot = match audit::snapshot(&mut *conn, db.kind, "SELECT name, description FROM roles WHERE name = ?", name).await? {
+        Some(s) => s,
+        None => return Ok(None),
     };
+    let permissions: Vec<(String,)> = sqlx::query_as(&db.sql("SELECT permission_name FROM role_permissions WHERE role_name = ? ORDER BY permission_name"))
+        .bind(name)
+        .fetch_all(&mut *conn)
+        .await?;
+    snapshot["permissions"] = json!(permissions.into_iter().map(|(p,)| p).collect::<Vec<_>>());
+    Ok(Some(snapshot))
+}
 
-    let res = sqlx::query(
-        "INSERT INTO anime_series (id, title, description, content_type, status, schedule_day, rating) VALUES (?, ?, ?, ?, ?, ?, ?)"
-    )
-    .bind(&id)
-    .bind(&req.title)
-    .bind(&req.description)
-    .bind(&req.content_type)
-    .bind(&req.status)
-    .bind(&req.schedule_day)
-    .bind(req.rating.unwrap_or(0.0))
-    .execute(&mut *tx)
-    .await;
-
-    if let Err(e) = res {
-        return HttpResponse::InternalServerError().json(json!({"error": e.to_string()}));
-    }
-
-    if let Some(g_ids) = &req.genre_ids {
-        for gid in g_ids {
-            let g_res = sqlx::query("INSERT INTO anime_genres (anime_id, genre_id) VALUES (?, ?)")
-                .bind(&id)
-                .bind(gid)
-                .execute(&mut *tx)
-                .await;
-            if let Err(e) = g_res {
-                 let _ = tx.rollback().await;
-                 return HttpResponse::InternalServerError().json(json!({"error": e.to_string()}));
-            }
-        }
-    }
+fn content_not_found() -> AppError {
+    AppError::NotFound("Content not found".to_string())
+}
 
-    if let Err(e) = tx.commit().await {
-        return HttpResponse::InternalServerError().json(json!({"error": e.to_string()}));
-    }
+fn user_not_found() -> AppError {
+    AppError::NotFound("User not found".to_string())
+}
 
-    HttpResponse::Ok().json(json!({"message": "Content created", "id": id}))
+pub async fn create_anime(
+    db: web::Data<AppState>,
+    http_req: HttpRequest,
+    req: web::Json<CreateAnimeRequest>,
+) -> Result<HttpResponse, AppError> {
+    let id = db.repos.content.create(&Actor::from_request(&http_req), &req).await?;
+    Ok(HttpResponse::Ok().json(json!({"message": "Content created", "id": id})))
 }
 
 pub async fn update_anime(
-    pool: web::Data<AnyPool>,
+    db: web::Data<AppState>,
+    http_req: HttpRequest,
     path: web::Path<String>,
     req: web::Json<UpdateAnimeRequest>,
-) -> impl Responder {
-    let id = path.into_inner();
-
-    let mut tx = match pool.begin().await {
-        Ok(t) => t,
-        Err(e) => return HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
-    };
-
-    let result = sqlx::query(
-        "UPDATE anime_series SET
-            title = COALESCE(?, title),
-            description = COALESCE(?, description),
-            content_type = COALESCE(?, content_type),
-            status = COALESCE(?, status),
-            schedule_day = COALESCE(?, schedule_day),
-            rating = COALESCE(?, rating)
-        WHERE id = ?"
-    )
-    .bind(&req.title)
-    .bind(&req.description)
-    .bind(&req.content_type)
-    .bind(&req.status)
-    .bind(&req.schedule_day)
-    .bind(req.rating)
-    .bind(&id)
-    .execute(&mut *tx)
-    .await;
-
-    if let Err(e) = result {
-         return HttpResponse::InternalServerError().json(json!({"error": e.to_string()}));
-    }
-
-    // Handle genres update: clear old, insert new (simplest strategy)
-    if let Some(g_ids) = &req.genre_ids {
-        let del = sqlx::query("DELETE FROM anime_genres WHERE anime_id = ?")
-            .bind(&id)
-            .execute(&mut *tx)
-            .await;
-
-        if let Err(e) = del {
-             let _ = tx.rollback().await;
-             return HttpResponse::InternalServerError().json(json!({"error": e.to_string()}));
-        }
-
-        for gid in g_ids {
-            let g_res = sqlx::query("INSERT INTO anime_genres (anime_id, genre_id) VALUES (?, ?)")
-                .bind(&id)
-                .bind(gid)
-                .execute(&mut *tx)
-                .await;
-            if let Err(e) = g_res {
-                 let _ = tx.rollback().await;
-                 return HttpResponse::InternalServerError().json(json!({"error": e.to_string()}));
-            }
-        }
+) -> Result<HttpResponse, AppError> {
+    if !db.repos.content.update(&Actor::from_request(&http_req), &path.into_inner(), &req).await? {
+        return Err(content_not_found());
     }
-
-    if let Err(e) = tx.commit().await {
-        return HttpResponse::InternalServerError().json(json!({"error": e.to_string()}));
-    }
-
-    HttpResponse::Ok().json(json!({"message": "Content updated"}))
+    Ok(HttpResponse::Ok().json(json!({"message": "Content updated"})))
 }
 
 pub async fn delete_anime(
-    pool: web::Data<AnyPool>,
+    db: web::Data<AppState>,
+    http_req: HttpRequest,
     path: web::Path<String>,
-) -> impl Responder {
-    let id = path.into_inner();
-    let result = sqlx::query("DELETE FROM anime_series WHERE id = ?")
-        .bind(&id)
-        .execute(pool.get_ref())
-        .await;
-
-    match result {
-        Ok(_) => HttpResponse::Ok().json(json!({"message": "Content deleted"})),
-        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+) -> Result<HttpResponse, AppError> {
+    if !db.repos.content.delete(&Actor::from_request(&http_req), &path.into_inner()).await? {
+        return Err(content_not_found());
     }
+    Ok(HttpResponse::Ok().json(json!({"message": "Content deleted"})))
 }
 
 pub async fn upload_episode(
-    pool: web::Data<AnyPool>,
+    config: web::Data<Config>,
     payload: Multipart,
-) -> impl Responder {
-    match save_video(payload).await {
-        Ok(path) => HttpResponse::Ok().json(json!({"path": path})),
-        Err(_) => HttpResponse::InternalServerError().json(json!({"error": "Upload failed"})),
-    }
+) -> Result<HttpResponse, AppError> {
+    let path = save_video(payload, &config.server.upload_dir).await?;
+    Ok(HttpResponse::Ok().json(json!({"path": path})))
 }
 
 pub async fn create_episode_meta(
-    pool: web::Data<AnyPool>,
+    db: web::Data<AppState>,
+    http_req: HttpRequest,
     req: web::Json<CreateEpisodeRequest>,
     video_path: web::Query<std::collections::HashMap<String, String>>,
-) -> impl Responder {
+) -> Result<HttpResponse, AppError> {
     let path = video_path.get("path").cloned().unwrap_or_default();
-    let id = Uuid::new_v4().to_string();
-
-    let result = sqlx::query(
-        "INSERT INTO episodes (id, series_id, title, episode_number, video_path) VALUES (?, ?, ?, ?, ?)"
-    )
-    .bind(&id)
-    .bind(&req.series_id)
-    .bind(&req.title)
-    .bind(req.episode_number)
-    .bind(&path)
-    .execute(pool.get_ref())
-    .await;
-
-    match result {
-        Ok(_) => HttpResponse::Ok().json(json!({"message": "Episode created", "id": id})),
-        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
-    }
+    let id = db.repos.episodes.create(&Actor::from_request(&http_req), &req, &path).await?;
+    Ok(HttpResponse::Ok().json(json!({"message": "Episode created", "id": id})))
 }
 
-pub async fn get_system_metrics() -> impl Responder {
+pub async fn get_system_metrics() -> HttpResponse {
     let load = sys_info::loadavg().unwrap_or(sys_info::LoadAvg { one: 0.0, five: 0.0, fifteen: 0.0 });
     let mem = sys_info::mem_info().unwrap_or(sys_info::MemInfo { total: 0, free: 0, avail: 0, buffers: 0, cached: 0, swap_total: 0, swap_free: 0 });
     let disk = sys_info::disk_info().unwrap_or(sys_info::DiskInfo { total: 0, free: 0 });
@@ -191,28 +122,387 @@ pub async fn get_system_metrics() -> impl Responder {
     }))
 }
 
+const DEFAULT_PAGE_SIZE: i64 = 20;
+const MAX_PAGE_SIZE: i64 = 100;
+// Built-in roles in order of privilege, for promote/demote
+const ROLE_LADDER: &[&str] = &["user", "admin", SUPERUSER_ROLE];
+
+// Paginated user list. `q` matches username or email; `status` is one of
+// active, suspended or banned.
 pub async fn get_users(
-    pool: web::Data<AnyPool>,
-) -> impl Responder {
-    let users: Vec<User> = sqlx::query_as("SELECT * FROM users")
-        .fetch_all(pool.get_ref())
-        .await
-        .unwrap_or(vec![]);
-    HttpResponse::Ok().json(users)
+    db: web::Data<AppState>,
+    query: web::Query<UserListQuery>,
+) -> Result<HttpResponse, AppError> {
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query.per_page.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    // The row offset has to fit the LIMIT/OFFSET bind
+    if (page - 1).checked_mul(per_page).is_none() {
+        return Err(AppError::BadRequest("page is too large".to_string()));
+    }
+    let non_empty = |v: &Option<String>| v.as_deref().map(str::trim).filter(|v| !v.is_empty()).map(str::to_string);
+    let status = match query.status.as_deref() {
+        None | Some("") => None,
+        Some(s) => Some(UserStatus::parse(s)
+            .ok_or_else(|| AppError::BadRequest("status must be active, suspended or banned".to_string()))?),
+    };
+    let filter = UserFilter { q: non_empty(&query.q), role: non_empty(&query.role), status };
+
+    let (users, total) = db.repos.users.list(&filter, page, per_page).await?;
+    Ok(HttpResponse::Ok().json(json!({
+        "items": users,
+        "page": page,
+        "per_page": per_page,
+        "total": total
+    })))
+}
+
+fn last_superuser_error() -> AppError {
+    AppError::Conflict("Cannot remove the last superuser".to_string())
 }
 
 pub async fn delete_user(
-    pool: web::Data<AnyPool>,
+    db: web::Data<AppState>,
+    redis: web::Data<dyn SessionStore>,
+    keys: web::Data<Keyring>,
+    http_req: HttpRequest,
     path: web::Path<String>,
-) -> impl Responder {
+) -> Result<HttpResponse, AppError> {
     let id = path.into_inner();
-    let result = sqlx::query("DELETE FROM users WHERE id = ?")
-        .bind(&id)
-        .execute(pool.get_ref())
+    ensure_outranks(&db, &claims(&http_req)?, &id).await?;
+    match db.repos.users.delete(&Actor::from_request(&http_req), &id).await? {
+        UserWrite::Done => {}
+        UserWrite::NotFound => return Err(user_not_found()),
+        UserWrite::LastSuperuser => return Err(last_superuser_error()),
+    }
+
+    // Outstanding tokens would otherwise keep working until they expire
+    revoke_user(redis.get_ref(), &id, keys.settings.access_ttl_seconds).await?;
+    Ok(HttpResponse::Ok().json(json!({"message": "User deleted"})))
+}
+
+pub async fn get_roles(
+    db: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let mut roles: Vec<Role> = sqlx::query_as("SELECT name, description, builtin FROM roles ORDER BY name")
+        .fetch_all(&db.pool)
+        .await?;
+
+    for r in &mut roles {
+        r.permissions = permissions_for_role(&db, &r.name).await?;
+    }
+    Ok(HttpResponse::Ok().json(roles))
+}
+
+pub async fn get_permissions(
+    db: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let permissions: Vec<Permission> = sqlx::query_as("SELECT * FROM permissions ORDER BY name")
+        .fetch_all(&db.pool)
+        .await?;
+    Ok(HttpResponse::Ok().json(permissions))
+}
+
+pub async fn create_role(
+    db: web::Data<AppState>,
+    http_req: HttpRequest,
+    req: web::Json<CreateRoleRequest>,
+) -> Result<HttpResponse, AppError> {
+    let name = req.name.trim().to_lowercase();
+    if name.is_empty() {
+        return Err(AppError::BadRequest("Role name is required".to_string()));
+    }
+
+    // Reject unknown permission names up front instead of relying on FK enforcement
+    let known: Vec<(String,)> = sqlx::query_as("SELECT name FROM permissions")
+        .fetch_all(&db.pool)
+        .await?;
+    let unknown: Vec<&String> = req.permissions.iter()
+        .filter(|p| !known.iter().any(|(k,)| k == *p))
+        .collect();
+    if !unknown.is_empty() {
+        return Err(AppError::BadRequest("Unknown permissions".to_string()).with("permissions", unknown));
+    }
+    ensure_grantable(&claims(&http_req)?, &name, &req.permissions)?;
+
+    // Dropping the transaction on an early return rolls it back
+    let mut tx = db.pool.begin().await?;
+
+    let res = sqlx::query(&db.sql("INSERT INTO roles (name, description, builtin) VALUES (?, ?, 0)"))
+        .bind(&name)
+        .bind(&req.description)
+        .execute(&mut *tx)
         .await;
+    if res.is_err() {
+        return Err(AppError::Conflict("Role already exists".to_string()));
+    }
+
+    for perm in &req.permissions {
+        sqlx::query(&db.sql("INSERT INTO role_permissions (role_name, permission_name) VALUES (?, ?)"))
+            .bind(&name)
+            .bind(perm)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    let after = role_snapshot(&mut tx, &db, &name).await?;
+    audit_in(&mut tx, &db, &Actor::from_request(&http_req), "role.create", "role", &name, None, after).await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(json!({"message": "Role created", "name": name})))
+}
+
+pub async fn delete_role(
+    db: web::Data<AppState>,
+    http_req: HttpRequest,
+    path: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let name = path.into_inner();
+
+    let role: Option<Role> = sqlx::query_as(&db.sql("SELECT name, description, builtin FROM roles WHERE name = ?"))
+        .bind(&name)
+        .fetch_optional(&db.pool)
+        .await?;
+
+    match role {
+        None => return Err(AppError::NotFound("Role not found".to_string())),
+        Some(r) if r.builtin != 0 => return Err(AppError::BadRequest("Built-in roles cannot be deleted".to_string())),
+        Some(_) => {}
+    }
+
+    let in_use: (i64,) = sqlx::query_as(&db.sql("SELECT COUNT(*) FROM users WHERE role = ?"))
+        .bind(&name)
+        .fetch_one(&db.pool)
+        .await?;
+    if in_use.0 > 0 {
+        return Err(AppError::Conflict("Role is assigned to users".to_string()));
+    }
+
+    let mut tx = db.pool.begin().await?;
+    let before = role_snapshot(&mut tx, &db, &name).await?;
+
+    // Explicit delete: SQLite only cascades with PRAGMA foreign_keys enabled
+    sqlx::query(&db.sql("DELETE FROM role_permissions WHERE role_name = ?"))
+        .bind(&name)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(&db.sql("DELETE FROM roles WHERE name = ?"))
+        .bind(&name)
+        .execute(&mut *tx)
+        .await?;
+
+    audit_in(&mut tx, &db, &Actor::from_request(&http_req), "role.delete", "role", &name, before, None).await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(json!({"message": "Role deleted"})))
+}
+
+pub async fn assign_role(
+    db: web::Data<AppState>,
+    redis: web::Data<dyn SessionStore>,
+    keys: web::Data<Keyring>,
+    http_req: HttpRequest,
+    path: web::Path<String>,
+    req: web::Json<AssignRoleRequest>,
+) -> Result<HttpResponse, AppError> {
+    let id = path.into_inner();
+
+    let exists: (i64,) = sqlx::query_as(&db.sql("SELECT COUNT(*) FROM roles WHERE name = ?"))
+        .bind(&req.role)
+        .fetch_one(&db.pool)
+        .await?;
+    if exists.0 == 0 {
+        return Err(AppError::BadRequest("Unknown role".to_string()));
+    }
+
+    let caller = claims(&http_req)?;
+    set_role(&db, redis.get_ref(), keys.get_ref(), &Actor::from_request(&http_req), &caller, &id, &req.role).await
+}
+
+// ROLES_MANAGE alone must not be a way up: callers can only hand out
+// permissions they hold, and only a superuser can make another one.
+fn ensure_grantable(caller: &TokenClaims, role: &str, permissions: &[String]) -> Result<(), AppError> {
+    if role == SUPERUSER_ROLE && caller.role != SUPERUSER_ROLE {
+        return Err(AppError::Forbidden("Only a superuser can grant the superuser role".to_string()));
+    }
+    let missing: Vec<&String> = permissions.iter().filter(|p| !caller.permissions.contains(p)).collect();
+    if !missing.is_empty() {
+        return Err(AppError::Forbidden("Cannot grant permissions you do not hold".to_string()).with("permissions", missing));
+    }
+    Ok(())
+}
+
+// Accounts above the caller are off limits: only a superuser can act on
+// another superuser, and nobody on a user holding permissions they lack.
+async fn ensure_outranks(db: &AppState, caller: &TokenClaims, id: &str) -> Result<(), AppError> {
+    let target = db.repos.users.find(id).await?.ok_or_else(user_not_found)?;
+    if target.role == SUPERUSER_ROLE && caller.role != SUPERUSER_ROLE {
+        return Err(AppError::Forbidden("Only a superuser can change a superuser".to_string()));
+    }
+    let missing: Vec<String> = permissions_for_role(db, &target.role).await?.into_iter()
+        .filter(|p| !caller.permissions.contains(p))
+        .collect();
+    if !missing.is_empty() {
+        return Err(AppError::Forbidden("Cannot change a user holding permissions you do not hold".to_string()).with("permissions", missing));
+    }
+    Ok(())
+}
+
+// Applies the change and records it; NotFound when there is no such user,
+// Conflict when it would leave no active superuser
+async fn update_user(db: &AppState, actor: &Actor, id: &str, change: UserChange) -> Result<(), AppError> {
+    match db.repos.users.update(actor, id, &change).await? {
+        UserWrite::Done => Ok(()),
+        UserWrite::NotFound => Err(user_not_found()),
+        UserWrite::LastSuperuser => Err(last_superuser_error()),
+    }
+}
+
+async fn set_role(db: &AppState, redis: &dyn SessionStore, keys: &Keyring, actor: &Actor, caller: &TokenClaims, id: &str, role: &str) -> Result<HttpResponse, AppError> {
+    ensure_outranks(db, caller, id).await?;
+    ensure_grantable(caller, role, &permissions_for_role(db, role).await?)?;
+    update_user(db, actor, id, UserChange::Role(role.to_string())).await?;
+    // Force a refresh so the new permissions are picked up; sessions stay valid
+    revoke_all_access_tokens(redis, id, keys.settings.access_ttl_seconds).await?;
+    Ok(HttpResponse::Ok().json(json!({"message": "Role assigned", "role": role})))
+}
+
+// Moves the user one step along user -> admin -> superuser. Custom roles are
+// not on the ladder and are changed with PUT /users/{id}/role.
+async fn step_role(db: &AppState, redis: &dyn SessionStore, keys: &Keyring, http_req: &HttpRequest, id: &str, up: bool) -> Result<HttpResponse, AppError> {
+    let current = db.repos.users.find(id).await?.ok_or_else(user_not_found)?.role;
+    let position = ROLE_LADDER.iter().position(|r| *r == current)
+        .ok_or_else(|| AppError::BadRequest(format!("Role '{}' is not one of {}", current, ROLE_LADDER.join(", "))))?;
+    let next = if up { ROLE_LADDER.get(position + 1) } else { position.checked_sub(1).and_then(|p| ROLE_LADDER.get(p)) };
+    match next {
+        Some(role) => set_role(db, redis, keys, &Actor::from_request(http_req), &claims(http_req)?, id, role).await,
+        None => Err(AppError::BadRequest(if up { "User already has the highest role" } else { "User already has the lowest role" }.to_string())),
+    }
+}
+
+pub async fn promote_user(
+    db: web::Data<AppState>,
+    redis: web::Data<dyn SessionStore>,
+    keys: web::Data<Keyring>,
+    http_req: HttpRequest,
+    path: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    step_role(&db, redis.get_ref(), keys.get_ref(), &http_req, &path.into_inner(), true).await
+}
 
-    match result {
-        Ok(_) => HttpResponse::Ok().json(json!({"message": "User deleted"})),
-        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+pub async fn demote_user(
+    db: web::Data<AppState>,
+    redis: web::Data<dyn SessionStore>,
+    keys: web::Data<Keyring>,
+    http_req: HttpRequest,
+    path: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    step_role(&db, redis.get_ref(), keys.get_ref(), &http_req, &path.into_inner(), false).await
+}
+
+// Refuses bans and suspensions of the caller; the repository refuses the
+// last superuser when it applies the change
+fn check_restrictable(actor: &Actor, id: &str) -> Result<(), AppError> {
+    if actor.id == id {
+        return Err(AppError::BadRequest("You cannot restrict your own account".to_string()));
     }
+    Ok(())
+}
+
+// Ends every session of a newly restricted user, so the middleware rejects
+// tokens issued before the change; login and refresh check the row itself.
+// A 503 here means the restriction is stored but old tokens may still work.
+async fn finish_restriction(redis: &dyn SessionStore, keys: &Keyring, id: &str, message: &str) -> Result<HttpResponse, AppError> {
+    revoke_user(redis, id, keys.settings.access_ttl_seconds).await?;
+    Ok(HttpResponse::Ok().json(json!({"message": message})))
+}
+
+pub async fn suspend_user(
+    db: web::Data<AppState>,
+    redis: web::Data<dyn SessionStore>,
+    keys: web::Data<Keyring>,
+    http_req: HttpRequest,
+    path: web::Path<String>,
+    body: web::Json<SuspendUserRequest>,
+) -> Result<HttpResponse, AppError> {
+    let id = path.into_inner();
+    let actor = Actor::from_request(&http_req);
+    let until = match DateTime::parse_from_rfc3339(&body.until) {
+        Ok(t) if t.with_timezone(&Utc) > Utc::now() => t.with_timezone(&Utc).format(TIMESTAMP_FORMAT).to_string(),
+        _ => return Err(AppError::BadRequest("until must be a future RFC 3339 timestamp".to_string())),
+    };
+    check_restrictable(&actor, &id)?;
+    ensure_outranks(&db, &claims(&http_req)?, &id).await?;
+
+    let change = UserChange::Suspend { until, reason: body.reason.trim().to_string() };
+    update_user(&db, &actor, &id, change).await?;
+    finish_restriction(redis.get_ref(), keys.get_ref(), &id, "User suspended").await
+}
+
+pub async fn ban_user(
+    db: web::Data<AppState>,
+    redis: web::Data<dyn SessionStore>,
+    keys: web::Data<Keyring>,
+    http_req: HttpRequest,
+    path: web::Path<String>,
+    body: web::Json<BanUserRequest>,
+) -> Result<HttpResponse, AppError> {
+    let id = path.into_inner();
+    let actor = Actor::from_request(&http_req);
+    check_restrictable(&actor, &id)?;
+    ensure_outranks(&db, &claims(&http_req)?, &id).await?;
+
+    let change = UserChange::Ban { reason: body.reason.trim().to_string() };
+    update_user(&db, &actor, &id, change).await?;
+    finish_restriction(redis.get_ref(), keys.get_ref(), &id, "User banned").await
+}
+
+// Lifting a restriction needs no revocation: the user simply logs in again
+pub async fn unsuspend_user(db: web::Data<AppState>, http_req: HttpRequest, path: web::Path<String>) -> Result<HttpResponse, AppError> {
+    let id = path.into_inner();
+    ensure_outranks(&db, &claims(&http_req)?, &id).await?;
+    update_user(&db, &Actor::from_request(&http_req), &id, UserChange::Unsuspend).await?;
+    Ok(HttpResponse::Ok().json(json!({"message": "Suspension lifted"})))
+}
+
+pub async fn unban_user(db: web::Data<AppState>, http_req: HttpRequest, path: web::Path<String>) -> Result<HttpResponse, AppError> {
+    let id = path.into_inner();
+    ensure_outranks(&db, &claims(&http_req)?, &id).await?;
+    update_user(&db, &Actor::from_request(&http_req), &id, UserChange::Unban).await?;
+    Ok(HttpResponse::Ok().json(json!({"message": "Ban lifted"})))
+}
+
+// Lifts a login lockout (see services::lockout)
+pub async fn unlock_user(
+    db: web::Data<AppState>,
+    redis: web::Data<dyn SessionStore>,
+    http_req: HttpRequest,
+    path: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let id = path.into_inner();
+    let user = db.repos.users.find(&id).await?.ok_or_else(user_not_found)?;
+
+    lockout::unlock(redis.get_ref(), &user.username).await?;
+    // The lockout lives in Redis, so there is no transaction to share
+    audit_standalone(&db, &Actor::from_request(&http_req), "user.unlock", "user", &user.id, None).await?;
+    Ok(HttpResponse::Ok().json(json!({"message": "User unlocked"})))
+}
+
+// Invites are only needed when REGISTRATION_MODE=invite; they expire after 7 days
+pub async fn create_invite(
+    db: web::Data<AppState>,
+    redis: web::Data<dyn SessionStore>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let actor = Actor::from_request(&req);
+
+    let code = store_invite(redis.get_ref(), &actor.id, 7 * 24 * 3600).await?;
+    // The code itself stays out of the log; it is a credential until used
+    audit_standalone(&db, &actor, "invite.create", "invite", "", Some(json!({"expires_in_days": 7}))).await?;
+    Ok(HttpResponse::Ok().json(json!({"invite_code": code})))
+}
+
+// For actions whose state lives outside the database
+async fn audit_standalone(db: &AppState, actor: &Actor, action: &str, target_type: &str, target_id: &str, after: Option<Value>) -> Result<(), sqlx::Error> {
+    let mut conn = db.pool.acquire().await?;
+    audit::record(&mut conn, db.kind, actor, action, target_type, target_id, None, after).await
 }
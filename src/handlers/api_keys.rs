@@ -0,0 +1,111 @@
+use actix_web::{web, HttpResponse, HttpRequest};
+use crate::db::AppState;
+use crate::error::AppError;
+use crate::handlers::common::claims;
+use chrono::Duration;
+use uuid::Uuid;
+use crate::models::api_key::{ApiKey, CreateApiKeyRequest};
+use crate::services::api_keys::{generate_key, hash_key, split_scopes, timestamp_in};
+use crate::validation::ValidationErrors;
+use serde_json::json;
+
+const DEFAULT_EXPIRY_DAYS: i64 = 90;
+const MAX_EXPIRY_DAYS: i64 = 365;
+const MAX_NAME_LEN: usize = 64;
+
+pub async fn create_key(
+    db: web::Data<AppState>,
+    req: HttpRequest,
+    body: web::Json<CreateApiKeyRequest>,
+) -> Result<HttpResponse, AppError> {
+    let claims = claims(&req)?;
+    // A leaked key must not be able to mint longer-lived or broader ones
+    if claims.api_key.is_some() {
+        return Err(AppError::Forbidden("API keys cannot manage API keys".to_string()));
+    }
+
+    let mut errors = ValidationErrors::default();
+    let name = body.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        errors.add("name", format!("must be between 1 and {} characters", MAX_NAME_LEN));
+    }
+    for scope in &body.scopes {
+        if !claims.permissions.contains(scope) {
+            errors.add("scopes", format!("'{}' is not one of your permissions", scope));
+        }
+    }
+    let days = body.expires_in_days.unwrap_or(DEFAULT_EXPIRY_DAYS);
+    if !(1..=MAX_EXPIRY_DAYS).contains(&days) {
+        errors.add("expires_in_days", format!("must be between 1 and {}", MAX_EXPIRY_DAYS));
+    }
+    if !errors.is_empty() {
+        return Err(errors.into());
+    }
+
+    let id = Uuid::new_v4().to_string();
+    let (prefix, key) = generate_key();
+    let mut scopes = body.scopes.clone();
+    scopes.sort();
+    scopes.dedup();
+    let expires_at = timestamp_in(Duration::days(days));
+
+    sqlx::query(&db.sql("INSERT INTO api_keys (id, user_id, name, prefix, key_hash, scopes, mfa, expires_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?)"))
+        .bind(&id)
+        .bind(&claims.sub)
+        .bind(name)
+        .bind(&prefix)
+        .bind(hash_key(&key))
+        .bind(scopes.join(","))
+        .bind(claims.mfa as i64)
+        .bind(&expires_at)
+        .execute(&db.pool)
+        .await?;
+
+    // The key is only ever shown in this response
+    Ok(HttpResponse::Created().json(json!({
+        "id": id,
+        "name": name,
+        "key": key,
+        "prefix": prefix,
+        "scopes": scopes,
+        "expires_at": expires_at
+    })))
+}
+
+pub async fn list_keys(db: web::Data<AppState>, req: HttpRequest) -> Result<HttpResponse, AppError> {
+    let user_id = claims(&req)?.sub;
+
+    let mut keys: Vec<ApiKey> = sqlx::query_as(&db.sql("SELECT * FROM api_keys WHERE user_id = ? ORDER BY created_at DESC"))
+        .bind(&user_id)
+        .fetch_all(&db.pool)
+        .await?;
+
+    for key in &mut keys {
+        key.scope_list = split_scopes(&key.scopes);
+    }
+    Ok(HttpResponse::Ok().json(keys))
+}
+
+pub async fn revoke_key(
+    db: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let claims = claims(&req)?;
+    let key_id = path.into_inner();
+    // A key may revoke itself, e.g. from a script that detected it leaked
+    if claims.api_key.as_deref().is_some_and(|own| own != key_id) {
+        return Err(AppError::Forbidden("API keys cannot manage API keys".to_string()));
+    }
+
+    let result = sqlx::query(&db.sql("DELETE FROM api_keys WHERE id = ? AND user_id = ?"))
+        .bind(&key_id)
+        .bind(&claims.sub)
+        .execute(&db.pool)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound("API key not found".to_string()));
+    }
+    Ok(HttpResponse::Ok().json(json!({"message": "API key revoked"})))
+}
@@ -0,0 +1,101 @@
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use crate::db::AppState;
+use crate::error::AppError;
+use crate::models::audit::{AuditEntry, AuditQuery};
+use crate::services::api_keys::TIMESTAMP_FORMAT;
+use crate::services::audit::{csv_row, CSV_HEADER};
+use serde_json::json;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 500;
+
+// WHERE clause and binds shared by the list and the CSV export
+fn audit_filter(query: &AuditQuery) -> Result<(String, Vec<String>), AppError> {
+    let mut conditions: Vec<&str> = Vec::new();
+    let mut binds: Vec<String> = Vec::new();
+    let exact = [
+        ("actor_id = ?", &query.actor),
+        ("action = ?", &query.action),
+        ("target_type = ?", &query.target_type),
+        ("target_id = ?", &query.target_id),
+    ];
+    for (condition, value) in exact {
+        if let Some(v) = value.as_deref().filter(|v| !v.is_empty()) {
+            conditions.push(condition);
+            binds.push(v.to_string());
+        }
+    }
+    for (condition, value) in [("created_at >= ?", &query.from), ("created_at < ?", &query.to)] {
+        if let Some(v) = value.as_deref().filter(|v| !v.is_empty()) {
+            let t = DateTime::parse_from_rfc3339(v)
+                .map_err(|_| AppError::BadRequest("from and to must be RFC 3339 timestamps".to_string()))?;
+            conditions.push(condition);
+            binds.push(t.with_timezone(&Utc).format(TIMESTAMP_FORMAT).to_string());
+        }
+    }
+    let filter = if conditions.is_empty() { String::new() } else { format!(" WHERE {}", conditions.join(" AND ")) };
+    Ok((filter, binds))
+}
+
+// Newest first, paginated like /api/admin/users
+pub async fn get_audit_log(
+    db: web::Data<AppState>,
+    query: web::Query<AuditQuery>,
+) -> Result<HttpResponse, AppError> {
+    let (filter, binds) = audit_filter(&query)?;
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query.per_page.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    let count_sql = db.sql(&format!("SELECT COUNT(*) FROM audit_log{}", filter)).into_owned();
+    let mut count_query = sqlx::query_as::<_, (i64,)>(&count_sql);
+    for b in &binds {
+        count_query = count_query.bind(b);
+    }
+    let total = count_query.fetch_one(&db.pool).await?.0;
+
+    let list_sql = db.sql(&format!("SELECT * FROM audit_log{} ORDER BY created_at DESC, id LIMIT ? OFFSET ?", filter)).into_owned();
+    let mut list_query = sqlx::query_as::<_, AuditEntry>(&list_sql);
+    for b in &binds {
+        list_query = list_query.bind(b);
+    }
+    let entries = list_query
+        .bind(per_page)
+        .bind((page - 1) * per_page)
+        .fetch_all(&db.pool)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "items": entries,
+        "page": page,
+        "per_page": per_page,
+        "total": total
+    })))
+}
+
+// Same filters as the list, every matching entry, oldest first
+pub async fn export_audit_log(
+    db: web::Data<AppState>,
+    query: web::Query<AuditQuery>,
+) -> Result<HttpResponse, AppError> {
+    let (filter, binds) = audit_filter(&query)?;
+
+    let sql = db.sql(&format!("SELECT * FROM audit_log{} ORDER BY created_at, id", filter)).into_owned();
+    let mut list_query = sqlx::query_as::<_, AuditEntry>(&sql);
+    for b in &binds {
+        list_query = list_query.bind(b);
+    }
+    let entries = list_query.fetch_all(&db.pool).await?;
+
+    let mut csv = String::from(CSV_HEADER);
+    csv.push_str("\r\n");
+    for entry in &entries {
+        csv.push_str(&csv_row(entry));
+        csv.push_str("\r\n");
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header(("Content-Disposition", format!("attachment; filename=\"audit-{}.csv\"", Utc::now().format("%Y%m%d%H%M%S"))))
+        .body(csv))
+}
@@ -1,126 +1,403 @@
-use actix_web::{web, HttpResponse, Responder, HttpRequest, HttpMessage};
-use sqlx::AnyPool;
+use actix_web::{web, HttpResponse, HttpRequest};
+use crate::db::AppState;
+use crate::error::AppError;
+use crate::handlers::common::claims;
 use uuid::Uuid;
 use bcrypt::{hash, verify, DEFAULT_COST};
-use crate::models::user::{User, LoginRequest, RegisterRequest};
+use crate::models::user::{AccountRestriction, User, LoginRequest, RegisterRequest, USER_COLUMNS};
 use crate::models::auth::RefreshRequest;
-use crate::auth::create_jwt;
-use crate::services::redis::{RedisPool, store_refresh_token, get_refresh_token, revoke_token};
-use serde_json::json;
+use crate::auth::{create_jwt, csrf_matches, CookieSettings, Keyring, MfaPolicy, RegistrationMode, TokenSettings, CSRF_COOKIE, REFRESH_COOKIE};
+use crate::config::Config;
+use crate::services::redis::{SessionStore, RefreshOutcome, revoke_access_token, revoke_session_tokens, revoke_user, consume_invite, create_one_time_token};
+use crate::services::rbac::permissions_for_role;
+use crate::services::api_keys::timestamp_now;
+use crate::services::lockout::{locked_for, record_failure, clear_failures, failure_delay};
+use crate::validation::{ValidationErrors, validate_username, validate_password, validate_email, is_breached_password};
+use crate::services::mailer::Mailer;
+use crate::handlers::account::send_verification_email;
+use serde_json::{json, Value};
+use std::sync::OnceLock;
+
+// Time allowed between the password step and the second factor
+const MFA_PENDING_TTL_SECONDS: u64 = 5 * 60;
+
+pub fn client_ip(req: &HttpRequest) -> Option<String> {
+    req.peer_addr().map(|a| a.ip().to_string())
+}
+
+// Hash verified against when the username does not exist
+fn dummy_hash() -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| hash("not-a-real-password", DEFAULT_COST).expect("bcrypt hash"))
+}
+
+// 403 for banned or currently suspended accounts
+pub fn check_restriction(u: &User) -> Result<(), AppError> {
+    match u.restriction(&timestamp_now()) {
+        None => Ok(()),
+        Some(AccountRestriction::Banned { reason }) => Err(AppError::Forbidden("Account banned".to_string())
+            .with("reason", reason)),
+        Some(AccountRestriction::Suspended { until, reason }) => Err(AppError::Forbidden("Account suspended".to_string())
+            .with("suspended_until", until)
+            .with("reason", reason)),
+    }
+}
 
 pub async fn register(
-    pool: web::Data<AnyPool>,
+    db: web::Data<AppState>,
+    redis: web::Data<dyn SessionStore>,
+    mode: web::Data<RegistrationMode>,
+    mailer: web::Data<dyn Mailer>,
+    config: web::Data<Config>,
     req: web::Json<RegisterRequest>,
-) -> impl Responder {
-    let hashed_password = hash(&req.password, DEFAULT_COST).unwrap();
+) -> Result<HttpResponse, AppError> {
+    if *mode.get_ref() == RegistrationMode::Closed {
+        return Err(AppError::Forbidden("Registration is closed".to_string()));
+    }
+
+    let mut errors = ValidationErrors::default();
+    validate_username(&req.username, &mut errors);
+    validate_password(&req.password, &req.username, &mut errors);
+    let email = req.email.as_deref().map(|e| e.trim().to_lowercase()).filter(|e| !e.is_empty());
+    if let Some(email) = &email {
+        validate_email(email, &mut errors);
+    }
+    if is_breached_password(&config.auth, &req.password).await {
+        errors.add("password", "appears in a known data breach");
+    }
+    if *mode.get_ref() == RegistrationMode::InviteOnly && req.invite_code.as_deref().unwrap_or("").is_empty() {
+        errors.add("invite_code", "is required");
+    }
+    if !errors.is_empty() {
+        return Err(errors.into());
+    }
+
+    let hashed_password = hash(&req.password, DEFAULT_COST).map_err(|e| AppError::internal(format!("bcrypt error: {}", e)))?;
     let id = Uuid::new_v4().to_string();
+    // Public registration only ever creates plain users
     let role = "user";
 
-    // Check if it's the first user
-    let count: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM users")
-        .fetch_one(pool.get_ref())
-        .await
-        .unwrap_or((0,));
+    // The UNIQUE constraint is case-sensitive; "Alice" must not coexist with "alice"
+    let taken: (i64,) = sqlx::query_as(&db.sql("SELECT COUNT(*) FROM users WHERE LOWER(username) = LOWER(?)"))
+        .bind(&req.username)
+        .fetch_one(&db.pool)
+        .await?;
+    if taken.0 > 0 {
+        return Err(username_taken());
+    }
 
-    let final_role = if count.0 == 0 { "superuser" } else { role };
+    if let Some(email) = &email {
+        let in_use: (i64,) = sqlx::query_as(&db.sql("SELECT COUNT(*) FROM users WHERE LOWER(email) = ?"))
+            .bind(email)
+            .fetch_one(&db.pool)
+            .await?;
+        if in_use.0 > 0 {
+            return Err(AppError::BadRequest("Email already in use".to_string()));
+        }
+    }
+
+    if let (RegistrationMode::InviteOnly, Some(code)) = (*mode.get_ref(), &req.invite_code) {
+        let valid = consume_invite(redis.get_ref(), code).await.map_err(|e| AppError::unavailable("Invite store", e))?;
+        if !valid {
+            return Err(AppError::Forbidden("Invalid or used invite code".to_string()));
+        }
+    }
 
-    let result = sqlx::query("INSERT INTO users (id, username, password, role) VALUES (?, ?, ?, ?)")
+    sqlx::query(&db.sql("INSERT INTO users (id, username, password, role, email) VALUES (?, ?, ?, ?, ?)"))
         .bind(&id)
         .bind(&req.username)
         .bind(&hashed_password)
-        .bind(final_role)
-        .execute(pool.get_ref())
-        .await;
+        .bind(role)
+        .bind(&email)
+        .execute(&db.pool)
+        .await
+        // Lost a race with another registration of the same name
+        .map_err(|e| match AppError::from(e) {
+            AppError::Conflict(_) => username_taken(),
+            other => other,
+        })?;
 
-    match result {
-        Ok(_) => HttpResponse::Ok().json(json!({"message": "User registered", "id": id, "role": final_role})),
-        Err(_) => HttpResponse::BadRequest().json(json!({"error": "Username taken"})),
+    // The account works without a verified email; only password reset needs one
+    if let Some(email) = &email {
+        if let Err(e) = send_verification_email(redis.get_ref(), mailer.get_ref(), &config.server.base_url, &id, email).await {
+            log::error!("Failed to send verification email: {}", e);
+        }
     }
+    Ok(HttpResponse::Ok().json(json!({"message": "User registered", "id": id, "role": role})))
+}
+
+fn username_taken() -> AppError {
+    AppError::BadRequest("Username taken".to_string())
 }
 
 pub async fn login(
-    pool: web::Data<AnyPool>,
-    redis: web::Data<RedisPool>,
+    db: web::Data<AppState>,
+    redis: web::Data<dyn SessionStore>,
+    keys: web::Data<Keyring>,
+    policy: web::Data<MfaPolicy>,
+    cookie_settings: web::Data<CookieSettings>,
+    http_req: HttpRequest,
     req: web::Json<LoginRequest>,
-) -> impl Responder {
-    let user: Option<User> = sqlx::query_as("SELECT * FROM users WHERE username = ?")
+) -> Result<HttpResponse, AppError> {
+    let ip = client_ip(&http_req);
+    let ip_key = ip.clone().unwrap_or_else(|| "unknown".to_string());
+
+    match locked_for(redis.get_ref(), &req.username, &ip_key).await {
+        Ok(Some(retry_after)) => {
+            return Err(AppError::TooManyRequests {
+                detail: "Too many failed attempts, try again later".to_string(),
+                retry_after: Some(retry_after),
+            });
+        }
+        Ok(None) => {}
+        // Fail open, like the rate limiter
+        Err(e) => log::error!("Redis error: {}", e),
+    }
+
+    let user: Option<User> = sqlx::query_as(&db.sql(&format!("SELECT {} FROM users WHERE username = ?", USER_COLUMNS)))
         .bind(&req.username)
-        .fetch_optional(pool.get_ref())
-        .await
-        .unwrap_or(None);
-
-    match user {
-        Some(u) => {
-            if verify(&req.password, &u.password).unwrap_or(false) {
-                // Generate Access Token (Short lived)
-                let access_token = create_jwt(&u.id, &u.role).unwrap();
-
-                // Generate Refresh Token (Long lived)
-                let refresh_token = Uuid::new_v4().to_string();
-
-                // Store Refresh Token in Redis (7 days = 604800s)
-                let _ = store_refresh_token(redis.get_ref(), &u.id, &refresh_token, 604800).await;
-
-                HttpResponse::Ok().json(json!({
-                    "access_token": access_token,
-                    "refresh_token": refresh_token,
-                    "user_id": u.id,
-                    "role": u.role
-                }))
-            } else {
-                HttpResponse::Unauthorized().json(json!({"error": "Invalid credentials"}))
+        .fetch_optional(&db.pool)
+        .await?;
+
+    // Always run bcrypt so unknown usernames cost as much as wrong passwords
+    let stored_hash = user.as_ref().map(|u| u.password.as_str()).unwrap_or_else(|| dummy_hash());
+    let password_ok = verify(&req.password, stored_hash).unwrap_or(false);
+
+    let u = match user {
+        Some(u) if password_ok => u,
+        // Same response whether or not the username exists
+        _ => {
+            let failures = record_failure(redis.get_ref(), &req.username, &ip_key).await.unwrap_or_else(|e| {
+                log::error!("Redis error: {}", e);
+                0
+            });
+            tokio::time::sleep(failure_delay(failures)).await;
+            return Err(AppError::Unauthorized("Invalid credentials".to_string()));
+        }
+    };
+
+    if let Err(e) = clear_failures(redis.get_ref(), &u.username).await {
+        log::error!("Redis error: {}", e);
+    }
+
+    let cookies = req.use_cookies.then_some(cookie_settings.get_ref());
+    finish_login(&db, redis.get_ref(), keys.get_ref(), policy.get_ref(), &u, req.device_name.as_deref(), ip.as_deref(), cookies).await
+}
+
+// Called once the first factor (password or external provider) is verified.
+// Enrolled accounts get a pending token and finish at /api/login/2fa.
+#[allow(clippy::too_many_arguments)]
+pub async fn finish_login(
+    db: &AppState,
+    redis: &dyn SessionStore,
+    keys: &Keyring,
+    policy: &MfaPolicy,
+    u: &User,
+    device_name: Option<&str>,
+    ip: Option<&str>,
+    cookies: Option<&CookieSettings>,
+) -> Result<HttpResponse, AppError> {
+    check_restriction(u)?;
+    if u.totp_enabled != 0 {
+        let pending = format!("{}|{}", u.id, device_name.unwrap_or(""));
+        let mfa_token = create_one_time_token(redis, "mfa", &pending, MFA_PENDING_TTL_SECONDS).await?;
+        return Ok(HttpResponse::Ok().json(json!({
+            "mfa_required": true,
+            "mfa_token": mfa_token,
+            "expires_in": MFA_PENDING_TTL_SECONDS
+        })));
+    }
+
+    start_session(db, redis, keys, policy, u, device_name, ip, false, cookies).await
+}
+
+// Opens a session and answers with the token pair.
+#[allow(clippy::too_many_arguments)]
+pub async fn start_session(
+    db: &AppState,
+    redis: &dyn SessionStore,
+    keys: &Keyring,
+    policy: &MfaPolicy,
+    u: &User,
+    device_name: Option<&str>,
+    ip: Option<&str>,
+    mfa: bool,
+    cookies: Option<&CookieSettings>,
+) -> Result<HttpResponse, AppError> {
+    // Also reached from /api/login/2fa, after a ban issued during the first step
+    check_restriction(u)?;
+    // One session per device, so logging in elsewhere keeps this one alive
+    let (session_id, refresh_token) = redis.create_session(&u.id, device_name, ip, mfa, keys.settings.refresh_ttl_seconds).await?;
+
+    // Generate Access Token (Short lived)
+    let permissions = permissions_for_role(db, &u.role).await?;
+    let access_token = create_jwt(keys, &u.id, &u.role, &permissions, Some(&session_id), mfa)?;
+
+    let body = json!({
+        "expires_in": keys.settings.access_ttl_seconds,
+        "session_id": session_id,
+        "user_id": u.id,
+        "role": u.role,
+        "permissions": permissions,
+        // Admin routes stay closed until the account enrolls in 2FA (POST /api/2fa/setup)
+        "mfa_enrollment_required": policy.require_for_admin && !permissions.is_empty() && u.totp_enabled == 0
+    });
+    let cookies = cookies.map(|c| (c, Uuid::new_v4().simple().to_string()));
+    Ok(token_response(body, &access_token, &refresh_token, cookies, &keys.settings))
+}
+
+// Adds the token pair to the JSON body, or for cookie mode sets it as HttpOnly
+// cookies and hands out the CSRF token instead.
+fn token_response(mut body: Value, access_token: &str, refresh_token: &str, cookies: Option<(&CookieSettings, String)>, settings: &TokenSettings) -> HttpResponse {
+    match cookies {
+        None => {
+            body["access_token"] = json!(access_token);
+            body["refresh_token"] = json!(refresh_token);
+            HttpResponse::Ok().json(body)
+        }
+        Some((cookie_settings, csrf_token)) => {
+            let mut res = HttpResponse::Ok();
+            for cookie in cookie_settings.session_cookies(access_token, refresh_token, &csrf_token, settings) {
+                res.cookie(cookie);
             }
+            body["csrf_token"] = json!(csrf_token);
+            res.json(body)
         }
-        None => HttpResponse::Unauthorized().json(json!({"error": "User not found"})),
     }
 }
 
+// Takes the refresh token from the body, or in cookie mode from the refresh
+// cookie, which then also requires the CSRF header.
 pub async fn refresh(
-    pool: web::Data<AnyPool>,
-    redis: web::Data<RedisPool>,
-    req: web::Json<RefreshRequest>,
-) -> impl Responder {
-    // Validate stored refresh token
-    let stored_token = get_refresh_token(redis.get_ref(), &req.user_id).await;
+    db: web::Data<AppState>,
+    redis: web::Data<dyn SessionStore>,
+    keys: web::Data<Keyring>,
+    cookie_settings: web::Data<CookieSettings>,
+    http_req: HttpRequest,
+    req: Option<web::Json<RefreshRequest>>,
+) -> Result<HttpResponse, AppError> {
+    let (token, cookie_mode) = match req.map(|r| r.into_inner().refresh_token) {
+        Some(token) => (token, false),
+        None => match http_req.cookie(REFRESH_COOKIE) {
+            Some(c) if csrf_matches(http_req.cookie(CSRF_COOKIE), http_req.headers()) => (c.value().to_string(), true),
+            Some(_) => return Err(AppError::Forbidden("CSRF token missing or invalid".to_string())),
+            None => return Err(AppError::BadRequest("Missing refresh token".to_string())),
+        },
+    };
 
-    match stored_token {
-        Ok(token) if token == req.refresh_token => {
-            // Valid! Generate new tokens.
+    let ip = client_ip(&http_req);
+    let outcome = redis.rotate_refresh_token(&token, ip.as_deref(), keys.settings.refresh_ttl_seconds).await?;
 
+    match outcome {
+        RefreshOutcome::Rotated { user_id, session_id, refresh_token, mfa } => {
             // Get user role to generate new JWT
-            let user: Option<User> = sqlx::query_as("SELECT * FROM users WHERE id = ?")
-                .bind(&req.user_id)
-                .fetch_optional(pool.get_ref())
-                .await
-                .unwrap_or(None);
+            let user: Option<User> = sqlx::query_as(&db.sql(&format!("SELECT {} FROM users WHERE id = ?", USER_COLUMNS)))
+                .bind(&user_id)
+                .fetch_optional(&db.pool)
+                .await?;
 
             if let Some(u) = user {
-                let new_access = create_jwt(&u.id, &u.role).unwrap();
-                // Rotate refresh token? (Optional, let's keep it simple and re-use or rotate)
-                // Let's rotate.
-                let new_refresh = Uuid::new_v4().to_string();
-                let _ = store_refresh_token(redis.get_ref(), &u.id, &new_refresh, 604800).await;
-
-                HttpResponse::Ok().json(json!({
-                    "access_token": new_access,
-                    "refresh_token": new_refresh
-                }))
+                if let Err(e) = check_restriction(&u) {
+                    let _ = redis.revoke_session(&user_id, &session_id).await;
+                    return Err(e);
+                }
+                let permissions = permissions_for_role(&db, &u.role).await?;
+                let new_access = create_jwt(keys.get_ref(), &u.id, &u.role, &permissions, Some(&session_id), mfa)?;
+
+                // The CSRF token stays the same for the life of the session
+                let cookies = cookie_mode.then(|| {
+                    let csrf = http_req.cookie(CSRF_COOKIE).map(|c| c.value().to_string()).unwrap_or_default();
+                    (cookie_settings.get_ref(), csrf)
+                });
+                Ok(token_response(json!({"expires_in": keys.settings.access_ttl_seconds}), &new_access, &refresh_token, cookies, &keys.settings))
             } else {
-                 HttpResponse::Unauthorized().json(json!({"error": "User not found"}))
+                 let _ = redis.revoke_session(&user_id, &session_id).await;
+                 Err(AppError::Unauthorized("User not found".to_string()))
             }
         }
-        _ => HttpResponse::Unauthorized().json(json!({"error": "Invalid refresh token"}))
+        RefreshOutcome::Reused { user_id, session_id } => {
+            log::warn!("Refresh token reuse detected for user {}, session revoked", user_id);
+            // The access token from the last rotation may be the stolen one
+            revoke_session_tokens(redis.get_ref(), &session_id, keys.settings.access_ttl_seconds).await?;
+            Err(AppError::Unauthorized("Refresh token reuse detected, session revoked".to_string()))
+        }
+        RefreshOutcome::Invalid => Err(AppError::Unauthorized("Invalid refresh token".to_string())),
     }
 }
 
+// Ends the session of the calling access token and revokes the token itself
 pub async fn logout(
-    redis: web::Data<RedisPool>,
+    redis: web::Data<dyn SessionStore>,
+    cookie_settings: web::Data<CookieSettings>,
     req: HttpRequest,
-) -> impl Responder {
-    if let Some(claims) = req.extensions().get::<crate::models::TokenClaims>() {
-         let _ = revoke_token(redis.get_ref(), &claims.sub).await;
-         HttpResponse::Ok().json(json!({"message": "Logged out"}))
-    } else {
-         HttpResponse::Unauthorized().finish()
+) -> Result<HttpResponse, AppError> {
+    let claims = claims(&req)?;
+    // A key has no session to end; without this it would end all of them
+    if claims.api_key.is_some() {
+        return Err(AppError::Forbidden("API keys cannot log out, revoke the key instead".to_string()));
+    }
+
+    match &claims.sid {
+        Some(sid) => { redis.revoke_session(&claims.sub, sid).await?; }
+        // Tokens issued before sessions existed: end them all
+        None => redis.revoke_sessions(&claims.sub).await?,
     }
+
+    revoke_access_token(redis.get_ref(), &claims).await?;
+    let mut res = HttpResponse::Ok();
+    for cookie in cookie_settings.cleared_cookies() {
+        res.cookie(cookie);
+    }
+    Ok(res.json(json!({"message": "Logged out"})))
+}
+
+// Ends every session of the caller and revokes all of their access tokens
+pub async fn logout_all(
+    redis: web::Data<dyn SessionStore>,
+    keys: web::Data<Keyring>,
+    cookie_settings: web::Data<CookieSettings>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let user_id = claims(&req)?.sub;
+
+    revoke_user(redis.get_ref(), &user_id, keys.settings.access_ttl_seconds).await?;
+    let mut res = HttpResponse::Ok();
+    for cookie in cookie_settings.cleared_cookies() {
+        res.cookie(cookie);
+    }
+    Ok(res.json(json!({"message": "Logged out everywhere"})))
+}
+
+pub async fn get_sessions(
+    redis: web::Data<dyn SessionStore>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let claims = claims(&req)?;
+    let sessions = redis.list_sessions(&claims.sub, claims.sid.as_deref()).await?;
+    Ok(HttpResponse::Ok().json(sessions))
+}
+
+// Ends a session, usually of another device. Its access token is revoked too:
+// the caller never held it, so it goes by the session id.
+pub async fn delete_session(
+    redis: web::Data<dyn SessionStore>,
+    keys: web::Data<Keyring>,
+    path: web::Path<String>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let user_id = claims(&req)?.sub;
+    let session_id = path.into_inner();
+
+    if !redis.revoke_session(&user_id, &session_id).await? {
+        return Err(AppError::NotFound("Session not found".to_string()));
+    }
+    revoke_session_tokens(redis.get_ref(), &session_id, keys.settings.access_ttl_seconds).await?;
+    Ok(HttpResponse::Ok().json(json!({"message": "Session revoked"})))
+}
+
+// Public keys for verifying tokens issued by this API (empty for HS256)
+pub async fn jwks(
+    keys: web::Data<Keyring>,
+) -> HttpResponse {
+    HttpResponse::Ok().json(keys.jwks())
 }
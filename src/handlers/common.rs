@@ -1 +1,10 @@
-pub mod common {}
+// Shared handler helpers go here.
+use actix_web::{HttpMessage, HttpRequest};
+use crate::error::AppError;
+use crate::models::TokenClaims;
+
+// Claims put in place by JwtAuth; missing only on routes it lets through
+pub fn claims(req: &HttpRequest) -> Result<TokenClaims, AppError> {
+    req.extensions().get::<TokenClaims>().cloned()
+        .ok_or_else(|| AppError::Unauthorized("Authentication required".to_string()))
+}
@@ -1,135 +1,196 @@
-use actix_web::{web, HttpResponse, Responder};
-use sqlx::AnyPool;
-use crate::models::content::{AnimeSeries, Episode, Genre};
+use actix_web::{web, HttpRequest, HttpResponse};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use crate::db::AppState;
+use crate::error::AppError;
+use crate::models::content::{AnimeSeries, CatalogueQuery};
+use crate::repositories::{ContentFilter, ContentSort, Cursor, PageRequest, Position, SortField};
 use serde_json::json;
 
-async fn fetch_genres_for_anime(pool: &AnyPool, anime_id: &str) -> Vec<Genre> {
-    sqlx::query_as(
-        "SELECT g.* FROM genres g
-         JOIN anime_genres ag ON g.id = ag.genre_id
-         WHERE ag.anime_id = ?"
-    )
-    .bind(anime_id)
-    .fetch_all(pool)
-    .await
-    .unwrap_or(vec![])
-}
-
-// Generic internal list fetcher
-async fn get_content_list(pool: &AnyPool, content_type: Option<&str>) -> Vec<AnimeSeries> {
-    let mut anime: Vec<AnimeSeries> = if let Some(ct) = content_type {
-        sqlx::query_as("SELECT * FROM anime_series WHERE content_type = ?")
-            .bind(ct)
-            .fetch_all(pool)
-            .await
-            .unwrap_or(vec![])
-    } else {
-        sqlx::query_as("SELECT * FROM anime_series")
-            .fetch_all(pool)
-            .await
-            .unwrap_or(vec![])
+// Fills in `genres` for a whole list with one batched lookup. Every handler
+// returning series goes through here.
+async fn with_genres(db: &AppState, mut anime: Vec<AnimeSeries>) -> Result<Vec<AnimeSeries>, sqlx::Error> {
+    let ids: Vec<String> = anime.iter().map(|a| a.id.clone()).collect();
+    let mut genres = db.repos.genres.for_series(&ids).await?;
+    for a in &mut anime {
+        a.genres = genres.remove(&a.id).unwrap_or_default();
+    }
+    Ok(anime)
+}
+
+const DEFAULT_PAGE_SIZE: i64 = 20;
+const MAX_PAGE_SIZE: i64 = 100;
+
+// What a `cursor` value carries. It only makes sense for the sort it came
+// from, so that is recorded too.
+#[derive(Serialize, Deserialize)]
+struct CursorToken {
+    sort: String,
+    desc: bool,
+    before: bool,
+    #[serde(flatten)]
+    cursor: Cursor,
+}
+
+fn encode_cursor(sort: ContentSort, before: bool, cursor: Cursor) -> String {
+    let token = CursorToken { sort: sort.field.name().to_string(), desc: sort.descending, before, cursor };
+    URL_SAFE_NO_PAD.encode(serde_json::to_vec(&token).unwrap_or_default())
+}
+
+fn decode_cursor(value: &str, sort: ContentSort) -> Result<Position, AppError> {
+    let token: CursorToken = URL_SAFE_NO_PAD.decode(value).ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .ok_or_else(|| AppError::BadRequest("Invalid cursor".to_string()))?;
+    if token.sort != sort.field.name() || token.desc != sort.descending {
+        return Err(AppError::BadRequest("cursor was issued for a different sort or order".to_string()));
+    }
+    Ok(if token.before { Position::Before(token.cursor) } else { Position::After(token.cursor) })
+}
+
+// This request's path and query with the paging parameter replaced. Cursors
+// are URL-safe, so the value needs no escaping.
+fn page_link(req: &HttpRequest, param: &str, value: &str) -> String {
+    let replaced = format!("{}={}", param, value);
+    let mut pairs: Vec<&str> = req.query_string().split('&')
+        .filter(|p| !p.is_empty() && !matches!(p.split('=').next(), Some("page") | Some("cursor")))
+        .collect();
+    pairs.push(&replaced);
+    format!("{}?{}", req.path(), pairs.join("&"))
+}
+
+fn parse_sort(query: &CatalogueQuery) -> Result<ContentSort, AppError> {
+    let field = match query.sort.as_deref() {
+        None | Some("") => SortField::CreatedAt,
+        Some(s) => SortField::parse(s)
+            .ok_or_else(|| AppError::BadRequest("sort must be title, rating, created_at or latest_episode".to_string()))?,
+    };
+    let descending = match query.order.as_deref() {
+        None | Some("") => field != SortField::Title,
+        Some("asc") => false,
+        Some("desc") => true,
+        Some(_) => return Err(AppError::BadRequest("order must be asc or desc".to_string())),
     };
+    Ok(ContentSort { field, descending })
+}
 
-    // Populate genres efficiently? For now N+1 is fine for low traffic/learning
-    for a in &mut anime {
-        a.genres = fetch_genres_for_anime(pool, &a.id).await;
+fn parse_filter(query: &CatalogueQuery, content_type: Option<&str>) -> Result<ContentFilter, AppError> {
+    let non_empty = |v: &Option<String>| v.as_deref().map(str::trim).filter(|v| !v.is_empty()).map(str::to_string);
+    let genre_ids = query.genre.as_deref().unwrap_or("").split(',')
+        .map(str::trim)
+        .filter(|g| !g.is_empty())
+        .map(|g| g.parse())
+        .collect::<Result<Vec<i64>, _>>()
+        .map_err(|_| AppError::BadRequest("genre must be a comma-separated list of genre ids".to_string()))?;
+    Ok(ContentFilter {
+        content_type: content_type.map(str::to_string),
+        status: non_empty(&query.status),
+        genre_ids,
+        year: query.year,
+        min_rating: query.min_rating,
+        schedule_day: non_empty(&query.schedule_day),
+    })
+}
+
+// Catalogue list responder: one page plus counts and links to its neighbours
+async fn content_list(db: &AppState, req: &HttpRequest, query: &CatalogueQuery, content_type: Option<&str>) -> Result<HttpResponse, AppError> {
+    let sort = parse_sort(query)?;
+    let filter = parse_filter(query, content_type)?;
+    let per_page = query.per_page.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let position = match query.cursor.as_deref().filter(|c| !c.is_empty()) {
+        Some(c) => decode_cursor(c, sort)?,
+        None => Position::Page(query.page.unwrap_or(1).max(1)),
+    };
+    // The row offset has to fit the LIMIT/OFFSET bind
+    if let Position::Page(n) = position {
+        if (n - 1).checked_mul(per_page).is_none() {
+            return Err(AppError::BadRequest("page is too large".to_string()));
+        }
+    }
+    let page = PageRequest { per_page, position };
+
+    let result = db.repos.content.list(&filter, sort, &page).await?;
+    let next_cursor = result.next.map(|c| encode_cursor(sort, false, c));
+    let prev_cursor = result.prev.map(|c| encode_cursor(sort, true, c));
+    // Offset requests keep paging by number, cursor requests by cursor
+    let (next, prev) = match page.position {
+        Position::Page(n) => (
+            next_cursor.as_ref().and(n.checked_add(1)).map(|next| page_link(req, "page", &next.to_string())),
+            prev_cursor.as_ref().map(|_| page_link(req, "page", &(n - 1).to_string())),
+        ),
+        _ => (
+            next_cursor.as_deref().map(|c| page_link(req, "cursor", c)),
+            prev_cursor.as_deref().map(|c| page_link(req, "cursor", c)),
+        ),
+    };
+
+    let mut body = json!({
+        "items": with_genres(db, result.items).await?,
+        "total": result.total,
+        "per_page": per_page,
+        "next_cursor": next_cursor,
+        "prev_cursor": prev_cursor,
+        "links": { "next": next, "prev": prev },
+    });
+    if let Position::Page(n) = page.position {
+        body["page"] = json!(n);
     }
-    anime
+    Ok(HttpResponse::Ok().json(body))
 }
 
-pub async fn get_anime_list(pool: web::Data<AnyPool>) -> impl Responder {
-    let list = get_content_list(pool.get_ref(), Some("Anime")).await;
-    HttpResponse::Ok().json(list)
+pub async fn get_anime_list(db: web::Data<AppState>, req: HttpRequest, query: web::Query<CatalogueQuery>) -> Result<HttpResponse, AppError> {
+    content_list(&db, &req, &query, Some("Anime")).await
 }
 
-pub async fn get_donghua_list(pool: web::Data<AnyPool>) -> impl Responder {
-    let list = get_content_list(pool.get_ref(), Some("Donghua")).await;
-    HttpResponse::Ok().json(list)
+pub async fn get_donghua_list(db: web::Data<AppState>, req: HttpRequest, query: web::Query<CatalogueQuery>) -> Result<HttpResponse, AppError> {
+    content_list(&db, &req, &query, Some("Donghua")).await
 }
 
-pub async fn get_movie_list(pool: web::Data<AnyPool>) -> impl Responder {
-    let list = get_content_list(pool.get_ref(), Some("Movie")).await;
-    HttpResponse::Ok().json(list)
+pub async fn get_movie_list(db: web::Data<AppState>, req: HttpRequest, query: web::Query<CatalogueQuery>) -> Result<HttpResponse, AppError> {
+    content_list(&db, &req, &query, Some("Movie")).await
 }
 
-pub async fn get_all_content(pool: web::Data<AnyPool>) -> impl Responder {
-    let list = get_content_list(pool.get_ref(), None).await;
-    HttpResponse::Ok().json(list)
+pub async fn get_all_content(db: web::Data<AppState>, req: HttpRequest, query: web::Query<CatalogueQuery>) -> Result<HttpResponse, AppError> {
+    content_list(&db, &req, &query, None).await
 }
 
 pub async fn get_anime_detail(
-    pool: web::Data<AnyPool>,
+    db: web::Data<AppState>,
     path: web::Path<String>,
-) -> impl Responder {
+) -> Result<HttpResponse, AppError> {
     let id = path.into_inner();
-    let mut anime: Option<AnimeSeries> = sqlx::query_as("SELECT * FROM anime_series WHERE id = ?")
-        .bind(&id)
-        .fetch_optional(pool.get_ref())
-        .await
-        .unwrap_or(None);
-
-    if let Some(ref mut a) = anime {
-        a.genres = fetch_genres_for_anime(pool.get_ref(), &a.id).await;
-    }
-
-    let episodes: Vec<Episode> = sqlx::query_as("SELECT * FROM episodes WHERE series_id = ? ORDER BY episode_number")
-        .bind(&id)
-        .fetch_all(pool.get_ref())
-        .await
-        .unwrap_or(vec![]);
-
-    match anime {
-        Some(a) => HttpResponse::Ok().json(json!({ "series": a, "episodes": episodes })),
-        None => HttpResponse::NotFound().json(json!({"error": "Content not found"})),
-    }
+    let anime = db.repos.content.find(&id).await?
+        .ok_or_else(|| AppError::NotFound("Content not found".to_string()))?;
+    let anime = with_genres(&db, vec![anime]).await?.remove(0);
+    let episodes = db.repos.episodes.for_series(&id).await?;
+    Ok(HttpResponse::Ok().json(json!({ "series": anime, "episodes": episodes })))
 }
 
 pub async fn get_schedule(
-    pool: web::Data<AnyPool>,
-) -> impl Responder {
-    let mut anime: Vec<AnimeSeries> = sqlx::query_as("SELECT * FROM anime_series WHERE schedule_day IS NOT NULL")
-        .fetch_all(pool.get_ref())
-        .await
-        .unwrap_or(vec![]);
-
-    for a in &mut anime {
-        a.genres = fetch_genres_for_anime(pool.get_ref(), &a.id).await;
-    }
-    HttpResponse::Ok().json(anime)
+    db: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let anime = db.repos.content.scheduled().await?;
+    Ok(HttpResponse::Ok().json(with_genres(&db, anime).await?))
 }
 
 pub async fn search_content(
-    pool: web::Data<AnyPool>,
+    db: web::Data<AppState>,
     query: web::Query<std::collections::HashMap<String, String>>,
-) -> impl Responder {
+) -> Result<HttpResponse, AppError> {
     let q = query.get("q").cloned().unwrap_or_default();
 
-    // Naive search - Note: || concatenation is standard SQL
-    let mut result: Vec<AnimeSeries> = sqlx::query_as("SELECT * FROM anime_series WHERE title LIKE '%' || ? || '%'")
-        .bind(&q)
-        .fetch_all(pool.get_ref())
-        .await
-        .unwrap_or(vec![]);
-
-    for a in &mut result {
-        a.genres = fetch_genres_for_anime(pool.get_ref(), &a.id).await;
-    }
-
+    let result = db.repos.content.search(&q).await?;
     let filtered: Vec<AnimeSeries> = if let Some(t) = query.get("type") {
         result.into_iter().filter(|a| a.content_type.eq_ignore_ascii_case(t)).collect()
     } else {
         result
     };
 
-    HttpResponse::Ok().json(filtered)
+    Ok(HttpResponse::Ok().json(with_genres(&db, filtered).await?))
 }
 
 pub async fn get_genres(
-    pool: web::Data<AnyPool>,
-) -> impl Responder {
-    let genres: Vec<Genre> = sqlx::query_as("SELECT * FROM genres ORDER BY name")
-        .fetch_all(pool.get_ref())
-        .await
-        .unwrap_or(vec![]);
-    HttpResponse::Ok().json(genres)
+    db: web::Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    Ok(HttpResponse::Ok().json(db.repos.genres.list().await?))
 }
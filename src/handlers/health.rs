@@ -0,0 +1,26 @@
+use actix_web::{web, HttpResponse, Responder};
+use serde_json::json;
+use crate::db::AppState;
+use crate::db::DbStatus;
+
+// Public, for load balancers and operators: which database startup selected
+// and whether it still answers. Running on the fallback database reports
+// "degraded"; an unreachable database answers 503. No URLs or credentials.
+pub async fn health(db: web::Data<AppState>, status: web::Data<DbStatus>) -> impl Responder {
+    let reachable = sqlx::query("SELECT 1").fetch_one(&db.pool).await.is_ok();
+
+    let body = json!({
+        "status": if reachable && !status.fallback { "ok" } else { "degraded" },
+        "database": {
+            "backend": status.kind.name(),
+            "strict": status.strict,
+            "fallback": status.fallback,
+            "reachable": reachable,
+        }
+    });
+    if reachable {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
@@ -0,0 +1,227 @@
+use actix_web::{web, HttpResponse, HttpRequest};
+use crate::db::AppState;
+use crate::error::AppError;
+use crate::handlers::common::claims;
+use bcrypt::verify;
+use chrono::Utc;
+use crate::auth::{CookieSettings, Keyring, MfaPolicy};
+use crate::handlers::auth::{client_ip, start_session};
+use crate::models::user::{User, MfaLoginRequest, TotpCodeRequest, DisableTotpRequest, USER_COLUMNS};
+use crate::services::lockout::{locked_for, record_failure, clear_failures, failure_delay};
+use crate::services::rbac::permissions_for_role;
+use crate::services::redis::{SessionStore, peek_one_time_token, consume_one_time_token};
+use crate::services::totp;
+use serde_json::json;
+
+async fn current_user(db: &AppState, req: &HttpRequest) -> Result<User, AppError> {
+    let user_id = claims(req)?.sub;
+    sqlx::query_as(&db.sql(&format!("SELECT {} FROM users WHERE id = ?", USER_COLUMNS)))
+        .bind(user_id)
+        .fetch_optional(&db.pool)
+        .await?
+        .ok_or_else(|| AppError::Unauthorized("User not found".to_string()))
+}
+
+fn already_enabled() -> AppError {
+    AppError::BadRequest("Two-factor authentication is already enabled".to_string())
+}
+
+fn not_enabled() -> AppError {
+    AppError::BadRequest("Two-factor authentication is not enabled".to_string())
+}
+
+fn invalid_code() -> AppError {
+    AppError::BadRequest("Invalid code".to_string())
+}
+
+// Verifies a TOTP code and records its time step, so each code works at most
+// once. Some(false) for a valid code that was already used.
+async fn use_totp_code(db: &AppState, u: &User, code: &str) -> Option<bool> {
+    let step = totp::verify_code(u.totp_secret.as_deref()?, code, Utc::now().timestamp() as u64)?;
+    if step as i64 <= u.totp_last_step {
+        return Some(false);
+    }
+    // The conditional UPDATE settles two requests racing with the same code
+    let result = sqlx::query(&db.sql("UPDATE users SET totp_last_step = ? WHERE id = ? AND totp_last_step < ?"))
+        .bind(step as i64)
+        .bind(&u.id)
+        .bind(step as i64)
+        .execute(&db.pool)
+        .await;
+    Some(matches!(result, Ok(r) if r.rows_affected() == 1))
+}
+
+// Accepts the current TOTP code at most once, or burns a recovery code.
+async fn check_second_factor(db: &AppState, u: &User, code: &str) -> bool {
+    if let Some(accepted) = use_totp_code(db, u, code).await {
+        return accepted;
+    }
+
+    totp::use_recovery_code(db, &u.id, code).await.unwrap_or_else(|e| {
+        log::error!("Recovery code lookup failed: {}", e);
+        false
+    })
+}
+
+// Starts enrollment: stores a fresh secret that only takes effect once a code
+// from it is confirmed at /api/2fa/enable.
+pub async fn setup(
+    db: web::Data<AppState>,
+    policy: web::Data<MfaPolicy>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let u = current_user(&db, &req).await?;
+    if u.totp_enabled != 0 {
+        return Err(already_enabled());
+    }
+
+    let secret = totp::generate_secret();
+    sqlx::query(&db.sql("UPDATE users SET totp_secret = ? WHERE id = ?"))
+        .bind(&secret)
+        .bind(&u.id)
+        .execute(&db.pool)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "secret": secret,
+        "otpauth_uri": totp::provisioning_uri(&policy.issuer, &u.username, &secret)
+    })))
+}
+
+// Confirms enrollment with a code from the app and hands out recovery codes, once.
+pub async fn enable(
+    db: web::Data<AppState>,
+    req: HttpRequest,
+    body: web::Json<TotpCodeRequest>,
+) -> Result<HttpResponse, AppError> {
+    let u = current_user(&db, &req).await?;
+    if u.totp_enabled != 0 {
+        return Err(already_enabled());
+    }
+    let secret = u.totp_secret.as_deref()
+        .ok_or_else(|| AppError::BadRequest("Start enrollment at /api/2fa/setup first".to_string()))?;
+    let step = totp::verify_code(secret, &body.code, Utc::now().timestamp() as u64)
+        .ok_or_else(invalid_code)?;
+
+    sqlx::query(&db.sql("UPDATE users SET totp_enabled = 1, totp_last_step = ? WHERE id = ?"))
+        .bind(step as i64)
+        .bind(&u.id)
+        .execute(&db.pool)
+        .await?;
+
+    let codes = totp::replace_recovery_codes(&db, &u.id).await.map_err(AppError::internal)?;
+    Ok(HttpResponse::Ok().json(json!({
+        "message": "Two-factor authentication enabled, log in again to reach admin routes",
+        "recovery_codes": codes
+    })))
+}
+
+pub async fn disable(
+    db: web::Data<AppState>,
+    policy: web::Data<MfaPolicy>,
+    req: HttpRequest,
+    body: web::Json<DisableTotpRequest>,
+) -> Result<HttpResponse, AppError> {
+    let u = current_user(&db, &req).await?;
+    if u.totp_enabled == 0 {
+        return Err(not_enabled());
+    }
+    if policy.require_for_admin && !permissions_for_role(&db, &u.role).await?.is_empty() {
+        return Err(AppError::Forbidden("Two-factor authentication is required for your role".to_string()));
+    }
+    if !verify(&body.password, &u.password).unwrap_or(false) || !check_second_factor(&db, &u, &body.code).await {
+        return Err(AppError::Unauthorized("Invalid credentials".to_string()));
+    }
+
+    sqlx::query(&db.sql("UPDATE users SET totp_secret = NULL, totp_enabled = 0, totp_last_step = 0 WHERE id = ?"))
+        .bind(&u.id)
+        .execute(&db.pool)
+        .await?;
+    sqlx::query(&db.sql("DELETE FROM recovery_codes WHERE user_id = ?"))
+        .bind(&u.id)
+        .execute(&db.pool)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(json!({"message": "Two-factor authentication disabled"})))
+}
+
+// Replaces all recovery codes; needs a current TOTP code.
+pub async fn regenerate_recovery_codes(
+    db: web::Data<AppState>,
+    req: HttpRequest,
+    body: web::Json<TotpCodeRequest>,
+) -> Result<HttpResponse, AppError> {
+    let u = current_user(&db, &req).await?;
+    if u.totp_enabled == 0 {
+        return Err(not_enabled());
+    }
+    if use_totp_code(&db, &u, &body.code).await != Some(true) {
+        return Err(invalid_code());
+    }
+
+    let codes = totp::replace_recovery_codes(&db, &u.id).await.map_err(AppError::internal)?;
+    Ok(HttpResponse::Ok().json(json!({"recovery_codes": codes})))
+}
+
+pub async fn status(db: web::Data<AppState>, req: HttpRequest) -> Result<HttpResponse, AppError> {
+    let u = current_user(&db, &req).await?;
+    Ok(HttpResponse::Ok().json(json!({
+        "enabled": u.totp_enabled != 0,
+        "recovery_codes_remaining": totp::remaining_recovery_codes(&db, &u.id).await
+    })))
+}
+
+// Second login step: trades the pending token from /api/login plus a code for
+// a session. Wrong codes count towards the same lockout as wrong passwords.
+pub async fn login_2fa(
+    db: web::Data<AppState>,
+    redis: web::Data<dyn SessionStore>,
+    keys: web::Data<Keyring>,
+    policy: web::Data<MfaPolicy>,
+    cookie_settings: web::Data<CookieSettings>,
+    http_req: HttpRequest,
+    req: web::Json<MfaLoginRequest>,
+) -> Result<HttpResponse, AppError> {
+    let invalid = || AppError::Unauthorized("Invalid or expired login attempt".to_string());
+
+    let pending = peek_one_time_token(redis.get_ref(), "mfa", &req.mfa_token).await?.ok_or_else(invalid)?;
+    let (user_id, device_name) = pending.split_once('|').unwrap_or((&pending, ""));
+
+    let u: User = sqlx::query_as(&db.sql(&format!("SELECT {} FROM users WHERE id = ?", USER_COLUMNS)))
+        .bind(user_id)
+        .fetch_optional(&db.pool)
+        .await?
+        .ok_or_else(invalid)?;
+
+    let ip = client_ip(&http_req);
+    let ip_key = ip.clone().unwrap_or_else(|| "unknown".to_string());
+    match locked_for(redis.get_ref(), &u.username, &ip_key).await {
+        Ok(Some(retry_after)) => {
+            return Err(AppError::TooManyRequests {
+                detail: "Too many failed attempts, try again later".to_string(),
+                retry_after: Some(retry_after),
+            });
+        }
+        Ok(None) => {}
+        Err(e) => log::error!("Redis error: {}", e),
+    }
+
+    if !check_second_factor(&db, &u, &req.code).await {
+        let failures = record_failure(redis.get_ref(), &u.username, &ip_key).await.unwrap_or_else(|e| {
+            log::error!("Redis error: {}", e);
+            0
+        });
+        tokio::time::sleep(failure_delay(failures)).await;
+        return Err(AppError::Unauthorized("Invalid code".to_string()));
+    }
+
+    // Consuming the pending token last means a concurrent duplicate gets nothing
+    consume_one_time_token(redis.get_ref(), "mfa", &req.mfa_token).await?.ok_or_else(invalid)?;
+    if let Err(e) = clear_failures(redis.get_ref(), &u.username).await {
+        log::error!("Redis error: {}", e);
+    }
+
+    let device_name = Some(device_name).filter(|d| !d.is_empty());
+    let cookies = req.use_cookies.then_some(cookie_settings.get_ref());
+    start_session(&db, redis.get_ref(), keys.get_ref(), policy.get_ref(), &u, device_name, ip.as_deref(), true, cookies).await
+}
@@ -1,4 +1,11 @@
 pub mod auth;
 pub mod content;
 pub mod admin;
+pub mod account;
+pub mod mfa;
+pub mod api_keys;
+pub mod oidc;
+pub mod profile;
+pub mod audit;
+pub mod health;
 pub mod common; // shared things if any
@@ -0,0 +1,231 @@
+use actix_web::cookie::Cookie;
+use actix_web::{web, HttpResponse, HttpRequest};
+use serde::Deserialize;
+use crate::db::AppState;
+use crate::error::AppError;
+use crate::handlers::common::claims;
+use crate::auth::{secrets_match, validate_jwt, CookieSettings, Keyring, MfaPolicy, RegistrationMode, ACCESS_COOKIE, OIDC_FLOW_COOKIE};
+use crate::handlers::auth::{client_ip, finish_login};
+use crate::models::user::{User, USER_COLUMNS};
+use crate::services::oidc::{FlowState, LinkError, OidcClient, NO_PASSWORD, random_secret, resolve_identity};
+use crate::services::redis::{SessionStore, create_one_time_token, consume_one_time_token, is_access_token_revoked};
+use serde_json::json;
+
+// Time the user has to finish signing in at the provider
+const FLOW_TTL_SECONDS: u64 = 10 * 60;
+
+#[derive(Debug, Deserialize)]
+pub struct AuthorizeQuery {
+    #[serde(default)]
+    pub use_cookies: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
+
+pub async fn list_providers(client: web::Data<OidcClient>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({"providers": client.provider_names()}))
+}
+
+// Stores the flow secrets under a fresh `state` and builds the provider URL,
+// plus the cookie the callback must come back with.
+async fn start_flow(
+    client: &OidcClient,
+    redis: &dyn SessionStore,
+    cookie_settings: &CookieSettings,
+    provider_name: &str,
+    link_user_id: Option<String>,
+    use_cookies: bool,
+) -> Result<(String, Cookie<'static>), AppError> {
+    let provider = client.provider(provider_name).ok_or_else(unknown_provider)?;
+
+    let flow = FlowState {
+        provider: provider.name.clone(),
+        verifier: random_secret(),
+        nonce: random_secret(),
+        link_user_id,
+        use_cookies,
+        browser_secret: random_secret(),
+    };
+    let state = create_one_time_token(redis, "oidc", &serde_json::to_string(&flow).map_err(AppError::internal)?, FLOW_TTL_SECONDS).await?;
+
+    let url = client.authorization_url(provider, &state, &flow.nonce, &flow.verifier).await.map_err(|e| {
+        log::error!("OIDC provider {} unavailable: {}", provider.name, e);
+        AppError::BadGateway("Identity provider unavailable".to_string())
+    })?;
+    Ok((url, cookie_settings.oidc_flow_cookie(&flow.browser_secret, FLOW_TTL_SECONDS as i64)))
+}
+
+fn unknown_provider() -> AppError {
+    AppError::NotFound("Unknown provider".to_string())
+}
+
+// Sends the browser to the provider's sign-in page
+pub async fn authorize(
+    client: web::Data<OidcClient>,
+    redis: web::Data<dyn SessionStore>,
+    cookie_settings: web::Data<CookieSettings>,
+    path: web::Path<String>,
+    query: web::Query<AuthorizeQuery>,
+) -> Result<HttpResponse, AppError> {
+    let (url, cookie) = start_flow(client.get_ref(), redis.get_ref(), cookie_settings.get_ref(), &path.into_inner(), None, query.use_cookies).await?;
+    Ok(HttpResponse::Found().insert_header(("Location", url)).cookie(cookie).finish())
+}
+
+// Same flow for a logged-in user adding a provider; answers with the URL so
+// the frontend can navigate there. The callback must carry the same user's
+// access token.
+pub async fn link(
+    client: web::Data<OidcClient>,
+    redis: web::Data<dyn SessionStore>,
+    cookie_settings: web::Data<CookieSettings>,
+    req: HttpRequest,
+    path: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let claims = claims(&req)?;
+    if claims.api_key.is_some() {
+        return Err(AppError::Forbidden("API keys cannot link identities".to_string()));
+    }
+
+    let (url, cookie) = start_flow(client.get_ref(), redis.get_ref(), cookie_settings.get_ref(), &path.into_inner(), Some(claims.sub), false).await?;
+    Ok(HttpResponse::Ok().cookie(cookie).json(json!({"authorization_url": url})))
+}
+
+// The callback is a public route, so the middleware has not looked at any
+// token; link flows check it here. Bearer header first, then the access cookie.
+async fn signed_in_user(keys: &Keyring, redis: &dyn SessionStore, req: &HttpRequest) -> Result<Option<String>, AppError> {
+    let token = match req.headers().get("Authorization") {
+        Some(header) => header.to_str().ok().and_then(|h| h.strip_prefix("Bearer ")).map(str::to_string),
+        None => req.cookie(ACCESS_COOKIE).map(|c| c.value().to_string()),
+    };
+    let claims = match token.and_then(|t| validate_jwt(keys, &t).ok()) {
+        Some(c) => c,
+        None => return Ok(None),
+    };
+    if is_access_token_revoked(redis, &claims).await? {
+        return Ok(None);
+    }
+    Ok(Some(claims.sub))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn callback(
+    db: web::Data<AppState>,
+    redis: web::Data<dyn SessionStore>,
+    keys: web::Data<Keyring>,
+    policy: web::Data<MfaPolicy>,
+    mode: web::Data<RegistrationMode>,
+    client: web::Data<OidcClient>,
+    cookie_settings: web::Data<CookieSettings>,
+    http_req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<CallbackQuery>,
+) -> Result<HttpResponse, AppError> {
+    if let Some(error) = &query.error {
+        return Err(AppError::BadRequest("Sign-in was cancelled or refused".to_string()).with("provider_error", error));
+    }
+    let (code, state) = match (&query.code, &query.state) {
+        (Some(c), Some(s)) => (c, s),
+        _ => return Err(AppError::BadRequest("Missing code or state".to_string())),
+    };
+
+    // Single use: a replayed callback URL finds nothing
+    let flow: Option<FlowState> = consume_one_time_token(redis.get_ref(), "oidc", state).await?
+        .and_then(|v| serde_json::from_str(&v).ok());
+    let provider_name = path.into_inner();
+    // A state lured into another browser arrives without the flow cookie
+    let browser_secret = http_req.cookie(OIDC_FLOW_COOKIE).map(|c| c.value().to_string()).unwrap_or_default();
+    let flow = match flow {
+        Some(f) if f.provider == provider_name && secrets_match(&f.browser_secret, &browser_secret) => f,
+        _ => return Err(AppError::BadRequest("Invalid or expired sign-in attempt".to_string())),
+    };
+    if let Some(link_user_id) = &flow.link_user_id {
+        if signed_in_user(keys.get_ref(), redis.get_ref(), &http_req).await?.as_ref() != Some(link_user_id) {
+            return Err(AppError::Forbidden("Finish linking while signed in to the account that started it".to_string()));
+        }
+    }
+    let provider = client.provider(&provider_name).ok_or_else(unknown_provider)?;
+
+    let identity = client.exchange_code(provider, code, &flow.verifier, &flow.nonce).await.map_err(|e| {
+        log::warn!("OIDC sign-in with {} failed: {}", provider_name, e);
+        AppError::Unauthorized("Could not verify the identity provider response".to_string())
+    })?;
+
+    let allow_signup = *mode.get_ref() == RegistrationMode::Open;
+    let user_id = resolve_identity(&db, &identity, flow.link_user_id.as_deref(), allow_signup).await.map_err(|e| match e {
+        LinkError::LinkedElsewhere => AppError::Conflict("This identity is linked to another account".to_string()),
+        LinkError::ProviderAlreadyLinked => AppError::Conflict("Another identity from this provider is already linked".to_string()),
+        LinkError::EmailInUse => AppError::Conflict("An account with this email already exists, log in and link the provider from your account".to_string()),
+        LinkError::SignupClosed => AppError::Forbidden("Registration is closed".to_string()),
+        LinkError::Database(e) => AppError::internal(e),
+    })?;
+
+    let cleared = cookie_settings.oidc_flow_cookie("", 0);
+    if flow.link_user_id.is_some() {
+        return Ok(HttpResponse::Ok().cookie(cleared).json(json!({"message": "Identity linked", "provider": provider_name})));
+    }
+
+    let u: User = sqlx::query_as(&db.sql(&format!("SELECT {} FROM users WHERE id = ?", USER_COLUMNS)))
+        .bind(&user_id)
+        .fetch_optional(&db.pool)
+        .await?
+        .ok_or_else(|| AppError::Unauthorized("User not found".to_string()))?;
+
+    let ip = client_ip(&http_req);
+    let device_name = format!("Signed in with {}", provider_name);
+    let cookies = flow.use_cookies.then_some(cookie_settings.get_ref());
+    let mut res = finish_login(&db, redis.get_ref(), keys.get_ref(), policy.get_ref(), &u, Some(&device_name), ip.as_deref(), cookies).await?;
+    res.add_cookie(&cleared).map_err(AppError::internal)?;
+    Ok(res)
+}
+
+pub async fn list_identities(db: web::Data<AppState>, req: HttpRequest) -> Result<HttpResponse, AppError> {
+    let user_id = claims(&req)?.sub;
+
+    let rows: Vec<(String, String, String)> = sqlx::query_as(&db.sql("SELECT provider, email, created_at FROM user_identities WHERE user_id = ? ORDER BY provider"))
+        .bind(&user_id)
+        .fetch_all(&db.pool)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(rows.into_iter()
+        .map(|(provider, email, created_at)| json!({"provider": provider, "email": email, "linked_at": created_at}))
+        .collect::<Vec<_>>()))
+}
+
+pub async fn unlink(
+    db: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let user_id = claims(&req)?.sub;
+    let provider = path.into_inner();
+
+    // Refuse to remove the last way to sign in
+    let password: Option<(String,)> = sqlx::query_as(&db.sql("SELECT password FROM users WHERE id = ?"))
+        .bind(&user_id)
+        .fetch_optional(&db.pool)
+        .await?;
+    let others: (i64,) = sqlx::query_as(&db.sql("SELECT COUNT(*) FROM user_identities WHERE user_id = ? AND provider <> ?"))
+        .bind(&user_id)
+        .bind(&provider)
+        .fetch_one(&db.pool)
+        .await?;
+    if password.is_some_and(|p| p.0 == NO_PASSWORD) && others.0 == 0 {
+        return Err(AppError::BadRequest("Set a password through password reset before removing your only sign-in method".to_string()));
+    }
+
+    let result = sqlx::query(&db.sql("DELETE FROM user_identities WHERE user_id = ? AND provider = ?"))
+        .bind(&user_id)
+        .bind(&provider)
+        .execute(&db.pool)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound("Provider not linked".to_string()));
+    }
+    Ok(HttpResponse::Ok().json(json!({"message": "Identity unlinked"})))
+}
@@ -0,0 +1,296 @@
+use actix_web::{web, HttpResponse, HttpRequest};
+use crate::db::AppState;
+use crate::error::AppError;
+use crate::handlers::common::claims;
+use bcrypt::{hash, verify, DEFAULT_COST};
+use crate::auth::{CookieSettings, Keyring};
+use crate::config::Config;
+use crate::handlers::auth::client_ip;
+use crate::models::profile::{ChangePasswordRequest, DeleteAccountRequest, Profile, UpdateProfileRequest};
+use crate::models::TokenClaims;
+use crate::repositories::UserWrite;
+use crate::services::audit::Actor;
+use crate::services::lockout::{locked_for, record_failure, clear_failures, failure_delay};
+use crate::services::oidc::NO_PASSWORD;
+use crate::services::redis::{SessionStore, revoke_user};
+use crate::validation::{ValidationErrors, validate_password_field, is_breached_password};
+use serde_json::json;
+
+const DISPLAY_NAME_MAX_LEN: usize = 50;
+const AVATAR_URL_MAX_LEN: usize = 512;
+const MAX_PREFERRED_GENRES: usize = 20;
+const CONTENT_TYPES: &[&str] = &["Anime", "Donghua", "Movie"];
+
+// Account endpoints are for people: a leaked API key must not be able to
+// change the password or delete the account.
+fn session_claims(req: &HttpRequest) -> Result<TokenClaims, AppError> {
+    let claims = claims(req)?;
+    if claims.api_key.is_some() {
+        return Err(AppError::Forbidden("API keys cannot manage the account".to_string()));
+    }
+    Ok(claims)
+}
+
+async fn load_profile(db: &AppState, user_id: &str) -> Result<Profile, sqlx::Error> {
+    let profile: Option<Profile> = sqlx::query_as(&db.sql("SELECT display_name, avatar_url, preferred_language, content_preferences FROM user_profiles WHERE user_id = ?"))
+        .bind(user_id)
+        .fetch_optional(&db.pool)
+        .await?;
+    Ok(profile.unwrap_or_default())
+}
+
+// (username, password hash) of the caller
+async fn load_credentials(db: &AppState, user_id: &str) -> Result<(String, String), AppError> {
+    sqlx::query_as(&db.sql("SELECT username, password FROM users WHERE id = ?"))
+        .bind(user_id)
+        .fetch_optional(&db.pool)
+        .await?
+        .ok_or_else(user_not_found)
+}
+
+fn user_not_found() -> AppError {
+    AppError::NotFound("User not found".to_string())
+}
+
+// Checks the current password again before a sensitive change. Failures count
+// towards the login lockout, so a stolen session cannot brute-force it.
+async fn reauthenticate(redis: &dyn SessionStore, req: &HttpRequest, username: &str, stored_hash: &str, password: &str) -> Result<(), AppError> {
+    let ip_key = client_ip(req).unwrap_or_else(|| "unknown".to_string());
+    match locked_for(redis, username, &ip_key).await {
+        Ok(Some(retry_after)) => {
+            return Err(AppError::TooManyRequests {
+                detail: "Too many failed attempts, try again later".to_string(),
+                retry_after: Some(retry_after),
+            });
+        }
+        Ok(None) => {}
+        Err(e) => log::error!("Redis error: {}", e),
+    }
+
+    if !verify(password, stored_hash).unwrap_or(false) {
+        let failures = record_failure(redis, username, &ip_key).await.unwrap_or_else(|e| {
+            log::error!("Redis error: {}", e);
+            0
+        });
+        tokio::time::sleep(failure_delay(failures)).await;
+        return Err(AppError::Unauthorized("Current password is incorrect".to_string()));
+    }
+
+    if let Err(e) = clear_failures(redis, username).await {
+        log::error!("Redis error: {}", e);
+    }
+    Ok(())
+}
+
+// Loose BCP 47 check: a 2-3 letter language, optionally followed by subtags
+fn is_language_tag(tag: &str) -> bool {
+    let mut parts = tag.split('-');
+    let language = parts.next().unwrap_or("");
+    (2..=3).contains(&language.len())
+        && language.chars().all(|c| c.is_ascii_lowercase())
+        && parts.all(|p| (2..=8).contains(&p.len()) && p.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+pub async fn get_me(db: web::Data<AppState>, req: HttpRequest) -> Result<HttpResponse, AppError> {
+    let claims = claims(&req)?;
+
+    // Explicit columns: sqlx::Any cannot decode NULL or DATETIME from SQLite
+    let account: Option<(String, String, String, i64, i64, String)> = sqlx::query_as(&db.sql(
+        "SELECT username, role, COALESCE(email, ''), email_verified, totp_enabled, COALESCE(CAST(created_at AS TEXT), '') FROM users WHERE id = ?"
+    ))
+    .bind(&claims.sub)
+    .fetch_optional(&db.pool)
+    .await?;
+    let (username, role, email, email_verified, totp_enabled, created_at) = account.ok_or_else(user_not_found)?;
+
+    let profile = load_profile(&db, &claims.sub).await?;
+    Ok(HttpResponse::Ok().json(json!({
+        "id": claims.sub,
+        "username": username,
+        "role": role,
+        "permissions": claims.permissions,
+        "email": Some(email).filter(|e| !e.is_empty()),
+        "email_verified": email_verified != 0,
+        "two_factor_enabled": totp_enabled != 0,
+        "created_at": created_at,
+        "display_name": profile.display_name,
+        "avatar_url": profile.avatar_url,
+        "preferred_language": profile.preferred_language,
+        "content_preferences": profile.preferences()
+    })))
+}
+
+pub async fn update_me(
+    db: web::Data<AppState>,
+    req: HttpRequest,
+    body: web::Json<UpdateProfileRequest>,
+) -> Result<HttpResponse, AppError> {
+    let user_id = claims(&req)?.sub;
+
+    let mut errors = ValidationErrors::default();
+    let display_name = body.display_name.as_deref().map(str::trim);
+    if let Some(name) = display_name {
+        if name.chars().count() > DISPLAY_NAME_MAX_LEN || name.chars().any(char::is_control) {
+            errors.add("display_name", format!("must be at most {} printable characters", DISPLAY_NAME_MAX_LEN));
+        }
+    }
+    // Empty clears the avatar; anything else must be an absolute http(s) URL
+    if let Some(url) = &body.avatar_url {
+        let valid = url.is_empty()
+            || (url.len() <= AVATAR_URL_MAX_LEN
+                && (url.starts_with("https://") || url.starts_with("http://"))
+                && !url.chars().any(|c| c.is_whitespace() || c.is_control()));
+        if !valid {
+            errors.add("avatar_url", format!("must be an http(s) URL of at most {} characters", AVATAR_URL_MAX_LEN));
+        }
+    }
+    if let Some(language) = &body.preferred_language {
+        if !is_language_tag(language) {
+            errors.add("preferred_language", "must be a language tag like 'en' or 'zh-CN'");
+        }
+    }
+    if let Some(prefs) = &body.content_preferences {
+        for content_type in &prefs.content_types {
+            if !CONTENT_TYPES.contains(&content_type.as_str()) {
+                errors.add("content_preferences.content_types", format!("'{}' must be one of {}", content_type, CONTENT_TYPES.join(", ")));
+            }
+        }
+        if prefs.genre_ids.len() > MAX_PREFERRED_GENRES {
+            errors.add("content_preferences.genre_ids", format!("must list at most {} genres", MAX_PREFERRED_GENRES));
+        }
+        for genre_id in &prefs.genre_ids {
+            let exists: Option<(i64,)> = sqlx::query_as(&db.sql("SELECT id FROM genres WHERE id = ?"))
+                .bind(genre_id)
+                .fetch_optional(&db.pool)
+                .await?;
+            if exists.is_none() {
+                errors.add("content_preferences.genre_ids", format!("genre {} does not exist", genre_id));
+            }
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors.into());
+    }
+
+    let preferences = body.content_preferences.as_ref().map(|p| {
+        let mut p = p.clone();
+        p.content_types.sort();
+        p.content_types.dedup();
+        p.genre_ids.sort();
+        p.genre_ids.dedup();
+        serde_json::to_string(&p)
+    }).transpose().map_err(AppError::internal)?;
+
+    let mut tx = db.pool.begin().await?;
+    // ON CONFLICT DO NOTHING works on both SQLite and Postgres
+    sqlx::query(&db.sql("INSERT INTO user_profiles (user_id) VALUES (?) ON CONFLICT (user_id) DO NOTHING"))
+        .bind(&user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(&db.sql(
+        "UPDATE user_profiles SET
+            display_name = COALESCE(CAST(? AS TEXT), display_name),
+            avatar_url = COALESCE(CAST(? AS TEXT), avatar_url),
+            preferred_language = COALESCE(CAST(? AS TEXT), preferred_language),
+            content_preferences = COALESCE(CAST(? AS TEXT), content_preferences),
+            updated_at = CURRENT_TIMESTAMP
+        WHERE user_id = ?"
+    ))
+    .bind(display_name)
+    .bind(&body.avatar_url)
+    .bind(&body.preferred_language)
+    .bind(preferences)
+    .bind(&user_id)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    let profile = load_profile(&db, &user_id).await?;
+    Ok(HttpResponse::Ok().json(json!({
+        "display_name": profile.display_name,
+        "avatar_url": profile.avatar_url,
+        "preferred_language": profile.preferred_language,
+        "content_preferences": profile.preferences()
+    })))
+}
+
+pub async fn change_password(
+    db: web::Data<AppState>,
+    redis: web::Data<dyn SessionStore>,
+    keys: web::Data<Keyring>,
+    cookie_settings: web::Data<CookieSettings>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    body: web::Json<ChangePasswordRequest>,
+) -> Result<HttpResponse, AppError> {
+    let claims = session_claims(&req)?;
+    let (username, stored_hash) = load_credentials(&db, &claims.sub).await?;
+    if stored_hash == NO_PASSWORD {
+        return Err(AppError::BadRequest("This account has no password, set one through password reset".to_string()));
+    }
+    reauthenticate(redis.get_ref(), &req, &username, &stored_hash, &body.current_password).await?;
+
+    let mut errors = ValidationErrors::default();
+    validate_password_field("new_password", &body.new_password, &username, &mut errors);
+    if body.new_password == body.current_password {
+        errors.add("new_password", "must differ from the current password");
+    }
+    if is_breached_password(&config.auth, &body.new_password).await {
+        errors.add("new_password", "appears in a known data breach");
+    }
+    if !errors.is_empty() {
+        return Err(errors.into());
+    }
+
+    let hashed_password = hash(&body.new_password, DEFAULT_COST).map_err(|e| AppError::internal(format!("bcrypt error: {}", e)))?;
+    sqlx::query(&db.sql("UPDATE users SET password = ? WHERE id = ?"))
+        .bind(&hashed_password)
+        .bind(&claims.sub)
+        .execute(&db.pool)
+        .await?;
+
+    // Same as a reset: every session, including this one, has to log in again
+    if let Err(e) = revoke_user(redis.get_ref(), &claims.sub, keys.settings.access_ttl_seconds).await {
+        log::error!("Failed to revoke tokens of user {}: {}", claims.sub, e);
+    }
+    let mut res = HttpResponse::Ok();
+    for cookie in cookie_settings.cleared_cookies() {
+        res.cookie(cookie);
+    }
+    Ok(res.json(json!({"message": "Password changed, please log in again"})))
+}
+
+// Deletes the account and everything it owns, then ends all its sessions.
+pub async fn delete_me(
+    db: web::Data<AppState>,
+    redis: web::Data<dyn SessionStore>,
+    keys: web::Data<Keyring>,
+    cookie_settings: web::Data<CookieSettings>,
+    req: HttpRequest,
+    body: web::Json<DeleteAccountRequest>,
+) -> Result<HttpResponse, AppError> {
+    let claims = session_claims(&req)?;
+    let (username, stored_hash) = load_credentials(&db, &claims.sub).await?;
+    // Provider-only accounts have no password to confirm with; their session is the proof
+    if stored_hash != NO_PASSWORD {
+        let password = body.password.as_deref().unwrap_or("");
+        reauthenticate(redis.get_ref(), &req, &username, &stored_hash, password).await?;
+    }
+
+    match db.repos.users.delete(&Actor::from_request(&req), &claims.sub).await? {
+        UserWrite::Done => {}
+        UserWrite::NotFound => return Err(AppError::NotFound("User not found".to_string())),
+        UserWrite::LastSuperuser => return Err(AppError::Conflict("Cannot remove the last superuser".to_string())),
+    }
+
+    if let Err(e) = revoke_user(redis.get_ref(), &claims.sub, keys.settings.access_ttl_seconds).await {
+        log::error!("Failed to revoke tokens of deleted user {}: {}", claims.sub, e);
+    }
+    let _ = clear_failures(redis.get_ref(), &username).await;
+
+    let mut res = HttpResponse::Ok();
+    for cookie in cookie_settings.cleared_cookies() {
+        res.cookie(cookie);
+    }
+    Ok(res.json(json!({"message": "Account deleted"})))
+}
@@ -1,16 +1,23 @@
 use actix_web::{web, App, HttpServer, HttpResponse};
 use actix_files as fs;
-use actix_cors::Cors;
 use dotenvy::dotenv;
 use std::env;
+use std::sync::Arc;
 
+mod config;
 mod db;
+mod error;
 mod models;
 mod handlers;
 mod auth;
 mod services;
 mod routes;
 mod middleware;
+mod validation;
+mod cli;
+mod migrate;
+mod query;
+mod repositories;
 
 #[cfg(test)]
 mod tests;
@@ -18,46 +25,95 @@ mod tests;
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
+
+    // Subcommands, e.g. `belajar-actix create-superuser --username alice`
+    let args: Vec<String> = env::args().skip(1).collect();
+    if !args.is_empty() {
+        if let Err(e) = cli::run(&args).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
     // env_logger::init(); // Replaced by file logger
 
     // Initialize File Logger
     let _guard = middleware::logger::init_file_logger();
 
+    // Settings from config/*.toml and the environment, checked before anything connects
+    let config = config::Config::load().unwrap_or_else(|e| invalid_configuration(e));
+    println!("Configuration profile: {}", config.profile.name());
+
+    // JWT signing keys; EdDSA key files are read here
+    let keyring = Arc::new(auth::Keyring::load(&config.jwt).unwrap_or_else(|e| invalid_configuration(e)));
+    let data_keys = web::Data::from(keyring.clone());
+
+    // Account emails (password reset, verification)
+    let data_mailer: web::Data<dyn services::mailer::Mailer> =
+        web::Data::from(services::mailer::mailer(&config.mail).unwrap_or_else(|e| invalid_configuration(e)));
+
     // Initialize Database
-    let pool: sqlx::AnyPool = db::init_db().await;
-    let data_pool = web::Data::new(pool);
+    let (pool, db_status) = db::init_db(&config.database).await;
+    let data_db = web::Data::new(db::AppState::new(pool, db_status.kind));
+    let data_db_status = web::Data::new(db_status);
 
     // Initialize Redis
-    let redis_pool = services::redis::init_redis().await;
-    let data_redis = web::Data::new(redis_pool.clone()); // clone client (cheap)
+    let redis_pool = services::redis::init_redis(&config.redis).await;
+    let session_store: Arc<dyn services::redis::SessionStore> = Arc::new(services::redis::RedisStore::new(redis_pool.clone()));
+    let data_redis = web::Data::from(session_store.clone());
+
+    let data_registration = web::Data::new(config.auth.registration);
+    let data_mfa_policy = web::Data::new(config.auth.mfa.clone());
+    let data_cookie_settings = web::Data::new(config.cookies.clone());
+
+    // External sign-in providers
+    let data_oidc = web::Data::new(services::oidc::OidcClient::new(config.oidc.clone()));
 
     // Rate Limiter
-    let limiter = middleware::limiter::RateLimit { pool: redis_pool.clone() };
+    let limiter = middleware::limiter::RateLimit { store: session_store.clone(), config: config.rate_limit.clone() };
 
     // Create directories if they don't exist
-    std::fs::create_dir_all("uploads").unwrap();
+    std::fs::create_dir_all(&config.server.upload_dir).unwrap();
     std::fs::create_dir_all("static").unwrap();
 
-    let port = env::var("PORT").unwrap_or("8080".to_string());
+    let port = config.server.port;
     println!("Starting server on port {}", port);
+    let upload_dir = config.server.upload_dir.clone();
+    let cors_config = config.cors.clone();
+    let data_config = web::Data::new(config);
 
     HttpServer::new(move || {
-        let cors = Cors::permissive();
+        let cors = middleware::cors::cors(&cors_config);
 
         App::new()
-            .wrap(cors)
             .wrap(limiter.clone()) // Rate Limiter Global
-            .wrap(middleware::auth::JwtAuth) // Global Auth Middleware (logic inside skips public routes)
-            .app_data(data_pool.clone())
+            .wrap(middleware::auth::JwtAuth { keys: keyring.clone(), redis: session_store.clone() }) // Global Auth Middleware (logic inside skips public routes)
+            .wrap(cors) // Outermost, so preflights and error responses carry CORS headers
+            .app_data(data_db.clone())
             .app_data(data_redis.clone())
+            .app_data(data_keys.clone())
+            .app_data(data_registration.clone())
+            .app_data(data_mailer.clone())
+            .app_data(data_mfa_policy.clone())
+            .app_data(data_oidc.clone())
+            .app_data(data_cookie_settings.clone())
+            .app_data(data_db_status.clone())
+            .app_data(data_config.clone())
             // Static files
             .service(fs::Files::new("/static", "./static").show_files_listing())
-            .service(fs::Files::new("/uploads", "./uploads").show_files_listing())
+            .service(fs::Files::new("/uploads", &upload_dir).show_files_listing())
             // API Routes
             .route("/", web::get().to(|| async { HttpResponse::Ok().body("Anime Streaming API Running") }))
             .configure(routes::config)
+            .configure(error::configure)
+            .default_service(web::to(error::not_found))
     })
     .bind(format!("0.0.0.0:{}", port))?
     .run()
     .await
 }
+
+fn invalid_configuration(e: String) -> ! {
+    eprintln!("Invalid configuration:\n{}", e);
+    std::process::exit(1);
+}
@@ -1,15 +1,24 @@
 use actix_web::{
     dev::{Service, ServiceRequest, ServiceResponse, Transform},
-    Error, HttpMessage, HttpResponse, body::EitherBody,
+    http::Method, web, Error, HttpMessage, ResponseError, body::EitherBody,
 };
 use futures::future::{ok, Ready};
 use futures::Future;
 use std::pin::Pin;
 use std::rc::Rc;
+use std::sync::Arc;
 use std::task::{Context, Poll};
-use crate::auth::validate_jwt;
+use crate::auth::{csrf_matches, validate_jwt, Keyring, ACCESS_COOKIE, CSRF_COOKIE};
+use crate::services::api_keys::authenticate;
+use crate::services::redis::{is_access_token_revoked, SessionStore};
+use crate::db::AppState;
+use crate::error::AppError;
 
-pub struct JwtAuth;
+#[derive(Clone)]
+pub struct JwtAuth {
+    pub keys: Arc<Keyring>,
+    pub redis: Arc<dyn SessionStore>,
+}
 
 impl<S, B> Transform<S, ServiceRequest> for JwtAuth
 where
@@ -26,12 +35,16 @@ where
     fn new_transform(&self, service: S) -> Self::Future {
         ok(JwtAuthMiddleware {
             service: Rc::new(service),
+            keys: self.keys.clone(),
+            redis: self.redis.clone(),
         })
     }
 }
 
 pub struct JwtAuthMiddleware<S> {
     service: Rc<S>,
+    keys: Arc<Keyring>,
+    redis: Arc<dyn SessionStore>,
 }
 
 impl<S, B> Service<ServiceRequest> for JwtAuthMiddleware<S>
@@ -55,7 +68,12 @@ where
         if path.starts_with("/api/login")
             || path.starts_with("/api/register")
             || path.starts_with("/static")
+            || path.starts_with("/.well-known/")
             || path.starts_with("/api/refresh")
+            || path.starts_with("/api/password/")
+            || path == "/api/verify-email"
+            || path == "/api/oauth/providers"
+            || (path.starts_with("/api/oauth/") && (path.ends_with("/authorize") || path.ends_with("/callback")))
             || path == "/api/anime"
             || path == "/api/donghua"
             || path == "/api/movies"
@@ -63,6 +81,7 @@ where
             || path == "/api/search"
             || path == "/api/all"
             || path == "/api/genres"
+            || path == "/api/health"
             || path == "/"
         {
              return Box::pin(async move {
@@ -71,28 +90,77 @@ where
              });
         }
 
-        let auth_header = req.headers().get("Authorization").cloned(); // clone header value
+        // Automation clients authenticate with a personal API key instead of a JWT
+        if let Some(api_key) = req.headers().get("X-API-Key").and_then(|h| h.to_str().ok()).map(str::to_string) {
+            let keys = self.keys.clone();
+            return Box::pin(async move {
+                let db = req.app_data::<web::Data<AppState>>().cloned();
+                let claims = match db {
+                    Some(db) => authenticate(db.get_ref(), &keys, &api_key).await,
+                    None => None,
+                };
+                match claims {
+                    Some(claims) => {
+                        req.extensions_mut().insert(claims);
+                        let res = srv.call(req).await?;
+                        Ok(res.map_into_left_body())
+                    }
+                    None => {
+                        let res = AppError::Unauthorized("Invalid API key".to_string()).error_response().map_into_right_body();
+                        Ok(req.into_response(res))
+                    }
+                }
+            });
+        }
 
-        if let Some(header) = auth_header {
-            if let Ok(auth_str) = header.to_str() {
-                if auth_str.starts_with("Bearer ") {
-                    let token = &auth_str[7..];
-                    match validate_jwt(token) {
-                        Ok(claims) => {
-                            req.extensions_mut().insert(claims);
-                             return Box::pin(async move {
-                                 let res = srv.call(req).await?;
-                                 Ok(res.map_into_left_body())
-                             });
-                        }
-                        Err(_) => {}
+        // Bearer header first; browsers in cookie mode send the access cookie,
+        // and state-changing requests must then echo the CSRF token.
+        let token = match req.headers().get("Authorization") {
+            Some(header) => header.to_str().ok().and_then(|h| h.strip_prefix("Bearer ")).map(str::to_string),
+            None => match req.cookie(ACCESS_COOKIE) {
+                Some(cookie) => {
+                    let safe = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+                    if !safe && !csrf_matches(req.cookie(CSRF_COOKIE), req.headers()) {
+                        return Box::pin(async move {
+                            let res = AppError::Forbidden("CSRF token missing or invalid".to_string())
+                                .error_response()
+                                .map_into_right_body();
+                            Ok(req.into_response(res))
+                        });
                     }
+                    Some(cookie.value().to_string())
                 }
+                None => None,
+            },
+        };
+
+        if let Some(token) = token {
+            if let Ok(claims) = validate_jwt(&self.keys, &token) {
+                let redis = self.redis.clone();
+                return Box::pin(async move {
+                    match is_access_token_revoked(redis.as_ref(), &claims).await {
+                        Ok(true) => {
+                            let res = AppError::Unauthorized("Token revoked".to_string()).error_response().map_into_right_body();
+                            return Ok(req.into_response(res));
+                        }
+                        Ok(false) => {}
+                        // Fail closed: a revoked token must not work again just
+                        // because the denylist is unreachable
+                        Err(e) => {
+                            let res = AppError::from(e).error_response().map_into_right_body();
+                            return Ok(req.into_response(res));
+                        }
+                    }
+
+                    req.extensions_mut().insert(claims);
+                    let res = srv.call(req).await?;
+                    Ok(res.map_into_left_body())
+                });
             }
         }
 
         Box::pin(async move {
-            let res = HttpResponse::Unauthorized().finish().map_into_right_body();
+            let res = AppError::Unauthorized("Missing or invalid token".to_string()).error_response().map_into_right_body();
             Ok(req.into_response(res))
         })
     }
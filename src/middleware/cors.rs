@@ -0,0 +1,21 @@
+use actix_cors::Cors;
+use actix_web::http::header;
+use crate::auth::CSRF_HEADER;
+use crate::config::CorsConfig;
+
+// Only the configured origins, never a wildcard: the API accepts cookies, so
+// any allowed origin can act as the signed-in user.
+pub fn cors(config: &CorsConfig) -> Cors {
+    let mut cors = Cors::default()
+        .allowed_methods(["GET", "POST", "PUT", "PATCH", "DELETE"])
+        .allowed_headers([header::AUTHORIZATION, header::CONTENT_TYPE, header::ACCEPT])
+        .allowed_header(CSRF_HEADER)
+        .allowed_header("X-API-Key")
+        .expose_headers([header::RETRY_AFTER, header::LINK])
+        .supports_credentials()
+        .max_age(3600);
+    for origin in &config.allowed_origins {
+        cors = cors.allowed_origin(origin);
+    }
+    cors
+}
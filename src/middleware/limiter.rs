@@ -1,18 +1,22 @@
 use actix_web::{
     dev::{Service, ServiceRequest, ServiceResponse, Transform},
-    Error, HttpResponse, body::EitherBody,
+    Error, ResponseError, body::EitherBody,
 };
 use futures::future::{ok, Ready};
 use futures::Future;
 use std::pin::Pin;
 use std::rc::Rc;
+use std::sync::Arc;
 use std::task::{Context, Poll};
-use crate::services::redis::RedisPool;
-use redis::AsyncCommands;
+use crate::config::RateLimitConfig;
+use crate::error::AppError;
+use crate::services::redis::SessionStore;
 
+// Fixed window per client IP, counted in the session store
 #[derive(Clone)]
 pub struct RateLimit {
-    pub pool: RedisPool,
+    pub store: Arc<dyn SessionStore>,
+    pub config: RateLimitConfig,
 }
 
 impl<S, B> Transform<S, ServiceRequest> for RateLimit
@@ -30,14 +34,16 @@ where
     fn new_transform(&self, service: S) -> Self::Future {
         ok(RateLimitMiddleware {
             service: Rc::new(service),
-            pool: self.pool.clone(),
+            store: self.store.clone(),
+            config: self.config.clone(),
         })
     }
 }
 
 pub struct RateLimitMiddleware<S> {
     service: Rc<S>,
-    pool: RedisPool,
+    store: Arc<dyn SessionStore>,
+    config: RateLimitConfig,
 }
 
 impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
@@ -56,12 +62,15 @@ where
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
         let srv = self.service.clone();
-        let pool = self.pool.clone();
+        let store = self.store.clone();
+        let limit = self.config.requests;
+        let ttl = self.config.window_secs;
 
         let ip = req.peer_addr().map(|a| a.ip().to_string()).unwrap_or_else(|| "unknown".to_string());
 
         Box::pin(async move {
-            let mut con = match pool.get_multiplexed_async_connection().await {
+            let key = format!("ratelimit:{}", ip);
+            let count = match store.incr_window(&key, ttl).await {
                 Ok(c) => c,
                 Err(e) => {
                     log::error!("Redis error: {}", e);
@@ -71,29 +80,12 @@ where
                 }
             };
 
-            let key = format!("ratelimit:{}", ip);
-            let limit = 100; // 100 req
-            let ttl = 60; // 60 sec
-
-            let script = redis::Script::new(r#"
-                let current = redis.call("INCR", KEYS[1])
-                if tonumber(current) == 1 then
-                    redis.call("EXPIRE", KEYS[1], ARGV[1])
-                end
-                return current
-            "#);
-
-            let count: u64 = match script.key(&key).arg(ttl).invoke_async(&mut con).await {
-                Ok(c) => c,
-                Err(e) => {
-                    log::error!("Redis script error: {}", e);
-                    let res = srv.call(req).await?;
-                    return Ok(res.map_into_left_body());
-                }
-            };
-
             if count > limit {
-                 let res = HttpResponse::TooManyRequests().body("Rate Limit Exceeded").map_into_right_body();
+                 // Whatever is left of the window; the full window if the store cannot say
+                 let retry_after = store.ttl(&key).await.ok().flatten().unwrap_or(ttl);
+                 let res = AppError::TooManyRequests { detail: "Rate limit exceeded".to_string(), retry_after: Some(retry_after) }
+                     .error_response()
+                     .map_into_right_body();
                  return Ok(req.into_response(res));
             }
 
@@ -1,3 +1,5 @@
 pub mod logger;
 pub mod auth;
 pub mod limiter;
+pub mod permission;
+pub mod cors;
@@ -0,0 +1,87 @@
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    web, Error, HttpMessage, ResponseError, body::EitherBody,
+};
+use futures::future::{ok, Ready};
+use futures::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use crate::auth::{check_permission, MfaPolicy};
+use crate::error::AppError;
+use crate::models::TokenClaims;
+
+// Rejects requests whose JWT does not carry the given permission, and tokens
+// issued without a second factor when MfaPolicy requires one.
+// Must run after JwtAuth so the claims are already in the request extensions.
+pub struct RequirePermission(pub &'static str);
+
+impl<S, B> Transform<S, ServiceRequest> for RequirePermission
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RequirePermissionMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequirePermissionMiddleware {
+            service: Rc::new(service),
+            permission: self.0,
+        })
+    }
+}
+
+pub struct RequirePermissionMiddleware<S> {
+    service: Rc<S>,
+    permission: &'static str,
+}
+
+impl<S, B> Service<ServiceRequest> for RequirePermissionMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let srv = self.service.clone();
+
+        let mfa_missing = req.app_data::<web::Data<MfaPolicy>>().is_some_and(|p| p.require_for_admin)
+            && !req.extensions().get::<TokenClaims>().is_some_and(|c| c.mfa);
+
+        let allowed = check_permission(&req, self.permission);
+
+        if allowed && !mfa_missing {
+            return Box::pin(async move {
+                let res = srv.call(req).await?;
+                Ok(res.map_into_left_body())
+            });
+        }
+
+        let authenticated = req.extensions().contains::<TokenClaims>();
+        let permission = self.permission;
+
+        Box::pin(async move {
+            let err = if !authenticated {
+                AppError::Unauthorized("Authentication required".to_string())
+            } else if !allowed {
+                AppError::Forbidden("Missing permission".to_string()).with("permission", permission)
+            } else {
+                AppError::Forbidden("Two-factor authentication required".to_string())
+            };
+            Ok(req.into_response(err.error_response().map_into_right_body()))
+        })
+    }
+}
@@ -0,0 +1,181 @@
+use sha2::{Digest, Sha256};
+use sqlx::{AnyPool, Executor};
+use crate::db::DbKind;
+use crate::query;
+use crate::services::api_keys::timestamp_now;
+
+// One numbered schema change. Files live in migrations/<dialect>/ as
+// NNNN_name.up.sql and NNNN_name.down.sql; add new ones to both lists below.
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub up: &'static str,
+    pub down: &'static str,
+}
+
+macro_rules! migration {
+    ($dialect:literal, $version:literal, $name:literal) => {
+        Migration {
+            version: $version,
+            name: $name,
+            up: include_str!(concat!("../migrations/", $dialect, "/", $name, ".up.sql")),
+            down: include_str!(concat!("../migrations/", $dialect, "/", $name, ".down.sql")),
+        }
+    };
+}
+
+const SQLITE: &[Migration] = &[
+    migration!("sqlite", 1, "0001_initial"),
+    migration!("sqlite", 2, "0002_catalogue_listing"),
+];
+
+const POSTGRES: &[Migration] = &[
+    migration!("postgres", 1, "0001_initial"),
+    migration!("postgres", 2, "0002_catalogue_listing"),
+];
+
+pub fn migrations(kind: DbKind) -> &'static [Migration] {
+    match kind {
+        DbKind::Sqlite => SQLITE,
+        DbKind::Postgres => POSTGRES,
+    }
+}
+
+impl Migration {
+    // Recorded when applied; a file edited afterwards no longer matches
+    pub fn checksum(&self) -> String {
+        format!("{:x}", Sha256::digest(self.up.as_bytes()))
+    }
+}
+
+// A verified row of the _migrations tracking table
+pub struct Applied {
+    pub version: i64,
+    pub applied_at: String, // api_keys::TIMESTAMP_FORMAT
+}
+
+const TRACKING_TABLE: &str = "CREATE TABLE IF NOT EXISTS _migrations (
+    version BIGINT PRIMARY KEY,
+    name TEXT NOT NULL,
+    checksum TEXT NOT NULL,
+    applied_at TEXT NOT NULL
+)";
+
+// Columns the startup code used to add with ALTER TABLE before migrations were
+// versioned. Databases from that era may lack some of them.
+const LEGACY_COLUMNS: &[&str] = &[
+    "ALTER TABLE users ADD COLUMN email TEXT",
+    "ALTER TABLE users ADD COLUMN email_verified INTEGER NOT NULL DEFAULT 0",
+    "ALTER TABLE users ADD COLUMN totp_secret TEXT",
+    "ALTER TABLE users ADD COLUMN totp_enabled INTEGER NOT NULL DEFAULT 0",
+    "ALTER TABLE users ADD COLUMN totp_last_step INTEGER NOT NULL DEFAULT 0",
+    "ALTER TABLE users ADD COLUMN banned INTEGER NOT NULL DEFAULT 0",
+    "ALTER TABLE users ADD COLUMN ban_reason TEXT NOT NULL DEFAULT ''",
+    "ALTER TABLE users ADD COLUMN suspended_until TEXT NOT NULL DEFAULT ''",
+    "ALTER TABLE users ADD COLUMN suspension_reason TEXT NOT NULL DEFAULT ''",
+];
+
+// Applied migrations in version order, after checking each one against the
+// files compiled into this build.
+pub async fn applied(pool: &AnyPool, kind: DbKind) -> Result<Vec<Applied>, String> {
+    pool.execute(TRACKING_TABLE).await.map_err(|e| format!("Cannot create _migrations: {}", e))?;
+
+    let rows: Vec<(i64, String, String, String)> = sqlx::query_as(
+        "SELECT version, name, checksum, applied_at FROM _migrations ORDER BY version"
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| format!("Cannot read _migrations: {}", e))?;
+
+    let known = migrations(kind);
+    let mut applied = Vec::new();
+    for (version, name, checksum, applied_at) in rows {
+        let migration = known.iter().find(|m| m.version == version)
+            .ok_or(format!("Migration {} is applied but unknown to this build", name))?;
+        if migration.checksum() != checksum {
+            return Err(format!("Migration {} was modified after it was applied (checksum mismatch)", migration.name));
+        }
+        applied.push(Applied { version, applied_at });
+    }
+    Ok(applied)
+}
+
+// Every known migration with the time it was applied, if it was
+pub async fn status(pool: &AnyPool, kind: DbKind) -> Result<Vec<(&'static Migration, Option<String>)>, String> {
+    let applied = applied(pool, kind).await?;
+    Ok(migrations(kind).iter()
+        .map(|m| (m, applied.iter().find(|a| a.version == m.version).map(|a| a.applied_at.clone())))
+        .collect())
+}
+
+// Applies pending migrations up to and including `target` (all when None),
+// each in its own transaction. Returns the versions applied.
+pub async fn up(pool: &AnyPool, kind: DbKind, target: Option<i64>) -> Result<Vec<i64>, String> {
+    let applied = applied(pool, kind).await?;
+    if applied.is_empty() {
+        adopt_legacy(pool).await;
+    }
+
+    let mut done = Vec::new();
+    for migration in migrations(kind) {
+        if applied.iter().any(|a| a.version == migration.version) {
+            continue;
+        }
+        if target.is_some_and(|t| migration.version > t) {
+            break;
+        }
+
+        let failed = |e: sqlx::Error| format!("Migration {} failed: {}", migration.name, e);
+        let mut tx = pool.begin().await.map_err(failed)?;
+        (&mut *tx).execute(migration.up).await.map_err(failed)?;
+        sqlx::query(&query::render(kind, "INSERT INTO _migrations (version, name, checksum, applied_at) VALUES (?, ?, ?, ?)"))
+            .bind(migration.version)
+            .bind(migration.name)
+            .bind(migration.checksum())
+            .bind(timestamp_now())
+            .execute(&mut *tx)
+            .await
+            .map_err(failed)?;
+        tx.commit().await.map_err(failed)?;
+        done.push(migration.version);
+    }
+    Ok(done)
+}
+
+// Reverts applied migrations newer than `target`, newest first. Returns the
+// versions reverted.
+pub async fn down(pool: &AnyPool, kind: DbKind, target: i64) -> Result<Vec<i64>, String> {
+    let applied = applied(pool, kind).await?;
+
+    let mut done = Vec::new();
+    for migration in migrations(kind).iter().rev() {
+        if migration.version <= target || !applied.iter().any(|a| a.version == migration.version) {
+            continue;
+        }
+
+        let failed = |e: sqlx::Error| format!("Reverting migration {} failed: {}", migration.name, e);
+        let mut tx = pool.begin().await.map_err(failed)?;
+        (&mut *tx).execute(migration.down).await.map_err(failed)?;
+        sqlx::query(&query::render(kind, "DELETE FROM _migrations WHERE version = ?"))
+            .bind(migration.version)
+            .execute(&mut *tx)
+            .await
+            .map_err(failed)?;
+        tx.commit().await.map_err(failed)?;
+        done.push(migration.version);
+    }
+    Ok(done)
+}
+
+// Databases created before migrations were versioned already have tables but
+// no _migrations rows. Bring their users table up to the shape 0001 expects;
+// its IF NOT EXISTS statements then leave existing tables alone. ADD COLUMN
+// fails harmlessly when the column is already there.
+async fn adopt_legacy(pool: &AnyPool) {
+    if sqlx::query("SELECT 1 FROM users LIMIT 1").fetch_optional(pool).await.is_err() {
+        return;
+    }
+    for query in LEGACY_COLUMNS {
+        let _ = sqlx::query(query).execute(pool).await;
+    }
+}
@@ -0,0 +1,31 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+// Personal API key. The secret itself is never stored, only its SHA-256.
+#[derive(Debug, Serialize, Deserialize, Clone, FromRow)]
+pub struct ApiKey {
+    pub id: String,
+    #[serde(skip_serializing)]
+    pub user_id: String,
+    pub name: String,
+    pub prefix: String, // Public part of the key, shown so users can tell keys apart
+    #[serde(skip_serializing)]
+    pub key_hash: String,
+    #[serde(skip)]
+    pub scopes: String, // Comma separated permission names
+    pub mfa: i64, // 1 when created from a session that passed 2FA
+    pub expires_at: String,
+    pub last_used_at: String, // Empty until first use
+    pub created_at: String,
+    #[sqlx(skip)]
+    #[serde(rename = "scopes")]
+    pub scope_list: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+    #[serde(default)]
+    pub scopes: Vec<String>, // Must be a subset of the caller's permissions
+    pub expires_in_days: Option<i64>, // Defaults to 90, at most 365
+}
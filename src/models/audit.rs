@@ -0,0 +1,29 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+// One administrative action, see services::audit. before/after hold only the
+// changed fields as JSON text (empty when there is no such side).
+#[derive(Debug, Serialize, Deserialize, Clone, FromRow)]
+pub struct AuditEntry {
+    pub id: String,
+    pub actor_id: String,
+    pub action: String, // e.g. "content.update", "user.ban"
+    pub target_type: String, // "content", "episode", "user", "role", "invite"
+    pub target_id: String,
+    pub before_json: String,
+    pub after_json: String,
+    pub ip: String,
+    pub created_at: String, // api_keys::TIMESTAMP_FORMAT
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditQuery {
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub target_type: Option<String>,
+    pub target_id: Option<String>,
+    pub from: Option<String>, // RFC 3339, inclusive
+    pub to: Option<String>, // RFC 3339, exclusive
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+}
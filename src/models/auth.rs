@@ -1,13 +1,18 @@
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct RefreshToken {
-    pub token: String,
-    pub user_id: String,
+pub struct RefreshRequest {
+    pub refresh_token: String, // "<session_id>.<secret>", the session id makes lookup direct
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct RefreshRequest {
-    pub refresh_token: String,
-    pub user_id: String, // Added user_id to simplify lookup without reverse index
+// One logged-in device. Each session is a refresh token rotation family.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Session {
+    pub id: String,
+    pub user_id: String,
+    pub device_name: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: String,
+    pub last_seen: String,
+    pub current: bool, // true for the session of the requesting access token
 }
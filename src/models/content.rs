@@ -1,8 +1,14 @@
 use serde::{Deserialize, Serialize};
-use sqlx::FromRow;
+use sqlx::any::AnyRow;
+use sqlx::{FromRow, Row};
+use crate::query::{nullable, real};
 // use chrono::NaiveDateTime;
 
-#[derive(Debug, Serialize, Deserialize, Clone, FromRow)]
+// Select these instead of `*`: created_at is DATETIME on SQLite
+pub const ANIME_COLUMNS: &str = "id, title, description, content_type, status, schedule_day, thumbnail_url, CAST(created_at AS TEXT) AS created_at, rating, year";
+pub const EPISODE_COLUMNS: &str = "id, series_id, title, episode_number, video_path, CAST(created_at AS TEXT) AS created_at";
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct AnimeSeries {
     pub id: String,
     pub title: String,
@@ -13,8 +19,26 @@ pub struct AnimeSeries {
     pub thumbnail_url: Option<String>,
     pub created_at: Option<String>, // String
     pub rating: Option<f32>,
-    #[sqlx(skip)]
-    pub genres: Vec<Genre>,
+    pub year: Option<i32>, // Release year
+    pub genres: Vec<Genre>, // Filled in separately
+}
+
+impl FromRow<'_, AnyRow> for AnimeSeries {
+    fn from_row(row: &AnyRow) -> Result<Self, sqlx::Error> {
+        Ok(AnimeSeries {
+            id: row.try_get("id")?,
+            title: row.try_get("title")?,
+            description: nullable(row, "description")?,
+            content_type: row.try_get("content_type")?,
+            status: row.try_get("status")?,
+            schedule_day: nullable(row, "schedule_day")?,
+            thumbnail_url: nullable(row, "thumbnail_url")?,
+            created_at: nullable(row, "created_at")?,
+            rating: real(row, "rating")?.map(|r| r as f32),
+            year: nullable(row, "year")?,
+            genres: Vec::new(),
+        })
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, FromRow)]
@@ -23,7 +47,7 @@ pub struct Genre {
     pub name: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, FromRow)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Episode {
     pub id: String,
     pub series_id: String,
@@ -33,6 +57,19 @@ pub struct Episode {
     pub created_at: Option<String>, // String
 }
 
+impl FromRow<'_, AnyRow> for Episode {
+    fn from_row(row: &AnyRow) -> Result<Self, sqlx::Error> {
+        Ok(Episode {
+            id: row.try_get("id")?,
+            series_id: row.try_get("series_id")?,
+            title: row.try_get("title")?,
+            episode_number: row.try_get("episode_number")?,
+            video_path: row.try_get("video_path")?,
+            created_at: nullable(row, "created_at")?,
+        })
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateAnimeRequest {
     pub title: String,
@@ -41,6 +78,7 @@ pub struct CreateAnimeRequest {
     pub status: String,
     pub schedule_day: Option<String>,
     pub rating: Option<f32>,
+    pub year: Option<i32>,
     pub genre_ids: Option<Vec<i64>>,
 }
 
@@ -52,6 +90,7 @@ pub struct UpdateAnimeRequest {
     pub status: Option<String>,
     pub schedule_day: Option<String>,
     pub rating: Option<f32>,
+    pub year: Option<i32>,
     pub genre_ids: Option<Vec<i64>>,
 }
 
@@ -61,3 +100,20 @@ pub struct CreateEpisodeRequest {
     pub title: String,
     pub episode_number: i32,
 }
+
+// Query string of the catalogue lists (/api/anime, /api/donghua, /api/movies,
+// /api/all). Offset paging with `page`, or keyset paging by following
+// `cursor` values from a previous response; `cursor` wins when both are given.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct CatalogueQuery {
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+    pub cursor: Option<String>,
+    pub sort: Option<String>,  // title, rating, created_at (default) or latest_episode
+    pub order: Option<String>, // asc or desc; title defaults to asc, the rest to desc
+    pub status: Option<String>,
+    pub genre: Option<String>, // Comma-separated genre ids, all must match
+    pub year: Option<i32>,
+    pub min_rating: Option<f32>,
+    pub schedule_day: Option<String>,
+}
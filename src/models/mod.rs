@@ -1,7 +1,9 @@
 pub mod user;
 pub mod content;
 pub mod auth;
+pub mod role;
+pub mod api_key;
+pub mod profile;
+pub mod audit;
 
 pub use user::*;
-pub use content::*;
-pub use auth::*;
@@ -0,0 +1,58 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+// Self-service part of the account, one row per user in `user_profiles`.
+// Columns are TEXT NOT NULL so the row decodes through sqlx::Any on SQLite.
+#[derive(Debug, Serialize, Deserialize, Clone, FromRow)]
+pub struct Profile {
+    pub display_name: String,
+    pub avatar_url: String,
+    pub preferred_language: String, // BCP 47 tag, e.g. "en", "id", "zh-CN"
+    #[serde(skip)]
+    pub content_preferences: String, // ContentPreferences as JSON
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            display_name: String::new(),
+            avatar_url: String::new(),
+            preferred_language: "en".to_string(),
+            content_preferences: "{}".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ContentPreferences {
+    #[serde(default)]
+    pub content_types: Vec<String>, // "Anime", "Donghua", "Movie"
+    #[serde(default)]
+    pub genre_ids: Vec<i64>,
+}
+
+impl Profile {
+    pub fn preferences(&self) -> ContentPreferences {
+        serde_json::from_str(&self.content_preferences).unwrap_or_default()
+    }
+}
+
+// Omitted fields are left unchanged
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateProfileRequest {
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
+    pub preferred_language: Option<String>,
+    pub content_preferences: Option<ContentPreferences>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteAccountRequest {
+    pub password: Option<String>, // Required unless the account only signs in through a provider
+}
@@ -0,0 +1,50 @@
+use serde::{Deserialize, Serialize};
+use sqlx::any::AnyRow;
+use sqlx::{FromRow, Row};
+use crate::query::nullable;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Role {
+    pub name: String,
+    pub description: Option<String>,
+    pub builtin: i64, // 1 for the seeded "user", "admin", "superuser" roles
+    pub permissions: Vec<String>, // Filled in separately
+}
+
+impl FromRow<'_, AnyRow> for Role {
+    fn from_row(row: &AnyRow) -> Result<Self, sqlx::Error> {
+        Ok(Role {
+            name: row.try_get("name")?,
+            description: nullable(row, "description")?,
+            builtin: row.try_get("builtin")?,
+            permissions: Vec::new(),
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Permission {
+    pub name: String,
+    pub description: Option<String>,
+}
+
+impl FromRow<'_, AnyRow> for Permission {
+    fn from_row(row: &AnyRow) -> Result<Self, sqlx::Error> {
+        Ok(Permission {
+            name: row.try_get("name")?,
+            description: nullable(row, "description")?,
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateRoleRequest {
+    pub name: String,
+    pub description: Option<String>,
+    pub permissions: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AssignRoleRequest {
+    pub role: String,
+}
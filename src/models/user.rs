@@ -1,44 +1,190 @@
 use serde::{Deserialize, Serialize};
-use sqlx::FromRow;
+use sqlx::any::AnyRow;
+use sqlx::{FromRow, Row};
+use crate::query::nullable;
 // use chrono::NaiveDateTime; // Removed to avoid AnyPool decode issues
 
-#[derive(Debug, Serialize, Deserialize, Clone, FromRow)]
+// Select these instead of `*`: created_at is DATETIME on SQLite
+pub const USER_COLUMNS: &str = "id, username, password, role, CAST(created_at AS TEXT) AS created_at, email, email_verified, totp_secret, totp_enabled, totp_last_step, banned, ban_reason, suspended_until, suspension_reason";
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct User {
     pub id: String,
     pub username: String,
     #[serde(skip_serializing)]
     pub password: String,
-    pub role: String, // "user", "admin", "superuser"
+    pub role: String, // name of a row in `roles`, e.g. "user", "admin", "superuser"
     pub created_at: Option<String>, // Changed to String for sqlx::Any compatibility
+    pub email: Option<String>,
+    pub email_verified: i64, // 0/1, INTEGER for sqlx::Any compatibility
+    #[serde(skip_serializing)]
+    pub totp_secret: Option<String>, // Base32; set at enrollment, before it is confirmed
+    pub totp_enabled: i64, // 0/1
+    #[serde(skip_serializing)]
+    pub totp_last_step: i64, // Last accepted time step, so a code cannot be replayed
+    pub banned: i64, // 0/1
+    pub ban_reason: String,
+    pub suspended_until: String, // api_keys::TIMESTAMP_FORMAT, empty when not suspended
+    pub suspension_reason: String,
+}
+
+impl FromRow<'_, AnyRow> for User {
+    fn from_row(row: &AnyRow) -> Result<Self, sqlx::Error> {
+        Ok(User {
+            id: row.try_get("id")?,
+            username: row.try_get("username")?,
+            password: row.try_get("password")?,
+            role: row.try_get("role")?,
+            created_at: nullable(row, "created_at")?,
+            email: nullable(row, "email")?,
+            email_verified: row.try_get("email_verified")?,
+            totp_secret: nullable(row, "totp_secret")?,
+            totp_enabled: row.try_get("totp_enabled")?,
+            totp_last_step: row.try_get("totp_last_step")?,
+            banned: row.try_get("banned")?,
+            ban_reason: row.try_get("ban_reason")?,
+            suspended_until: row.try_get("suspended_until")?,
+            suspension_reason: row.try_get("suspension_reason")?,
+        })
+    }
+}
+
+// Why an account may not sign in or use its tokens right now
+#[derive(Debug, Clone, PartialEq)]
+pub enum AccountRestriction {
+    Banned { reason: String },
+    Suspended { until: String, reason: String },
+}
+
+impl AccountRestriction {
+    // `now` and `suspended_until` share a fixed-width format, so they compare as strings
+    pub fn check(banned: i64, ban_reason: &str, suspended_until: &str, suspension_reason: &str, now: &str) -> Option<Self> {
+        if banned != 0 {
+            return Some(AccountRestriction::Banned { reason: ban_reason.to_string() });
+        }
+        if !suspended_until.is_empty() && suspended_until > now {
+            return Some(AccountRestriction::Suspended { until: suspended_until.to_string(), reason: suspension_reason.to_string() });
+        }
+        None
+    }
+}
+
+impl User {
+    pub fn restriction(&self, now: &str) -> Option<AccountRestriction> {
+        AccountRestriction::check(self.banned, &self.ban_reason, &self.suspended_until, &self.suspension_reason, now)
+    }
+}
+
+// Row of the admin user list. Explicit columns instead of `SELECT *` so it
+// decodes through sqlx::Any on SQLite (no NULL, no DATETIME).
+#[derive(Debug, Serialize, Deserialize, Clone, FromRow)]
+pub struct UserSummary {
+    pub id: String,
+    pub username: String,
+    pub role: String,
+    pub email: String,
+    pub email_verified: i64,
+    pub totp_enabled: i64,
+    pub created_at: String,
+    pub banned: i64,
+    pub ban_reason: String,
+    pub suspended_until: String,
+    pub suspension_reason: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserListQuery {
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+    pub q: Option<String>, // Matches username or email, case-insensitive
+    pub role: Option<String>,
+    pub status: Option<String>, // "active", "suspended" or "banned"
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SuspendUserRequest {
+    pub until: String, // RFC 3339, must be in the future
+    #[serde(default)]
+    pub reason: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BanUserRequest {
+    #[serde(default)]
+    pub reason: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LoginRequest {
     pub username: String,
     pub password: String,
+    pub device_name: Option<String>, // Shown in the session list, e.g. "Living room TV"
+    #[serde(default)]
+    pub use_cookies: bool, // Browser clients: tokens in HttpOnly cookies, see auth::CookieSettings
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RegisterRequest {
     pub username: String,
     pub password: String,
+    pub invite_code: Option<String>, // Required when REGISTRATION_MODE=invite
+    pub email: Option<String>, // Needed for password reset; verified by email link
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct TokenClaims {
-    pub sub: String,
-    pub role: String,
-    pub exp: usize,
+pub struct ForgotPasswordRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub new_password: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct RefreshToken {
+pub struct VerifyEmailRequest {
     pub token: String,
-    pub user_id: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct RefreshRequest {
-    pub refresh_token: String,
-    pub user_id: String,
+pub struct MfaLoginRequest {
+    pub mfa_token: String,
+    pub code: String, // Current TOTP code or one of the recovery codes
+    #[serde(default)]
+    pub use_cookies: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TotpCodeRequest {
+    pub code: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DisableTotpRequest {
+    pub password: String,
+    pub code: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TokenClaims {
+    pub sub: String,
+    pub role: String,
+    #[serde(default)]
+    pub permissions: Vec<String>,
+    pub sid: Option<String>, // Session the token was issued for
+    #[serde(default)]
+    pub mfa: bool, // Session was opened with a second factor
+    #[serde(skip)]
+    pub api_key: Option<String>, // Set when authenticated with X-API-Key instead of a JWT
+    #[serde(default)]
+    pub jti: String, // Unique token id, key of the revocation denylist
+    pub iss: String,
+    pub aud: String,
+    #[serde(default)]
+    pub iat: usize,
+    #[serde(default)]
+    pub iat_ms: i64, // iat in milliseconds, so revocations within the same second are ordered
+    pub nbf: usize,
+    pub exp: usize,
 }
@@ -0,0 +1,69 @@
+use std::borrow::Cow;
+use sqlx::any::AnyRow;
+use sqlx::{Any, Decode, Row, Type, TypeInfo, ValueRef};
+use crate::db::DbKind;
+
+// Dialect-aware SQL for sqlx::Any, which passes statements through verbatim
+// and only decodes what both drivers report identically.
+//
+// Write queries once with `?` placeholders and run them through
+// `AppState::sql`. Select dates with `CAST(col AS TEXT)` (SQLite DATETIME
+// cannot be decoded) and decode nullable or REAL columns with the helpers
+// below in a hand-written FromRow. A `None` bind reaches Postgres as an
+// integer NULL, so cast placeholders that may be NULL inside expressions,
+// e.g. `COALESCE(CAST(? AS TEXT), title)`.
+
+// Numbers placeholders for Postgres ($1, $2, ...). A `?` inside a string
+// literal is left alone.
+pub fn render(kind: DbKind, sql: &str) -> Cow<'_, str> {
+    if kind == DbKind::Sqlite || !sql.contains('?') {
+        return Cow::Borrowed(sql);
+    }
+    let mut out = String::with_capacity(sql.len() + 8);
+    let mut n = 0;
+    let mut quoted = false;
+    for c in sql.chars() {
+        match c {
+            '\'' => {
+                quoted = !quoted;
+                out.push(c);
+            }
+            '?' if !quoted => {
+                n += 1;
+                out.push('$');
+                out.push_str(&n.to_string());
+            }
+            _ => out.push(c),
+        }
+    }
+    Cow::Owned(out)
+}
+
+// Option<T> straight from a row fails on NULL with both drivers: Any values
+// never report `is_null()`, so look at the value's type name instead. The type
+// check is skipped too, since SQLite types a column by the value in the
+// statement's first row, which may itself have been NULL.
+pub fn nullable<'r, T>(row: &'r AnyRow, column: &str) -> Result<Option<T>, sqlx::Error>
+where
+    T: Decode<'r, Any> + Type<Any>,
+{
+    if is_null(row, column)? {
+        return Ok(None);
+    }
+    row.try_get_unchecked(column).map(Some)
+}
+
+fn is_null(row: &AnyRow, column: &str) -> Result<bool, sqlx::Error> {
+    Ok(row.try_get_raw(column)?.type_info().name() == "NULL")
+}
+
+// REAL arrives as a double from SQLite and as a float from Postgres
+pub fn real(row: &AnyRow, column: &str) -> Result<Option<f64>, sqlx::Error> {
+    if is_null(row, column)? {
+        return Ok(None);
+    }
+    match row.try_get_unchecked::<f64, _>(column) {
+        Ok(v) => Ok(Some(v)),
+        Err(_) => row.try_get_unchecked::<f32, _>(column).map(|v| Some(v as f64)),
+    }
+}
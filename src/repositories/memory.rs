@@ -0,0 +1,339 @@
+use serde_json::{json, Value};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+use uuid::Uuid;
+use crate::models::audit::AuditEntry;
+use crate::models::content::{AnimeSeries, CreateAnimeRequest, CreateEpisodeRequest, Episode, Genre, UpdateAnimeRequest};
+use crate::models::user::{User, UserSummary};
+use crate::services::api_keys::timestamp_now;
+use crate::services::audit::{self, Actor};
+use crate::services::rbac::SUPERUSER_ROLE;
+use super::{ContentFilter, ContentPage, ContentSort, PageRequest, Position, SortField, SortKey, ContentRepository, EpisodeRepository, GenreRepository, RepoFuture, UserChange, UserFilter, UserRepository, UserStatus, UserWrite};
+
+// In-memory stand-in for the SQL repositories, for handler tests. Fill
+// `data` directly to arrange a test and read `audit` to check what was logged.
+#[derive(Default)]
+pub struct MemoryStore {
+    pub data: Mutex<MemoryData>,
+}
+
+#[derive(Default)]
+pub struct MemoryData {
+    pub anime: Vec<AnimeSeries>, // `genres` left empty, see anime_genres
+    pub anime_genres: Vec<(String, i64)>,
+    pub episodes: Vec<Episode>,
+    pub genres: Vec<Genre>,
+    pub users: Vec<User>,
+    pub role_permissions: Vec<(String, String)>, // (role, permission)
+    pub audit: Vec<AuditEntry>,
+}
+
+impl MemoryStore {
+    pub fn data(&self) -> MutexGuard<'_, MemoryData> {
+        self.data.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// Same shapes as the SQL audit snapshots
+impl MemoryData {
+    fn anime_snapshot(&self, id: &str) -> Option<Value> {
+        let a = self.anime.iter().find(|a| a.id == id)?;
+        let mut genre_ids: Vec<i64> = self.anime_genres.iter().filter(|(s, _)| s == id).map(|(_, g)| *g).collect();
+        genre_ids.sort();
+        Some(json!({
+            "id": a.id, "title": a.title, "description": a.description, "content_type": a.content_type,
+            "status": a.status, "schedule_day": a.schedule_day, "thumbnail_url": a.thumbnail_url,
+            "rating": a.rating.map(f64::from), "year": a.year, "genre_ids": genre_ids
+        }))
+    }
+
+    fn user_snapshot(&self, id: &str) -> Option<Value> {
+        let u = self.users.iter().find(|u| u.id == id)?;
+        Some(json!({
+            "id": u.id, "username": u.username, "role": u.role, "email": u.email, "banned": u.banned,
+            "ban_reason": u.ban_reason, "suspended_until": u.suspended_until, "suspension_reason": u.suspension_reason
+        }))
+    }
+
+    // Same rule as sql::is_last_superuser
+    fn is_last_superuser(&self, id: &str) -> bool {
+        let now = timestamp_now();
+        let mut active = self.users.iter().filter(|u| u.role == SUPERUSER_ROLE && u.banned == 0 && u.suspended_until <= now);
+        matches!((active.next(), active.next()), (Some(u), None) if u.id == id)
+    }
+}
+
+fn compare_keys(a: &SortKey, b: &SortKey) -> Ordering {
+    match (a, b) {
+        (SortKey::Number(x), SortKey::Number(y)) => x.total_cmp(y),
+        (SortKey::Text(x), SortKey::Text(y)) => x.cmp(y),
+        (SortKey::Number(_), SortKey::Text(_)) => Ordering::Less,
+        (SortKey::Text(_), SortKey::Number(_)) => Ordering::Greater,
+    }
+}
+
+fn done<T: Send + 'static>(value: T) -> RepoFuture<'static, T> {
+    Box::pin(async move { Ok(value) })
+}
+
+impl ContentRepository for MemoryStore {
+    fn list<'a>(&'a self, filter: &'a ContentFilter, sort: ContentSort, page: &'a PageRequest) -> RepoFuture<'a, ContentPage> {
+        let data = self.data();
+        let key = |a: &AnimeSeries| match sort.field {
+            SortField::Title => SortKey::Text(a.title.to_lowercase()),
+            SortField::Rating => SortKey::Number(a.rating.unwrap_or(0.0) as f64),
+            SortField::CreatedAt => SortKey::Text(a.created_at.clone().unwrap_or_default()),
+            SortField::LatestEpisode => SortKey::Text(data.episodes.iter()
+                .filter(|e| e.series_id == a.id)
+                .filter_map(|e| e.created_at.clone())
+                .max()
+                .unwrap_or_default()),
+        };
+        let same = |value: Option<&str>, wanted: &Option<String>| wanted.as_deref().is_none_or(|w| value.is_some_and(|v| v.eq_ignore_ascii_case(w)));
+        let mut rows: Vec<(AnimeSeries, SortKey)> = data.anime.iter()
+            .filter(|a| filter.content_type.as_deref().is_none_or(|ct| a.content_type == ct))
+            .filter(|a| same(Some(&a.status), &filter.status) && same(a.schedule_day.as_deref(), &filter.schedule_day))
+            .filter(|a| filter.year.is_none_or(|y| a.year == Some(y)))
+            .filter(|a| filter.min_rating.is_none_or(|r| a.rating.unwrap_or(0.0) >= r))
+            .filter(|a| filter.genre_ids.iter().all(|g| data.anime_genres.iter().any(|(s, id)| *s == a.id && id == g)))
+            .map(|a| (a.clone(), key(a)))
+            .collect();
+        let total = rows.len() as i64;
+
+        // Listing order of a row relative to a position
+        let order = |key: &SortKey, id: &str, other_key: &SortKey, other_id: &str| {
+            let o = compare_keys(key, other_key).then_with(|| id.cmp(other_id));
+            if sort.descending { o.reverse() } else { o }
+        };
+        rows.sort_by(|(a, ka), (b, kb)| order(ka, &a.id, kb, &b.id));
+        let skip = match &page.position {
+            Position::Page(n) => (n - 1).saturating_mul(page.per_page) as usize,
+            Position::After(c) => {
+                rows.retain(|(a, k)| order(k, &a.id, &c.key, &c.id) == Ordering::Greater);
+                0
+            }
+            Position::Before(c) => {
+                rows.retain(|(a, k)| order(k, &a.id, &c.key, &c.id) == Ordering::Less);
+                rows.reverse();
+                0
+            }
+        };
+        let rows = rows.into_iter().skip(skip).take(page.per_page as usize + 1).collect();
+        done(ContentPage::from_rows(rows, total, page))
+    }
+
+    fn scheduled(&self) -> RepoFuture<'_, Vec<AnimeSeries>> {
+        done(self.data().anime.iter().filter(|a| a.schedule_day.is_some()).cloned().collect())
+    }
+
+    fn search<'a>(&'a self, q: &'a str) -> RepoFuture<'a, Vec<AnimeSeries>> {
+        let q = q.to_lowercase();
+        done(self.data().anime.iter().filter(|a| a.title.to_lowercase().contains(&q)).cloned().collect())
+    }
+
+    fn find<'a>(&'a self, id: &'a str) -> RepoFuture<'a, Option<AnimeSeries>> {
+        done(self.data().anime.iter().find(|a| a.id == id).cloned())
+    }
+
+    fn create<'a>(&'a self, actor: &'a Actor, req: &'a CreateAnimeRequest) -> RepoFuture<'a, String> {
+        let mut data = self.data();
+        let id = Uuid::new_v4().to_string();
+        data.anime.push(AnimeSeries {
+            id: id.clone(),
+            title: req.title.clone(),
+            description: req.description.clone(),
+            content_type: req.content_type.clone(),
+            status: req.status.clone(),
+            schedule_day: req.schedule_day.clone(),
+            thumbnail_url: None,
+            created_at: Some(timestamp_now()),
+            rating: Some(req.rating.unwrap_or(0.0)),
+            year: req.year,
+            genres: Vec::new(),
+        });
+        for gid in req.genre_ids.iter().flatten() {
+            data.anime_genres.push((id.clone(), *gid));
+        }
+        let after = data.anime_snapshot(&id);
+        data.audit.push(audit::entry(actor, "content.create", "content", &id, None, after));
+        done(id)
+    }
+
+    fn update<'a>(&'a self, actor: &'a Actor, id: &'a str, req: &'a UpdateAnimeRequest) -> RepoFuture<'a, bool> {
+        let mut data = self.data();
+        let before = data.anime_snapshot(id);
+        let Some(a) = data.anime.iter_mut().find(|a| a.id == id) else {
+            return done(false);
+        };
+        if let Some(v) = &req.title { a.title = v.clone(); }
+        if let Some(v) = &req.description { a.description = Some(v.clone()); }
+        if let Some(v) = &req.content_type { a.content_type = v.clone(); }
+        if let Some(v) = &req.status { a.status = v.clone(); }
+        if let Some(v) = &req.schedule_day { a.schedule_day = Some(v.clone()); }
+        if let Some(v) = req.rating { a.rating = Some(v); }
+        if let Some(v) = req.year { a.year = Some(v); }
+        if let Some(g_ids) = &req.genre_ids {
+            data.anime_genres.retain(|(s, _)| s != id);
+            data.anime_genres.extend(g_ids.iter().map(|g| (id.to_string(), *g)));
+        }
+        let after = data.anime_snapshot(id);
+        data.audit.push(audit::entry(actor, "content.update", "content", id, before, after));
+        done(true)
+    }
+
+    fn delete<'a>(&'a self, actor: &'a Actor, id: &'a str) -> RepoFuture<'a, bool> {
+        let mut data = self.data();
+        let Some(before) = data.anime_snapshot(id) else {
+            return done(false);
+        };
+        // Like the ON DELETE CASCADE on the tables
+        data.anime.retain(|a| a.id != id);
+        data.anime_genres.retain(|(s, _)| s != id);
+        data.episodes.retain(|e| e.series_id != id);
+        data.audit.push(audit::entry(actor, "content.delete", "content", id, Some(before), None));
+        done(true)
+    }
+}
+
+impl EpisodeRepository for MemoryStore {
+    fn for_series<'a>(&'a self, series_id: &'a str) -> RepoFuture<'a, Vec<Episode>> {
+        let mut episodes: Vec<Episode> = self.data().episodes.iter().filter(|e| e.series_id == series_id).cloned().collect();
+        episodes.sort_by_key(|e| e.episode_number);
+        done(episodes)
+    }
+
+    fn create<'a>(&'a self, actor: &'a Actor, req: &'a CreateEpisodeRequest, video_path: &'a str) -> RepoFuture<'a, String> {
+        let mut data = self.data();
+        let id = Uuid::new_v4().to_string();
+        data.episodes.push(Episode {
+            id: id.clone(),
+            series_id: req.series_id.clone(),
+            title: req.title.clone(),
+            episode_number: req.episode_number,
+            video_path: video_path.to_string(),
+            created_at: Some(timestamp_now()),
+        });
+        let after = json!({"id": id, "series_id": req.series_id, "title": req.title, "episode_number": req.episode_number, "video_path": video_path});
+        data.audit.push(audit::entry(actor, "episode.create", "episode", &id, None, Some(after)));
+        done(id)
+    }
+}
+
+impl UserRepository for MemoryStore {
+    fn list<'a>(&'a self, filter: &'a UserFilter, page: i64, per_page: i64) -> RepoFuture<'a, (Vec<UserSummary>, i64)> {
+        let now = timestamp_now();
+        let q = filter.q.as_deref().map(str::to_lowercase);
+        let data = self.data();
+        let mut matches: Vec<&User> = data.users.iter()
+            .filter(|u| q.as_deref().is_none_or(|q| {
+                u.username.to_lowercase().contains(q) || u.email.as_deref().unwrap_or("").to_lowercase().contains(q)
+            }))
+            .filter(|u| filter.role.as_deref().is_none_or(|r| u.role == r))
+            .filter(|u| match filter.status {
+                None => true,
+                Some(UserStatus::Active) => u.banned == 0 && u.suspended_until <= now,
+                Some(UserStatus::Suspended) => u.banned == 0 && u.suspended_until > now,
+                Some(UserStatus::Banned) => u.banned == 1,
+            })
+            .collect();
+        matches.sort_by(|a, b| a.username.cmp(&b.username));
+        let total = matches.len() as i64;
+        let items = matches.into_iter()
+            .skip((page - 1).saturating_mul(per_page) as usize)
+            .take(per_page as usize)
+            .map(|u| UserSummary {
+                id: u.id.clone(),
+                username: u.username.clone(),
+                role: u.role.clone(),
+                email: u.email.clone().unwrap_or_default(),
+                email_verified: u.email_verified,
+                totp_enabled: u.totp_enabled,
+                created_at: u.created_at.clone().unwrap_or_default(),
+                banned: u.banned,
+                ban_reason: u.ban_reason.clone(),
+                suspended_until: u.suspended_until.clone(),
+                suspension_reason: u.suspension_reason.clone(),
+            })
+            .collect();
+        done((items, total))
+    }
+
+    fn find<'a>(&'a self, id: &'a str) -> RepoFuture<'a, Option<User>> {
+        done(self.data().users.iter().find(|u| u.id == id).cloned())
+    }
+
+    fn role_permissions<'a>(&'a self, role: &'a str) -> RepoFuture<'a, Vec<String>> {
+        let mut permissions: Vec<String> = self.data().role_permissions.iter().filter(|(r, _)| r == role).map(|(_, p)| p.clone()).collect();
+        permissions.sort();
+        done(permissions)
+    }
+
+    fn update<'a>(&'a self, actor: &'a Actor, id: &'a str, change: &'a UserChange) -> RepoFuture<'a, UserWrite> {
+        let mut data = self.data();
+        if change.removes_superuser() && data.is_last_superuser(id) {
+            return done(UserWrite::LastSuperuser);
+        }
+        let before = data.user_snapshot(id);
+        let Some(u) = data.users.iter_mut().find(|u| u.id == id) else {
+            return done(UserWrite::NotFound);
+        };
+        match change {
+            UserChange::Role(role) => u.role = role.clone(),
+            UserChange::Suspend { until, reason } => {
+                u.suspended_until = until.clone();
+                u.suspension_reason = reason.clone();
+            }
+            UserChange::Unsuspend => {
+                u.suspended_until.clear();
+                u.suspension_reason.clear();
+            }
+            UserChange::Ban { reason } => {
+                u.banned = 1;
+                u.ban_reason = reason.clone();
+            }
+            UserChange::Unban => {
+                u.banned = 0;
+                u.ban_reason.clear();
+            }
+        }
+        let after = data.user_snapshot(id);
+        data.audit.push(audit::entry(actor, change.action(), "user", id, before, after));
+        done(UserWrite::Done)
+    }
+
+    fn delete<'a>(&'a self, actor: &'a Actor, id: &'a str) -> RepoFuture<'a, UserWrite> {
+        let mut data = self.data();
+        if data.is_last_superuser(id) {
+            return done(UserWrite::LastSuperuser);
+        }
+        let Some(before) = data.user_snapshot(id) else {
+            return done(UserWrite::NotFound);
+        };
+        data.users.retain(|u| u.id != id);
+        data.audit.push(audit::entry(actor, "user.delete", "user", id, Some(before), None));
+        done(UserWrite::Done)
+    }
+}
+
+impl GenreRepository for MemoryStore {
+    fn list(&self) -> RepoFuture<'_, Vec<Genre>> {
+        let mut genres = self.data().genres.clone();
+        genres.sort_by(|a, b| a.name.cmp(&b.name));
+        done(genres)
+    }
+
+    fn for_series<'a>(&'a self, series_ids: &'a [String]) -> RepoFuture<'a, HashMap<String, Vec<Genre>>> {
+        let data = self.data();
+        let mut genres: HashMap<String, Vec<Genre>> = HashMap::new();
+        for (series_id, genre_id) in data.anime_genres.iter().filter(|(s, _)| series_ids.contains(s)) {
+            if let Some(g) = data.genres.iter().find(|g| g.id == *genre_id) {
+                genres.entry(series_id.clone()).or_default().push(g.clone());
+            }
+        }
+        for list in genres.values_mut() {
+            list.sort_by_key(|g| g.id);
+        }
+        done(genres)
+    }
+}
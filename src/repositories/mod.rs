@@ -0,0 +1,258 @@
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use sqlx::AnyPool;
+use std::sync::Arc;
+use crate::db::DbKind;
+use crate::models::content::{AnimeSeries, CreateAnimeRequest, CreateEpisodeRequest, Episode, Genre, UpdateAnimeRequest};
+use crate::models::user::{User, UserSummary};
+use crate::services::audit::Actor;
+use crate::services::rbac::SUPERUSER_ROLE;
+
+#[cfg(test)]
+pub mod memory;
+pub mod sql;
+
+#[cfg(test)]
+pub use memory::MemoryStore;
+pub use sql::SqlRepository;
+
+// Data access for the catalogue and admin handlers, reached through
+// `AppState::repos`. Mutations take the acting admin and write the audit entry
+// together with the change. Series come back without genres; ask
+// GenreRepository for them, a whole list at a time.
+
+pub type RepoFuture<'a, T> = BoxFuture<'a, Result<T, sqlx::Error>>;
+
+pub trait ContentRepository: Send + Sync {
+    // One page of the catalogue, plus the total number of matches
+    fn list<'a>(&'a self, filter: &'a ContentFilter, sort: ContentSort, page: &'a PageRequest) -> RepoFuture<'a, ContentPage>;
+    fn scheduled(&self) -> RepoFuture<'_, Vec<AnimeSeries>>;
+    // Case-insensitive substring of the title
+    fn search<'a>(&'a self, q: &'a str) -> RepoFuture<'a, Vec<AnimeSeries>>;
+    fn find<'a>(&'a self, id: &'a str) -> RepoFuture<'a, Option<AnimeSeries>>;
+    // Returns the new id
+    fn create<'a>(&'a self, actor: &'a Actor, req: &'a CreateAnimeRequest) -> RepoFuture<'a, String>;
+    // update and delete return false when there is no such series
+    fn update<'a>(&'a self, actor: &'a Actor, id: &'a str, req: &'a UpdateAnimeRequest) -> RepoFuture<'a, bool>;
+    fn delete<'a>(&'a self, actor: &'a Actor, id: &'a str) -> RepoFuture<'a, bool>;
+}
+
+pub trait EpisodeRepository: Send + Sync {
+    // Ordered by episode number
+    fn for_series<'a>(&'a self, series_id: &'a str) -> RepoFuture<'a, Vec<Episode>>;
+    fn create<'a>(&'a self, actor: &'a Actor, req: &'a CreateEpisodeRequest, video_path: &'a str) -> RepoFuture<'a, String>;
+}
+
+pub trait UserRepository: Send + Sync {
+    // One page ordered by username, plus the total number of matches
+    fn list<'a>(&'a self, filter: &'a UserFilter, page: i64, per_page: i64) -> RepoFuture<'a, (Vec<UserSummary>, i64)>;
+    fn find<'a>(&'a self, id: &'a str) -> RepoFuture<'a, Option<User>>;
+    // Permissions granted to a role, ordered by name
+    fn role_permissions<'a>(&'a self, role: &'a str) -> RepoFuture<'a, Vec<String>>;
+    // Changes that would leave no active superuser are refused in the same
+    // transaction as the write, so two admins cannot race past the check
+    fn update<'a>(&'a self, actor: &'a Actor, id: &'a str, change: &'a UserChange) -> RepoFuture<'a, UserWrite>;
+    // Takes the rows the user owns (API keys, identities, profile...) with it
+    fn delete<'a>(&'a self, actor: &'a Actor, id: &'a str) -> RepoFuture<'a, UserWrite>;
+}
+
+pub trait GenreRepository: Send + Sync {
+    // Ordered by name
+    fn list(&self) -> RepoFuture<'_, Vec<Genre>>;
+    // Genres of every listed series in one round trip, keyed by series id and
+    // ordered by genre id. Series without genres are left out.
+    fn for_series<'a>(&'a self, series_ids: &'a [String]) -> RepoFuture<'a, HashMap<String, Vec<Genre>>>;
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ContentFilter {
+    pub content_type: Option<String>,
+    pub status: Option<String>, // Case-insensitive
+    pub genre_ids: Vec<i64>,    // The series must have every one of them
+    pub year: Option<i32>,
+    pub min_rating: Option<f32>,
+    pub schedule_day: Option<String>, // Case-insensitive
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortField {
+    Title,
+    Rating,
+    CreatedAt,
+    LatestEpisode, // Newest episode upload; series without episodes sort as oldest
+}
+
+impl SortField {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "title" => Some(SortField::Title),
+            "rating" => Some(SortField::Rating),
+            "created_at" => Some(SortField::CreatedAt),
+            "latest_episode" => Some(SortField::LatestEpisode),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SortField::Title => "title",
+            SortField::Rating => "rating",
+            SortField::CreatedAt => "created_at",
+            SortField::LatestEpisode => "latest_episode",
+        }
+    }
+}
+
+// Ties on the sort value are broken by id, in the same direction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ContentSort {
+    pub field: SortField,
+    pub descending: bool,
+}
+
+// A series' value for the sort field: the lowercased title, the rating (0 when
+// unset) or a timestamp ("" when there is none)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SortKey {
+    Number(f64),
+    Text(String),
+}
+
+// Keyset position: the series with this sort value and id
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Cursor {
+    pub key: SortKey,
+    pub id: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum Position {
+    Page(i64), // 1-based offset paging
+    After(Cursor),
+    Before(Cursor),
+}
+
+#[derive(Debug, Clone)]
+pub struct PageRequest {
+    pub per_page: i64,
+    pub position: Position,
+}
+
+// `next` and `prev` are set when there is more to see in that direction
+#[derive(Debug, Clone)]
+pub struct ContentPage {
+    pub items: Vec<AnimeSeries>,
+    pub total: i64,
+    pub next: Option<Cursor>,
+    pub prev: Option<Cursor>,
+}
+
+impl ContentPage {
+    // `rows` are fetched in scan order, up to per_page + 1 of them; for
+    // Position::Before that is the listing order reversed.
+    pub fn from_rows(mut rows: Vec<(AnimeSeries, SortKey)>, total: i64, page: &PageRequest) -> Self {
+        let more = rows.len() as i64 > page.per_page;
+        rows.truncate(page.per_page as usize);
+        let (has_prev, has_next) = match page.position {
+            Position::Page(n) => (n > 1, more),
+            Position::After(_) => (true, more),
+            Position::Before(_) => {
+                rows.reverse();
+                (more, true)
+            }
+        };
+        let cursor = |(a, key): &(AnimeSeries, SortKey)| Cursor { key: key.clone(), id: a.id.clone() };
+        ContentPage {
+            next: rows.last().filter(|_| has_next).map(cursor),
+            prev: rows.first().filter(|_| has_prev).map(cursor),
+            total,
+            items: rows.into_iter().map(|(a, _)| a).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UserStatus {
+    Active,
+    Suspended,
+    Banned,
+}
+
+impl UserStatus {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "active" => Some(UserStatus::Active),
+            "suspended" => Some(UserStatus::Suspended),
+            "banned" => Some(UserStatus::Banned),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct UserFilter {
+    pub q: Option<String>, // Substring of username or email, case-insensitive
+    pub role: Option<String>,
+    pub status: Option<UserStatus>,
+}
+
+// The admin changes to a user row, each with its own audit action
+#[derive(Debug, Clone)]
+pub enum UserChange {
+    Role(String),
+    Suspend { until: String, reason: String }, // until in api_keys::TIMESTAMP_FORMAT
+    Unsuspend,
+    Ban { reason: String },
+    Unban,
+}
+
+impl UserChange {
+    // Whether the change takes a superuser out of the active ones
+    pub fn removes_superuser(&self) -> bool {
+        match self {
+            UserChange::Role(role) => role != SUPERUSER_ROLE,
+            UserChange::Suspend { .. } | UserChange::Ban { .. } => true,
+            UserChange::Unsuspend | UserChange::Unban => false,
+        }
+    }
+
+    pub fn action(&self) -> &'static str {
+        match self {
+            UserChange::Role(_) => "user.role",
+            UserChange::Suspend { .. } => "user.suspend",
+            UserChange::Unsuspend => "user.unsuspend",
+            UserChange::Ban { .. } => "user.ban",
+            UserChange::Unban => "user.unban",
+        }
+    }
+}
+
+// Outcome of UserRepository::update and delete
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserWrite {
+    Done,
+    NotFound,
+    LastSuperuser, // The user is the only superuser neither banned nor suspended
+}
+
+#[derive(Clone)]
+pub struct Repositories {
+    pub content: Arc<dyn ContentRepository>,
+    pub episodes: Arc<dyn EpisodeRepository>,
+    pub users: Arc<dyn UserRepository>,
+    pub genres: Arc<dyn GenreRepository>,
+}
+
+impl Repositories {
+    pub fn sql(pool: &AnyPool, kind: DbKind) -> Self {
+        let repo = Arc::new(SqlRepository::new(pool.clone(), kind));
+        Repositories { content: repo.clone(), episodes: repo.clone(), users: repo.clone(), genres: repo }
+    }
+
+    #[cfg(test)]
+    pub fn memory(store: Arc<MemoryStore>) -> Self {
+        Repositories { content: store.clone(), episodes: store.clone(), users: store.clone(), genres: store }
+    }
+}
@@ -0,0 +1,546 @@
+use serde_json::{json, Value};
+use sqlx::any::AnyArguments;
+use sqlx::query::{Query, QueryAs};
+use sqlx::{Any, AnyConnection, AnyPool, FromRow, Row};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use uuid::Uuid;
+use crate::db::DbKind;
+use crate::models::content::{AnimeSeries, CreateAnimeRequest, CreateEpisodeRequest, Episode, Genre, UpdateAnimeRequest, ANIME_COLUMNS, EPISODE_COLUMNS};
+use crate::models::user::{User, UserSummary, USER_COLUMNS};
+use crate::query;
+use crate::services::api_keys::timestamp_now;
+use crate::services::audit::{self, Actor};
+use crate::services::rbac::SUPERUSER_ROLE;
+use super::{ContentFilter, ContentPage, ContentSort, PageRequest, Position, SortField, SortKey, ContentRepository, EpisodeRepository, GenreRepository, RepoFuture, UserChange, UserFilter, UserRepository, UserStatus, UserWrite};
+
+// Audit snapshots: the columns an admin can change, dates left out
+const ANIME_SNAPSHOT: &str = "SELECT id, title, description, content_type, status, schedule_day, thumbnail_url, rating, year FROM anime_series WHERE id = ?";
+const EPISODE_SNAPSHOT: &str = "SELECT id, series_id, title, episode_number, video_path FROM episodes WHERE id = ?";
+// Series ids per genre query, well under SQLite's limit on bound parameters
+pub const GENRE_BATCH_SIZE: usize = 500;
+const USER_SNAPSHOT: &str = "SELECT id, username, role, email, banned, ban_reason, suspended_until, suspension_reason FROM users WHERE id = ?";
+// Tables holding rows owned by a user, deleted before the user itself
+const USER_DATA_TABLES: &[&str] = &["recovery_codes", "api_keys", "user_identities", "user_profiles"];
+
+pub struct SqlRepository {
+    pool: AnyPool,
+    kind: DbKind,
+}
+
+// Listing query parameters, bound in order
+#[derive(Clone)]
+enum Arg {
+    Text(String),
+    Int(i64),
+    Real(f64),
+}
+
+fn bind_args<'q, Q: BindArg<'q>>(mut query: Q, args: &[Arg]) -> Q {
+    for arg in args {
+        query = query.bind_arg(arg.clone());
+    }
+    query
+}
+
+// query() and query_as() share no bind trait in sqlx
+trait BindArg<'q> {
+    fn bind_arg(self, arg: Arg) -> Self;
+}
+
+impl<'q> BindArg<'q> for Query<'q, Any, AnyArguments<'q>> {
+    fn bind_arg(self, arg: Arg) -> Self {
+        match arg {
+            Arg::Text(v) => self.bind(v),
+            Arg::Int(v) => self.bind(v),
+            Arg::Real(v) => self.bind(v),
+        }
+    }
+}
+
+impl<'q, O> BindArg<'q> for QueryAs<'q, Any, O, AnyArguments<'q>> {
+    fn bind_arg(self, arg: Arg) -> Self {
+        match arg {
+            Arg::Text(v) => self.bind(v),
+            Arg::Int(v) => self.bind(v),
+            Arg::Real(v) => self.bind(v),
+        }
+    }
+}
+
+fn filter_conditions(filter: &ContentFilter) -> (Vec<String>, Vec<Arg>) {
+    let mut conditions = vec![];
+    let mut args = vec![];
+    if let Some(v) = &filter.content_type {
+        conditions.push("content_type = ?".to_string());
+        args.push(Arg::Text(v.clone()));
+    }
+    if let Some(v) = &filter.status {
+        conditions.push("LOWER(status) = LOWER(?)".to_string());
+        args.push(Arg::Text(v.clone()));
+    }
+    if let Some(v) = &filter.schedule_day {
+        conditions.push("LOWER(schedule_day) = LOWER(?)".to_string());
+        args.push(Arg::Text(v.clone()));
+    }
+    if let Some(v) = filter.year {
+        conditions.push("year = ?".to_string());
+        args.push(Arg::Int(v as i64));
+    }
+    if let Some(v) = filter.min_rating {
+        conditions.push(format!("{} >= CAST(? AS REAL)", sort_expr(SortField::Rating)));
+        args.push(Arg::Real(v as f64));
+    }
+    for gid in &filter.genre_ids {
+        conditions.push("EXISTS (SELECT 1 FROM anime_genres ag WHERE ag.anime_id = anime_series.id AND ag.genre_id = ?)".to_string());
+        args.push(Arg::Int(*gid));
+    }
+    (conditions, args)
+}
+
+fn where_clause(conditions: &[String]) -> String {
+    if conditions.is_empty() {
+        String::new()
+    } else {
+        format!(" WHERE {}", conditions.join(" AND "))
+    }
+}
+
+// The value a listing sorts on, also compared against keyset cursors
+fn sort_expr(field: SortField) -> &'static str {
+    match field {
+        SortField::Title => "LOWER(title)",
+        SortField::Rating => "COALESCE(rating, CAST(0 AS REAL))",
+        SortField::CreatedAt => "COALESCE(CAST(created_at AS TEXT), '')",
+        SortField::LatestEpisode => "COALESCE((SELECT CAST(MAX(e.created_at) AS TEXT) FROM episodes e WHERE e.series_id = anime_series.id), '')",
+    }
+}
+
+impl SqlRepository {
+    pub fn new(pool: AnyPool, kind: DbKind) -> Self {
+        SqlRepository { pool, kind }
+    }
+
+    fn sql<'a>(&self, sql: &'a str) -> Cow<'a, str> {
+        query::render(self.kind, sql)
+    }
+
+    // Series row plus its genre ids
+    async fn anime_snapshot(&self, conn: &mut AnyConnection, id: &str) -> Result<Option<Value>, sqlx::Error> {
+        let mut snapshot = match audit::snapshot(&mut *conn, self.kind, ANIME_SNAPSHOT, id).await? {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+        let genres: Vec<(i64,)> = sqlx::query_as(&self.sql("SELECT genre_id FROM anime_genres WHERE anime_id = ? ORDER BY genre_id"))
+            .bind(id)
+            .fetch_all(&mut *conn)
+            .await?;
+        snapshot["genre_ids"] = json!(genres.into_iter().map(|(g,)| g).collect::<Vec<_>>());
+        Ok(Some(snapshot))
+    }
+
+    async fn insert_genres(&self, conn: &mut AnyConnection, id: &str, genre_ids: &[i64]) -> Result<(), sqlx::Error> {
+        for gid in genre_ids {
+            sqlx::query(&self.sql("INSERT INTO anime_genres (anime_id, genre_id) VALUES (?, ?)"))
+                .bind(id)
+                .bind(gid)
+                .execute(&mut *conn)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+impl ContentRepository for SqlRepository {
+    fn list<'a>(&'a self, filter: &'a ContentFilter, sort: ContentSort, page: &'a PageRequest) -> RepoFuture<'a, ContentPage> {
+        Box::pin(async move {
+            let (mut conditions, mut args) = filter_conditions(filter);
+            let count_sql = format!("SELECT COUNT(*) FROM anime_series{}", where_clause(&conditions));
+            let (total,): (i64,) = bind_args(sqlx::query_as(&self.sql(&count_sql)), &args)
+                .fetch_one(&self.pool)
+                .await?;
+
+            let expr = sort_expr(sort.field);
+            let value = if sort.field == SortField::Rating { "CAST(? AS REAL)" } else { "?" };
+            let (cursor, backwards) = match &page.position {
+                Position::Page(_) => (None, false),
+                Position::After(c) => (Some(c), false),
+                Position::Before(c) => (Some(c), true),
+            };
+            if let Some(c) = cursor {
+                let op = if sort.descending == backwards { ">" } else { "<" };
+                conditions.push(format!("({expr} {op} {value} OR ({expr} = {value} AND id {op} ?))"));
+                let key = match &c.key {
+                    SortKey::Number(n) => Arg::Real(*n),
+                    SortKey::Text(t) => Arg::Text(t.clone()),
+                };
+                args.extend([key.clone(), key, Arg::Text(c.id.clone())]);
+            }
+            let offset = match page.position {
+                Position::Page(n) => (n - 1).saturating_mul(page.per_page),
+                _ => 0,
+            };
+            let dir = if sort.descending != backwards { "DESC" } else { "ASC" };
+            let list_sql = format!(
+                "SELECT {}, {} AS sort_key FROM anime_series{} ORDER BY sort_key {dir}, id {dir} LIMIT ? OFFSET ?",
+                ANIME_COLUMNS, expr, where_clause(&conditions)
+            );
+            let rows = bind_args(sqlx::query(&self.sql(&list_sql)), &args)
+                .bind(page.per_page + 1)
+                .bind(offset)
+                .fetch_all(&self.pool)
+                .await?;
+
+            let mut keyed = Vec::with_capacity(rows.len());
+            for row in &rows {
+                let key = match sort.field {
+                    SortField::Rating => SortKey::Number(query::real(row, "sort_key")?.unwrap_or(0.0)),
+                    _ => SortKey::Text(row.try_get("sort_key")?),
+                };
+                keyed.push((AnimeSeries::from_row(row)?, key));
+            }
+            Ok(ContentPage::from_rows(keyed, total, page))
+        })
+    }
+
+    fn scheduled(&self) -> RepoFuture<'_, Vec<AnimeSeries>> {
+        Box::pin(async move {
+            sqlx::query_as(&format!("SELECT {} FROM anime_series WHERE schedule_day IS NOT NULL", ANIME_COLUMNS))
+                .fetch_all(&self.pool)
+                .await
+        })
+    }
+
+    fn search<'a>(&'a self, q: &'a str) -> RepoFuture<'a, Vec<AnimeSeries>> {
+        Box::pin(async move {
+            // Note: || concatenation is standard SQL. LOWER on both sides: LIKE
+            // is case-sensitive on Postgres
+            sqlx::query_as(&self.sql(&format!("SELECT {} FROM anime_series WHERE LOWER(title) LIKE '%' || LOWER(?) || '%'", ANIME_COLUMNS)))
+                .bind(q)
+                .fetch_all(&self.pool)
+                .await
+        })
+    }
+
+    fn find<'a>(&'a self, id: &'a str) -> RepoFuture<'a, Option<AnimeSeries>> {
+        Box::pin(async move {
+            sqlx::query_as(&self.sql(&format!("SELECT {} FROM anime_series WHERE id = ?", ANIME_COLUMNS)))
+                .bind(id)
+                .fetch_optional(&self.pool)
+                .await
+        })
+    }
+
+    fn create<'a>(&'a self, actor: &'a Actor, req: &'a CreateAnimeRequest) -> RepoFuture<'a, String> {
+        Box::pin(async move {
+            let id = Uuid::new_v4().to_string();
+            let mut tx = self.pool.begin().await?;
+            sqlx::query(&self.sql(
+                "INSERT INTO anime_series (id, title, description, content_type, status, schedule_day, rating, year) VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
+            ))
+            .bind(&id)
+            .bind(&req.title)
+            .bind(&req.description)
+            .bind(&req.content_type)
+            .bind(&req.status)
+            .bind(&req.schedule_day)
+            .bind(req.rating.unwrap_or(0.0))
+            .bind(req.year)
+            .execute(&mut *tx)
+            .await?;
+            if let Some(g_ids) = &req.genre_ids {
+                self.insert_genres(&mut tx, &id, g_ids).await?;
+            }
+
+            let after = self.anime_snapshot(&mut tx, &id).await?;
+            audit::record(&mut tx, self.kind, actor, "content.create", "content", &id, None, after).await?;
+            tx.commit().await?;
+            Ok(id)
+        })
+    }
+
+    fn update<'a>(&'a self, actor: &'a Actor, id: &'a str, req: &'a UpdateAnimeRequest) -> RepoFuture<'a, bool> {
+        Box::pin(async move {
+            let mut tx = self.pool.begin().await?;
+            let before = match self.anime_snapshot(&mut tx, id).await? {
+                Some(b) => b,
+                None => return Ok(false),
+            };
+
+            sqlx::query(&self.sql(
+                "UPDATE anime_series SET
+                    title = COALESCE(CAST(? AS TEXT), title),
+                    description = COALESCE(CAST(? AS TEXT), description),
+                    content_type = COALESCE(CAST(? AS TEXT), content_type),
+                    status = COALESCE(CAST(? AS TEXT), status),
+                    schedule_day = COALESCE(CAST(? AS TEXT), schedule_day),
+                    rating = COALESCE(CAST(? AS REAL), rating),
+                    year = COALESCE(CAST(? AS INTEGER), year)
+                WHERE id = ?"
+            ))
+            .bind(&req.title)
+            .bind(&req.description)
+            .bind(&req.content_type)
+            .bind(&req.status)
+            .bind(&req.schedule_day)
+            .bind(req.rating)
+            .bind(req.year)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+
+            // Genres: clear old, insert new (simplest strategy)
+            if let Some(g_ids) = &req.genre_ids {
+                sqlx::query(&self.sql("DELETE FROM anime_genres WHERE anime_id = ?"))
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?;
+                self.insert_genres(&mut tx, id, g_ids).await?;
+            }
+
+            let after = self.anime_snapshot(&mut tx, id).await?;
+            audit::record(&mut tx, self.kind, actor, "content.update", "content", id, Some(before), after).await?;
+            tx.commit().await?;
+            Ok(true)
+        })
+    }
+
+    fn delete<'a>(&'a self, actor: &'a Actor, id: &'a str) -> RepoFuture<'a, bool> {
+        Box::pin(async move {
+            let mut tx = self.pool.begin().await?;
+            let before = match self.anime_snapshot(&mut tx, id).await? {
+                Some(b) => b,
+                None => return Ok(false),
+            };
+            sqlx::query(&self.sql("DELETE FROM anime_series WHERE id = ?"))
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+            audit::record(&mut tx, self.kind, actor, "content.delete", "content", id, Some(before), None).await?;
+            tx.commit().await?;
+            Ok(true)
+        })
+    }
+}
+
+impl EpisodeRepository for SqlRepository {
+    fn for_series<'a>(&'a self, series_id: &'a str) -> RepoFuture<'a, Vec<Episode>> {
+        Box::pin(async move {
+            sqlx::query_as(&self.sql(&format!("SELECT {} FROM episodes WHERE series_id = ? ORDER BY episode_number", EPISODE_COLUMNS)))
+                .bind(series_id)
+                .fetch_all(&self.pool)
+                .await
+        })
+    }
+
+    fn create<'a>(&'a self, actor: &'a Actor, req: &'a CreateEpisodeRequest, video_path: &'a str) -> RepoFuture<'a, String> {
+        Box::pin(async move {
+            let id = Uuid::new_v4().to_string();
+            let mut tx = self.pool.begin().await?;
+            sqlx::query(&self.sql(
+                "INSERT INTO episodes (id, series_id, title, episode_number, video_path) VALUES (?, ?, ?, ?, ?)"
+            ))
+            .bind(&id)
+            .bind(&req.series_id)
+            .bind(&req.title)
+            .bind(req.episode_number)
+            .bind(video_path)
+            .execute(&mut *tx)
+            .await?;
+
+            let after = audit::snapshot(&mut tx, self.kind, EPISODE_SNAPSHOT, &id).await?;
+            audit::record(&mut tx, self.kind, actor, "episode.create", "episode", &id, None, after).await?;
+            tx.commit().await?;
+            Ok(id)
+        })
+    }
+}
+
+// True when the user is the only superuser neither banned nor suspended. Run
+// it in the transaction that makes the change: on Postgres FOR UPDATE locks
+// the active superusers until commit, so a concurrent change waits and then
+// sees this one; SQLite allows one writer, and a transaction that read stale
+// rows fails its write instead of committing.
+async fn is_last_superuser(conn: &mut AnyConnection, kind: DbKind, id: &str) -> Result<bool, sqlx::Error> {
+    let lock = if kind == DbKind::Postgres { " FOR UPDATE" } else { "" };
+    let active: Vec<(String,)> = sqlx::query_as(&query::render(kind, &format!(
+        "SELECT id FROM users WHERE role = ? AND banned = 0 AND suspended_until <= ? ORDER BY id{}",
+        lock
+    )))
+        .bind(SUPERUSER_ROLE)
+        .bind(timestamp_now())
+        .fetch_all(&mut *conn)
+        .await?;
+    Ok(active.len() == 1 && active[0].0 == id)
+}
+
+impl UserRepository for SqlRepository {
+    fn list<'a>(&'a self, filter: &'a UserFilter, page: i64, per_page: i64) -> RepoFuture<'a, (Vec<UserSummary>, i64)> {
+        Box::pin(async move {
+            let mut conditions: Vec<&str> = Vec::new();
+            let mut binds: Vec<String> = Vec::new();
+            if let Some(q) = filter.q.as_deref() {
+                // LOWER on both sides: LIKE is case-sensitive on Postgres
+                let pattern = format!("%{}%", q.to_lowercase().replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+                conditions.push("(LOWER(username) LIKE ? ESCAPE '\\' OR LOWER(COALESCE(email, '')) LIKE ? ESCAPE '\\')");
+                binds.push(pattern.clone());
+                binds.push(pattern);
+            }
+            if let Some(role) = filter.role.as_deref() {
+                conditions.push("role = ?");
+                binds.push(role.to_string());
+            }
+            match filter.status {
+                None => {}
+                Some(UserStatus::Active) => {
+                    conditions.push("banned = 0 AND suspended_until <= ?");
+                    binds.push(timestamp_now());
+                }
+                Some(UserStatus::Suspended) => {
+                    conditions.push("banned = 0 AND suspended_until > ?");
+                    binds.push(timestamp_now());
+                }
+                Some(UserStatus::Banned) => conditions.push("banned = 1"),
+            }
+            let where_clause = if conditions.is_empty() { String::new() } else { format!(" WHERE {}", conditions.join(" AND ")) };
+
+            let count_sql = self.sql(&format!("SELECT COUNT(*) FROM users{}", where_clause)).into_owned();
+            let mut count_query = sqlx::query_as::<_, (i64,)>(&count_sql);
+            for b in &binds {
+                count_query = count_query.bind(b);
+            }
+            let (total,) = count_query.fetch_one(&self.pool).await?;
+
+            let list_sql = self.sql(&format!(
+                "SELECT id, username, role, COALESCE(email, '') AS email, email_verified, totp_enabled,
+                    COALESCE(CAST(created_at AS TEXT), '') AS created_at, banned, ban_reason, suspended_until, suspension_reason
+                FROM users{} ORDER BY username LIMIT ? OFFSET ?",
+                where_clause
+            )).into_owned();
+            let mut list_query = sqlx::query_as::<_, UserSummary>(&list_sql);
+            for b in &binds {
+                list_query = list_query.bind(b);
+            }
+            let users = list_query
+                .bind(per_page)
+                .bind((page - 1).saturating_mul(per_page))
+                .fetch_all(&self.pool)
+                .await?;
+            Ok((users, total))
+        })
+    }
+
+    fn find<'a>(&'a self, id: &'a str) -> RepoFuture<'a, Option<User>> {
+        Box::pin(async move {
+            sqlx::query_as(&self.sql(&format!("SELECT {} FROM users WHERE id = ?", USER_COLUMNS)))
+                .bind(id)
+                .fetch_optional(&self.pool)
+                .await
+        })
+    }
+
+    fn role_permissions<'a>(&'a self, role: &'a str) -> RepoFuture<'a, Vec<String>> {
+        Box::pin(async move {
+            let rows: Vec<(String,)> = sqlx::query_as(&self.sql("SELECT permission_name FROM role_permissions WHERE role_name = ? ORDER BY permission_name"))
+                .bind(role)
+                .fetch_all(&self.pool)
+                .await?;
+            Ok(rows.into_iter().map(|(p,)| p).collect())
+        })
+    }
+
+    fn update<'a>(&'a self, actor: &'a Actor, id: &'a str, change: &'a UserChange) -> RepoFuture<'a, UserWrite> {
+        Box::pin(async move {
+            let (sql, binds): (&str, Vec<&str>) = match change {
+                UserChange::Role(role) => ("UPDATE users SET role = ? WHERE id = ?", vec![role]),
+                UserChange::Suspend { until, reason } => ("UPDATE users SET suspended_until = ?, suspension_reason = ? WHERE id = ?", vec![until, reason]),
+                UserChange::Unsuspend => ("UPDATE users SET suspended_until = '', suspension_reason = '' WHERE id = ?", vec![]),
+                UserChange::Ban { reason } => ("UPDATE users SET banned = 1, ban_reason = ? WHERE id = ?", vec![reason]),
+                UserChange::Unban => ("UPDATE users SET banned = 0, ban_reason = '' WHERE id = ?", vec![]),
+            };
+
+            let mut tx = self.pool.begin().await?;
+            let before = match audit::snapshot(&mut tx, self.kind, USER_SNAPSHOT, id).await? {
+                Some(b) => b,
+                None => return Ok(UserWrite::NotFound),
+            };
+            if change.removes_superuser() && is_last_superuser(&mut tx, self.kind, id).await? {
+                return Ok(UserWrite::LastSuperuser);
+            }
+            let sql = self.sql(sql);
+            let mut query = sqlx::query(&sql);
+            for b in binds {
+                query = query.bind(b);
+            }
+            query.bind(id).execute(&mut *tx).await?;
+
+            let after = audit::snapshot(&mut tx, self.kind, USER_SNAPSHOT, id).await?;
+            audit::record(&mut tx, self.kind, actor, change.action(), "user", id, Some(before), after).await?;
+            tx.commit().await?;
+            Ok(UserWrite::Done)
+        })
+    }
+
+    fn delete<'a>(&'a self, actor: &'a Actor, id: &'a str) -> RepoFuture<'a, UserWrite> {
+        Box::pin(async move {
+            let mut tx = self.pool.begin().await?;
+            let before = match audit::snapshot(&mut tx, self.kind, USER_SNAPSHOT, id).await? {
+                Some(b) => b,
+                None => return Ok(UserWrite::NotFound),
+            };
+            if is_last_superuser(&mut tx, self.kind, id).await? {
+                return Ok(UserWrite::LastSuperuser);
+            }
+            // Explicit deletes: SQLite only cascades with PRAGMA foreign_keys enabled
+            for table in USER_DATA_TABLES {
+                sqlx::query(&self.sql(&format!("DELETE FROM {} WHERE user_id = ?", table)))
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            sqlx::query(&self.sql("DELETE FROM users WHERE id = ?"))
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+            audit::record(&mut tx, self.kind, actor, "user.delete", "user", id, Some(before), None).await?;
+            tx.commit().await?;
+            Ok(UserWrite::Done)
+        })
+    }
+}
+
+impl GenreRepository for SqlRepository {
+    fn list(&self) -> RepoFuture<'_, Vec<Genre>> {
+        Box::pin(async move {
+            sqlx::query_as("SELECT id, name FROM genres ORDER BY name")
+                .fetch_all(&self.pool)
+                .await
+        })
+    }
+
+    fn for_series<'a>(&'a self, series_ids: &'a [String]) -> RepoFuture<'a, HashMap<String, Vec<Genre>>> {
+        Box::pin(async move {
+            let mut genres: HashMap<String, Vec<Genre>> = HashMap::new();
+            // One statement for any page or search result; only lists longer
+            // than GENRE_BATCH_SIZE take more
+            for chunk in series_ids.chunks(GENRE_BATCH_SIZE) {
+                let sql = format!(
+                    "SELECT ag.anime_id, g.id, g.name FROM anime_genres ag
+                     JOIN genres g ON g.id = ag.genre_id
+                     WHERE ag.anime_id IN ({})
+                     ORDER BY ag.anime_id, g.id",
+                    vec!["?"; chunk.len()].join(", ")
+                );
+                let sql = self.sql(&sql);
+                let mut query = sqlx::query_as::<_, (String, i64, String)>(&sql);
+                for id in chunk {
+                    query = query.bind(id);
+                }
+                for (anime_id, id, name) in query.fetch_all(&self.pool).await? {
+                    genres.entry(anime_id).or_default().push(Genre { id, name });
+                }
+            }
+            Ok(genres)
+        })
+    }
+}
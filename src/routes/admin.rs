@@ -1,16 +1,109 @@
 use actix_web::web;
-use crate::handlers::admin;
+use crate::auth::{CONTENT_WRITE, EPISODES_WRITE, METRICS_READ, USERS_READ, USERS_DELETE, USERS_MANAGE, ROLES_MANAGE, AUDIT_READ};
+use crate::handlers::{admin, audit};
+use crate::middleware::permission::RequirePermission;
 
 pub fn config(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/admin")
-            .route("/anime", web::post().to(admin::create_anime))
-            .route("/anime/{id}", web::put().to(admin::update_anime))
-            .route("/anime/{id}", web::delete().to(admin::delete_anime))
-            .route("/upload", web::post().to(admin::upload_episode))
-            .route("/episode", web::post().to(admin::create_episode_meta))
-            .route("/metrics", web::get().to(admin::get_system_metrics))
-            .route("/users", web::get().to(admin::get_users))
-            .route("/users/{id}", web::delete().to(admin::delete_user))
+            .service(
+                web::resource("/anime")
+                    .wrap(RequirePermission(CONTENT_WRITE))
+                    .route(web::post().to(admin::create_anime))
+            )
+            .service(
+                web::resource("/anime/{id}")
+                    .wrap(RequirePermission(CONTENT_WRITE))
+                    .route(web::put().to(admin::update_anime))
+                    .route(web::delete().to(admin::delete_anime))
+            )
+            .service(
+                web::resource("/upload")
+                    .wrap(RequirePermission(EPISODES_WRITE))
+                    .route(web::post().to(admin::upload_episode))
+            )
+            .service(
+                web::resource("/episode")
+                    .wrap(RequirePermission(EPISODES_WRITE))
+                    .route(web::post().to(admin::create_episode_meta))
+            )
+            .service(
+                web::resource("/metrics")
+                    .wrap(RequirePermission(METRICS_READ))
+                    .route(web::get().to(admin::get_system_metrics))
+            )
+            .service(
+                web::resource("/users")
+                    .wrap(RequirePermission(USERS_READ))
+                    .route(web::get().to(admin::get_users))
+            )
+            .service(
+                web::resource("/users/{id}")
+                    .wrap(RequirePermission(USERS_DELETE))
+                    .route(web::delete().to(admin::delete_user))
+            )
+            .service(
+                web::resource("/users/{id}/unlock")
+                    .wrap(RequirePermission(USERS_MANAGE))
+                    .route(web::post().to(admin::unlock_user))
+            )
+            .service(
+                web::resource("/users/{id}/role")
+                    .wrap(RequirePermission(ROLES_MANAGE))
+                    .route(web::put().to(admin::assign_role))
+            )
+            .service(
+                web::resource("/users/{id}/promote")
+                    .wrap(RequirePermission(ROLES_MANAGE))
+                    .route(web::post().to(admin::promote_user))
+            )
+            .service(
+                web::resource("/users/{id}/demote")
+                    .wrap(RequirePermission(ROLES_MANAGE))
+                    .route(web::post().to(admin::demote_user))
+            )
+            .service(
+                web::resource("/users/{id}/suspension")
+                    .wrap(RequirePermission(USERS_MANAGE))
+                    .route(web::post().to(admin::suspend_user))
+                    .route(web::delete().to(admin::unsuspend_user))
+            )
+            .service(
+                web::resource("/users/{id}/ban")
+                    .wrap(RequirePermission(USERS_MANAGE))
+                    .route(web::post().to(admin::ban_user))
+                    .route(web::delete().to(admin::unban_user))
+            )
+            .service(
+                web::resource("/invites")
+                    .wrap(RequirePermission(USERS_MANAGE))
+                    .route(web::post().to(admin::create_invite))
+            )
+            .service(
+                web::resource("/roles")
+                    .wrap(RequirePermission(ROLES_MANAGE))
+                    .route(web::get().to(admin::get_roles))
+                    .route(web::post().to(admin::create_role))
+            )
+            .service(
+                web::resource("/roles/{name}")
+                    .wrap(RequirePermission(ROLES_MANAGE))
+                    .route(web::delete().to(admin::delete_role))
+            )
+            .service(
+                web::resource("/audit")
+                    .wrap(RequirePermission(AUDIT_READ))
+                    .route(web::get().to(audit::get_audit_log))
+            )
+            .service(
+                web::resource("/audit/export")
+                    .wrap(RequirePermission(AUDIT_READ))
+                    .route(web::get().to(audit::export_audit_log))
+            )
+            .service(
+                web::resource("/permissions")
+                    .wrap(RequirePermission(ROLES_MANAGE))
+                    .route(web::get().to(admin::get_permissions))
+            )
     );
 }
@@ -1,12 +1,38 @@
 use actix_web::web;
-use crate::handlers::auth;
+use crate::handlers::{account, api_keys, auth, mfa, oidc, profile};
 
+// Registered directly on the /api scope: an empty-prefix scope would swallow
+// every /api path and hide the content and admin routes behind a 404.
 pub fn config(cfg: &mut web::ServiceConfig) {
-    cfg.service(
-        web::scope("")
-            .route("/register", web::post().to(auth::register))
-            .route("/login", web::post().to(auth::login))
-            .route("/refresh", web::post().to(auth::refresh))
-            .route("/logout", web::post().to(auth::logout))
-    );
+    cfg
+        .route("/register", web::post().to(auth::register))
+        .route("/login", web::post().to(auth::login))
+        .route("/login/2fa", web::post().to(mfa::login_2fa))
+        .route("/refresh", web::post().to(auth::refresh))
+        .route("/logout", web::post().to(auth::logout))
+        .route("/logout/all", web::post().to(auth::logout_all))
+        .route("/sessions", web::get().to(auth::get_sessions))
+        .route("/sessions/{id}", web::delete().to(auth::delete_session))
+        .route("/me", web::get().to(profile::get_me))
+        .route("/me", web::patch().to(profile::update_me))
+        .route("/me", web::delete().to(profile::delete_me))
+        .route("/me/password", web::post().to(profile::change_password))
+        .route("/password/forgot", web::post().to(account::forgot_password))
+        .route("/password/reset", web::post().to(account::reset_password))
+        .route("/verify-email", web::post().to(account::verify_email))
+        .route("/verify-email/resend", web::post().to(account::resend_verification))
+        .route("/2fa", web::get().to(mfa::status))
+        .route("/2fa/setup", web::post().to(mfa::setup))
+        .route("/2fa/enable", web::post().to(mfa::enable))
+        .route("/2fa/disable", web::post().to(mfa::disable))
+        .route("/2fa/recovery-codes", web::post().to(mfa::regenerate_recovery_codes))
+        .route("/keys", web::get().to(api_keys::list_keys))
+        .route("/keys", web::post().to(api_keys::create_key))
+        .route("/keys/{id}", web::delete().to(api_keys::revoke_key))
+        .route("/oauth/providers", web::get().to(oidc::list_providers))
+        .route("/oauth/identities", web::get().to(oidc::list_identities))
+        .route("/oauth/{provider}/authorize", web::get().to(oidc::authorize))
+        .route("/oauth/{provider}/callback", web::get().to(oidc::callback))
+        .route("/oauth/{provider}/link", web::post().to(oidc::link))
+        .route("/oauth/{provider}", web::delete().to(oidc::unlink));
 }
@@ -1,4 +1,5 @@
 use actix_web::web;
+use crate::handlers;
 
 pub mod auth;
 pub mod content;
@@ -9,5 +10,7 @@ pub fn config(cfg: &mut web::ServiceConfig) {
         .configure(auth::config)
         .configure(content::config)
         .configure(admin::config)
+        .route("/health", web::get().to(handlers::health::health))
     );
+    cfg.route("/.well-known/jwks.json", web::get().to(handlers::auth::jwks));
 }
@@ -0,0 +1,111 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use sha2::{Digest, Sha256};
+use crate::db::AppState;
+use uuid::Uuid;
+use crate::auth::Keyring;
+use crate::models::api_key::ApiKey;
+use crate::models::{AccountRestriction, TokenClaims};
+use crate::services::rbac::permissions_for_role;
+
+// Keys look like `bak_<prefix>_<secret>`. The prefix is stored in clear to find
+// the row; the whole key is hashed. Keys carry 128 random bits, so a plain
+// SHA-256 is enough and keeps the per-request check cheap (unlike bcrypt).
+const KEY_TAG: &str = "bak";
+// Fixed width so timestamps compare correctly as strings in SQL
+pub const TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H:%M:%SZ";
+// last_used_at is written at most this often per key
+const LAST_USED_RESOLUTION_SECONDS: i64 = 60;
+
+pub fn hash_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+// Returns (prefix, full key)
+pub fn generate_key() -> (String, String) {
+    let prefix = Uuid::new_v4().simple().to_string()[..8].to_string();
+    let secret = Uuid::new_v4().simple().to_string();
+    let key = format!("{}_{}_{}", KEY_TAG, prefix, secret);
+    (prefix, key)
+}
+
+pub fn parse_key(key: &str) -> Option<&str> {
+    let rest = key.strip_prefix(KEY_TAG)?.strip_prefix('_')?;
+    let (prefix, secret) = rest.split_once('_')?;
+    if prefix.is_empty() || secret.is_empty() {
+        return None;
+    }
+    Some(prefix)
+}
+
+pub fn timestamp_now() -> String {
+    Utc::now().format(TIMESTAMP_FORMAT).to_string()
+}
+
+pub fn timestamp_in(duration: Duration) -> String {
+    (Utc::now() + duration).format(TIMESTAMP_FORMAT).to_string()
+}
+
+pub fn split_scopes(scopes: &str) -> Vec<String> {
+    scopes.split(',').filter(|s| !s.is_empty()).map(str::to_string).collect()
+}
+
+// Resolves an X-API-Key header to the principal JwtAuth puts in the request
+// extensions. Permissions are the key's scopes still granted by the owner's
+// current role, so demoting the owner narrows every key they made.
+pub async fn authenticate(db: &AppState, keys: &Keyring, raw_key: &str) -> Option<TokenClaims> {
+    let prefix = parse_key(raw_key)?;
+    let key: ApiKey = sqlx::query_as(&db.sql("SELECT * FROM api_keys WHERE prefix = ?"))
+        .bind(prefix)
+        .fetch_optional(&db.pool)
+        .await
+        .unwrap_or(None)?;
+
+    if key.key_hash != hash_key(raw_key) {
+        return None;
+    }
+    let now = Utc::now();
+    let expires_at = NaiveDateTime::parse_from_str(&key.expires_at, TIMESTAMP_FORMAT).ok()?.and_utc();
+    if expires_at <= now {
+        return None;
+    }
+
+    let (role, banned, ban_reason, suspended_until, suspension_reason): (String, i64, String, String, String) = sqlx::query_as(&db.sql(
+        "SELECT role, banned, ban_reason, suspended_until, suspension_reason FROM users WHERE id = ?"
+    ))
+    .bind(&key.user_id)
+    .fetch_optional(&db.pool)
+    .await
+    .unwrap_or(None)?;
+    // Keys of banned or suspended owners stop working with the account
+    let now_str = now.format(TIMESTAMP_FORMAT).to_string();
+    if AccountRestriction::check(banned, &ban_reason, &suspended_until, &suspension_reason, &now_str).is_some() {
+        return None;
+    }
+    // Without the role's permissions the key cannot be scoped, so refuse it
+    let granted = permissions_for_role(db, &role).await.ok()?;
+    let permissions = split_scopes(&key.scopes).into_iter().filter(|s| granted.contains(s)).collect();
+
+    let cutoff = (now - Duration::seconds(LAST_USED_RESOLUTION_SECONDS)).format(TIMESTAMP_FORMAT).to_string();
+    let _ = sqlx::query(&db.sql("UPDATE api_keys SET last_used_at = ? WHERE id = ? AND last_used_at < ?"))
+        .bind(&now_str)
+        .bind(&key.id)
+        .bind(cutoff)
+        .execute(&db.pool)
+        .await;
+
+    Some(TokenClaims {
+        sub: key.user_id,
+        role,
+        permissions,
+        sid: None,
+        mfa: key.mfa != 0,
+        jti: String::new(),
+        api_key: Some(key.id),
+        iss: keys.settings.issuer.clone(),
+        aud: keys.settings.audience.clone(),
+        iat: now.timestamp() as usize,
+        iat_ms: now.timestamp_millis(),
+        nbf: now.timestamp() as usize,
+        exp: expires_at.timestamp() as usize,
+    })
+}
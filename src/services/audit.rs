@@ -0,0 +1,154 @@
+use actix_web::{HttpMessage, HttpRequest};
+use serde_json::{Map, Value};
+use sqlx::any::AnyRow;
+use sqlx::{AnyConnection, Column, Row, ValueRef};
+use uuid::Uuid;
+use crate::db::DbKind;
+use crate::handlers::auth::client_ip;
+use crate::models::audit::AuditEntry;
+use crate::models::TokenClaims;
+use crate::query;
+use crate::services::api_keys::timestamp_now;
+
+// Who performed an administrative action, taken from the request.
+pub struct Actor {
+    pub id: String,
+    pub ip: String,
+}
+
+impl Actor {
+    pub fn from_request(req: &HttpRequest) -> Self {
+        Actor {
+            id: req.extensions().get::<TokenClaims>().map(|c| c.sub.clone()).unwrap_or_default(),
+            ip: client_ip(req).unwrap_or_default(),
+        }
+    }
+}
+
+// The audit_log row for one action. Only the fields that differ between
+// `before` and `after` are kept.
+pub fn entry(actor: &Actor, action: &str, target_type: &str, target_id: &str, before: Option<Value>, after: Option<Value>) -> AuditEntry {
+    let (before, after) = diff(before, after);
+    AuditEntry {
+        id: Uuid::new_v4().to_string(),
+        actor_id: actor.id.clone(),
+        action: action.to_string(),
+        target_type: target_type.to_string(),
+        target_id: target_id.to_string(),
+        before_json: before.map(|v| v.to_string()).unwrap_or_default(),
+        after_json: after.map(|v| v.to_string()).unwrap_or_default(),
+        ip: actor.ip.clone(),
+        created_at: timestamp_now(),
+    }
+}
+
+// Writes one audit_log row. Call it with the transaction of the change itself,
+// so the entry and the change commit or roll back together.
+#[allow(clippy::too_many_arguments)]
+pub async fn record(
+    conn: &mut AnyConnection,
+    kind: DbKind,
+    actor: &Actor,
+    action: &str,
+    target_type: &str,
+    target_id: &str,
+    before: Option<Value>,
+    after: Option<Value>,
+) -> Result<(), sqlx::Error> {
+    let e = entry(actor, action, target_type, target_id, before, after);
+    sqlx::query(&query::render(kind,
+        "INSERT INTO audit_log (id, actor_id, action, target_type, target_id, before_json, after_json, ip, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)"
+    ))
+    .bind(&e.id)
+    .bind(&e.actor_id)
+    .bind(&e.action)
+    .bind(&e.target_type)
+    .bind(&e.target_id)
+    .bind(&e.before_json)
+    .bind(&e.after_json)
+    .bind(&e.ip)
+    .bind(&e.created_at)
+    .execute(conn)
+    .await
+    .map(|_| ())
+}
+
+// Reduces two object snapshots to the keys whose values changed.
+pub fn diff(before: Option<Value>, after: Option<Value>) -> (Option<Value>, Option<Value>) {
+    match (before, after) {
+        (Some(Value::Object(before)), Some(Value::Object(after))) => {
+            let mut old = Map::new();
+            let mut new = Map::new();
+            for (key, value) in &after {
+                if before.get(key) != Some(value) {
+                    old.insert(key.clone(), before.get(key).cloned().unwrap_or(Value::Null));
+                    new.insert(key.clone(), value.clone());
+                }
+            }
+            for (key, value) in &before {
+                if !after.contains_key(key) {
+                    old.insert(key.clone(), value.clone());
+                    new.insert(key.clone(), Value::Null);
+                }
+            }
+            (Some(Value::Object(old)), Some(Value::Object(new)))
+        }
+        other => other,
+    }
+}
+
+// Loads one row as a JSON object, e.g. the state of a target before a change.
+// List columns explicitly and CAST dates: sqlx::Any cannot decode DATETIME
+// from SQLite. NULLs become JSON null.
+pub async fn snapshot(conn: &mut AnyConnection, kind: DbKind, sql: &str, id: &str) -> Result<Option<Value>, sqlx::Error> {
+    let row = sqlx::query(&query::render(kind, sql)).bind(id).fetch_optional(conn).await?;
+    Ok(row.map(|r| row_to_json(&r)))
+}
+
+fn row_to_json(row: &AnyRow) -> Value {
+    let mut object = Map::new();
+    for column in row.columns() {
+        let i = column.ordinal();
+        let value = if row.try_get_raw(i).map(|v| v.is_null()).unwrap_or(true) {
+            Value::Null
+        } else if let Ok(v) = row.try_get::<i64, _>(i) {
+            Value::from(v)
+        } else if let Ok(v) = row.try_get::<f64, _>(i) {
+            Value::from(v)
+        } else if let Ok(v) = row.try_get::<String, _>(i) {
+            Value::from(v)
+        } else {
+            Value::Null
+        };
+        object.insert(column.name().to_string(), value);
+    }
+    Value::Object(object)
+}
+
+// RFC 4180 quoting. Cells starting with a formula character are prefixed with
+// a quote so spreadsheets do not evaluate them.
+pub fn csv_field(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+pub fn csv_row(entry: &AuditEntry) -> String {
+    [
+        &entry.created_at, &entry.actor_id, &entry.action, &entry.target_type,
+        &entry.target_id, &entry.before_json, &entry.after_json, &entry.ip,
+    ]
+    .iter()
+    .map(|v| csv_field(v))
+    .collect::<Vec<_>>()
+    .join(",")
+}
+
+pub const CSV_HEADER: &str = "timestamp,actor_id,action,target_type,target_id,before,after,ip";
@@ -0,0 +1,67 @@
+use std::time::Duration;
+use crate::services::redis::SessionStore;
+
+// Failed logins are counted per username and per client IP inside a sliding
+// window. Crossing a threshold locks that username/IP out for LOCK_SECONDS.
+const WINDOW_SECONDS: u64 = 15 * 60;
+const LOCK_SECONDS: u64 = 15 * 60;
+const MAX_USER_FAILURES: u64 = 5;
+const MAX_IP_FAILURES: u64 = 20;
+// Failures answered without an added delay
+const FREE_FAILURES: u64 = 2;
+const MAX_DELAY_MS: u64 = 4000;
+
+fn failures_key(kind: &str, value: &str) -> String {
+    format!("login_fail:{}:{}", kind, value)
+}
+
+fn lock_key(kind: &str, value: &str) -> String {
+    format!("login_lock:{}:{}", kind, value)
+}
+
+// Usernames are matched case-insensitively so "Admin" and "admin" share a counter
+fn normalize(username: &str) -> String {
+    username.trim().to_lowercase()
+}
+
+// Delay added to a failed login response: doubles with each failure after the free ones.
+pub fn failure_delay(failures: u64) -> Duration {
+    if failures <= FREE_FAILURES {
+        return Duration::ZERO;
+    }
+    let exponent = (failures - FREE_FAILURES - 1).min(16) as u32;
+    Duration::from_millis((250u64 << exponent).min(MAX_DELAY_MS))
+}
+
+// Seconds until the username or IP may try again, None when not locked.
+pub async fn locked_for(store: &dyn SessionStore, username: &str, ip: &str) -> Result<Option<u64>, redis::RedisError> {
+    let user = store.ttl(&lock_key("user", &normalize(username))).await?;
+    let ip = store.ttl(&lock_key("ip", ip)).await?;
+    Ok(user.max(ip))
+}
+
+// Counts a failed attempt, locks when a threshold is crossed and returns the
+// username's failure count in the current window.
+pub async fn record_failure(store: &dyn SessionStore, username: &str, ip: &str) -> Result<u64, redis::RedisError> {
+    let username = normalize(username);
+    let user_failures = store.incr_window(&failures_key("user", &username), WINDOW_SECONDS).await?;
+    if user_failures >= MAX_USER_FAILURES {
+        store.set_ex(&lock_key("user", &username), "1", LOCK_SECONDS).await?;
+    }
+    let ip_failures = store.incr_window(&failures_key("ip", ip), WINDOW_SECONDS).await?;
+    if ip_failures >= MAX_IP_FAILURES {
+        store.set_ex(&lock_key("ip", ip), "1", LOCK_SECONDS).await?;
+    }
+    Ok(user_failures)
+}
+
+// Called after a successful login. The IP counter is left to expire on its own.
+pub async fn clear_failures(store: &dyn SessionStore, username: &str) -> Result<(), redis::RedisError> {
+    store.delete(&[failures_key("user", &normalize(username))]).await
+}
+
+// Lifts a username lockout and resets its counter (admin action).
+pub async fn unlock(store: &dyn SessionStore, username: &str) -> Result<(), redis::RedisError> {
+    let username = normalize(username);
+    store.delete(&[failures_key("user", &username), lock_key("user", &username)]).await
+}
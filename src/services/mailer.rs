@@ -0,0 +1,118 @@
+use futures::future::BoxFuture;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use std::sync::Arc;
+use chrono::Utc;
+use uuid::Uuid;
+
+#[derive(Debug, Clone)]
+pub struct OutgoingEmail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+// Delivery backend for account emails. Picked at startup by `mailer`.
+pub trait Mailer: Send + Sync {
+    fn send<'a>(&'a self, email: &'a OutgoingEmail) -> BoxFuture<'a, Result<(), String>>;
+}
+
+// The [mail] section of Config (see config.rs for the keys and variables)
+#[derive(Debug, Clone)]
+pub struct MailSettings {
+    pub from: String, // MAIL_FROM, checked to parse as a mailbox
+    pub transport: MailTransport,
+}
+
+#[derive(Debug, Clone)]
+pub enum MailTransport {
+    File { dir: String },
+    Smtp(SmtpSettings),
+}
+
+#[derive(Debug, Clone)]
+pub struct SmtpSettings {
+    pub host: String,
+    pub port: Option<u16>, // The relay's default port when unset
+    pub credentials: Option<(String, String)>,
+    pub tls: SmtpTls,
+}
+
+// `None` is meant for local sinks such as MailHog
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SmtpTls {
+    StartTls,
+    Tls,
+    None,
+}
+
+// MAILER=smtp
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn new(smtp: &SmtpSettings, from: Mailbox) -> Result<Self, String> {
+        let host = smtp.host.as_str();
+        let mut builder = match smtp.tls {
+            SmtpTls::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host).map_err(|e| e.to_string())?,
+            SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(host).map_err(|e| e.to_string())?,
+            SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+        };
+        if let Some(port) = smtp.port {
+            builder = builder.port(port);
+        }
+        if let Some((user, pass)) = &smtp.credentials {
+            builder = builder.credentials(Credentials::new(user.clone(), pass.clone()));
+        }
+
+        Ok(SmtpMailer { transport: builder.build(), from })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send<'a>(&'a self, email: &'a OutgoingEmail) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let message = Message::builder()
+                .from(self.from.clone())
+                .to(email.to.parse::<Mailbox>().map_err(|e| e.to_string())?)
+                .subject(&email.subject)
+                .body(email.body.clone())
+                .map_err(|e| e.to_string())?;
+            self.transport.send(message).await.map_err(|e| e.to_string())?;
+            Ok(())
+        })
+    }
+}
+
+// MAILER=file (default): writes each email to MAIL_DIR (default "mail") and
+// logs it, so development and tests never need a mail server.
+pub struct FileMailer {
+    pub dir: String,
+}
+
+impl Mailer for FileMailer {
+    fn send<'a>(&'a self, email: &'a OutgoingEmail) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            tokio::fs::create_dir_all(&self.dir).await.map_err(|e| e.to_string())?;
+            let path = format!("{}/{}-{}.eml", self.dir, Utc::now().format("%Y%m%dT%H%M%S"), Uuid::new_v4());
+            let contents = format!("To: {}\nSubject: {}\n\n{}\n", email.to, email.subject, email.body);
+            tokio::fs::write(&path, contents).await.map_err(|e| e.to_string())?;
+            log::info!("Email to {} ({}) written to {}", email.to, email.subject, path);
+            Ok(())
+        })
+    }
+}
+
+// Fails only when the SMTP relay cannot be set up
+pub fn mailer(settings: &MailSettings) -> Result<Arc<dyn Mailer>, String> {
+    match &settings.transport {
+        MailTransport::Smtp(smtp) => {
+            let from = settings.from.parse().map_err(|e| format!("Invalid MAIL_FROM '{}': {}", settings.from, e))?;
+            Ok(Arc::new(SmtpMailer::new(smtp, from)?))
+        }
+        MailTransport::File { dir } => Ok(Arc::new(FileMailer { dir: dir.clone() })),
+    }
+}
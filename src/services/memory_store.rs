@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use chrono::Utc;
+use uuid::Uuid;
+use crate::models::auth::Session;
+use super::redis::{new_refresh_secret, parse_refresh_token, RefreshOutcome, SessionStore, StoreFuture};
+
+// In-memory stand-in for RedisStore, for handler tests. Expiry follows the
+// clock, so keys never outlive their TTL. Set `unavailable` to make every
+// call fail the way an unreachable Redis does.
+#[derive(Default)]
+pub struct MemorySessionStore {
+    data: Mutex<MemoryData>,
+    pub unavailable: AtomicBool,
+}
+
+#[derive(Default)]
+struct MemoryData {
+    values: HashMap<String, (String, Instant)>,
+    sessions: HashMap<String, StoredSession>,
+}
+
+struct StoredSession {
+    session: Session,
+    secret: String,
+    mfa: bool,
+    expires: Instant,
+}
+
+impl MemoryData {
+    fn purge(&mut self) {
+        let now = Instant::now();
+        self.values.retain(|_, (_, expires)| *expires > now);
+        self.sessions.retain(|_, s| s.expires > now);
+    }
+}
+
+fn expiry(ttl_seconds: u64) -> Instant {
+    Instant::now() + Duration::from_secs(ttl_seconds)
+}
+
+impl MemorySessionStore {
+    pub fn unavailable() -> Self {
+        let store = MemorySessionStore::default();
+        store.unavailable.store(true, Ordering::SeqCst);
+        store
+    }
+
+    fn data(&self) -> Result<MutexGuard<'_, MemoryData>, redis::RedisError> {
+        if self.unavailable.load(Ordering::SeqCst) {
+            return Err(redis::RedisError::from((redis::ErrorKind::IoError, "session store unavailable")));
+        }
+        let mut data = self.data.lock().unwrap_or_else(|e| e.into_inner());
+        data.purge();
+        Ok(data)
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    fn set_ex<'a>(&'a self, key: &'a str, value: &'a str, ttl_seconds: u64) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            self.data()?.values.insert(key.to_string(), (value.to_string(), expiry(ttl_seconds)));
+            Ok(())
+        })
+    }
+
+    fn get_many<'a>(&'a self, keys: &'a [String]) -> StoreFuture<'a, Vec<Option<String>>> {
+        Box::pin(async move {
+            let data = self.data()?;
+            Ok(keys.iter().map(|k| data.values.get(k).map(|(v, _)| v.clone())).collect())
+        })
+    }
+
+    fn get_del<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<String>> {
+        Box::pin(async move { Ok(self.data()?.values.remove(key).map(|(v, _)| v)) })
+    }
+
+    fn delete<'a>(&'a self, keys: &'a [String]) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            let mut data = self.data()?;
+            for key in keys {
+                data.values.remove(key);
+            }
+            Ok(())
+        })
+    }
+
+    fn ttl<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<u64>> {
+        Box::pin(async move {
+            let data = self.data()?;
+            // Rounded up, so a live key never reports zero
+            Ok(data.values.get(key).map(|(_, expires)| {
+                let left = expires.duration_since(Instant::now());
+                left.as_secs() + u64::from(left.subsec_nanos() > 0)
+            }))
+        })
+    }
+
+    fn incr_window<'a>(&'a self, key: &'a str, window_seconds: u64) -> StoreFuture<'a, u64> {
+        Box::pin(async move {
+            let mut data = self.data()?;
+            let entry = data.values.entry(key.to_string()).or_insert_with(|| ("0".to_string(), expiry(window_seconds)));
+            let count = entry.0.parse::<u64>().unwrap_or(0) + 1;
+            entry.0 = count.to_string();
+            Ok(count)
+        })
+    }
+
+    fn create_session<'a>(&'a self, user_id: &'a str, device_name: Option<&'a str>, ip: Option<&'a str>, mfa: bool, ttl_seconds: usize) -> StoreFuture<'a, (String, String)> {
+        Box::pin(async move {
+            let mut data = self.data()?;
+            let session_id = Uuid::new_v4().to_string();
+            let secret = new_refresh_secret();
+            let now = Utc::now().to_rfc3339();
+            let session = Session {
+                id: session_id.clone(),
+                user_id: user_id.to_string(),
+                device_name: device_name.filter(|d| !d.is_empty()).map(str::to_string),
+                ip: ip.filter(|i| !i.is_empty()).map(str::to_string),
+                created_at: now.clone(),
+                last_seen: now,
+                current: false,
+            };
+            let refresh_token = format!("{}.{}", session_id, secret);
+            data.sessions.insert(session_id.clone(), StoredSession { session, secret, mfa, expires: expiry(ttl_seconds as u64) });
+            Ok((session_id, refresh_token))
+        })
+    }
+
+    fn rotate_refresh_token<'a>(&'a self, token: &'a str, ip: Option<&'a str>, ttl_seconds: usize) -> StoreFuture<'a, RefreshOutcome> {
+        Box::pin(async move {
+            let (session_id, secret) = match parse_refresh_token(token) {
+                Some(parts) => parts,
+                None => return Ok(RefreshOutcome::Invalid),
+            };
+            let mut data = self.data()?;
+            let stored = match data.sessions.get_mut(session_id) {
+                Some(s) => s,
+                None => return Ok(RefreshOutcome::Invalid),
+            };
+            if stored.secret != secret {
+                let user_id = stored.session.user_id.clone();
+                data.sessions.remove(session_id);
+                return Ok(RefreshOutcome::Reused { user_id, session_id: session_id.to_string() });
+            }
+
+            stored.secret = new_refresh_secret();
+            stored.session.last_seen = Utc::now().to_rfc3339();
+            stored.session.ip = ip.filter(|i| !i.is_empty()).map(str::to_string);
+            stored.expires = expiry(ttl_seconds as u64);
+            Ok(RefreshOutcome::Rotated {
+                user_id: stored.session.user_id.clone(),
+                session_id: session_id.to_string(),
+                refresh_token: format!("{}.{}", session_id, stored.secret),
+                mfa: stored.mfa,
+            })
+        })
+    }
+
+    fn list_sessions<'a>(&'a self, user_id: &'a str, current_session: Option<&'a str>) -> StoreFuture<'a, Vec<Session>> {
+        Box::pin(async move {
+            let data = self.data()?;
+            let mut sessions: Vec<Session> = data.sessions.values()
+                .filter(|s| s.session.user_id == user_id)
+                .map(|s| Session { current: current_session == Some(s.session.id.as_str()), ..s.session.clone() })
+                .collect();
+            sessions.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+            Ok(sessions)
+        })
+    }
+
+    fn revoke_session<'a>(&'a self, user_id: &'a str, session_id: &'a str) -> StoreFuture<'a, bool> {
+        Box::pin(async move {
+            let mut data = self.data()?;
+            if data.sessions.get(session_id).is_none_or(|s| s.session.user_id != user_id) {
+                return Ok(false);
+            }
+            data.sessions.remove(session_id);
+            Ok(true)
+        })
+    }
+
+    fn revoke_sessions<'a>(&'a self, user_id: &'a str) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            self.data()?.sessions.retain(|_, s| s.session.user_id != user_id);
+            Ok(())
+        })
+    }
+}
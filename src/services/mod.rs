@@ -1,2 +1,11 @@
 pub mod video;
 pub mod redis;
+#[cfg(test)]
+pub mod memory_store;
+pub mod rbac;
+pub mod lockout;
+pub mod mailer;
+pub mod totp;
+pub mod api_keys;
+pub mod oidc;
+pub mod audit;
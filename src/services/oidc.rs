@@ -0,0 +1,382 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use crate::db::AppState;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use uuid::Uuid;
+use crate::validation::{validate_username, ValidationErrors};
+
+// Discovery documents and key sets are refetched after this long, or earlier
+// when an ID token names a key we do not know (provider key rotation).
+const METADATA_TTL: Duration = Duration::from_secs(3600);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+// Stored as the password of accounts created through a provider. It is not a
+// bcrypt hash, so password login never succeeds for them.
+pub const NO_PASSWORD: &str = "!";
+
+// One external provider, from [oidc.providers.<name>] in Config or
+// OIDC_PROVIDERS=google,keycloak with OIDC_<NAME>_ISSUER, _CLIENT_ID,
+// _CLIENT_SECRET, _REDIRECT_URI and optionally _SCOPES (default "openid email profile").
+#[derive(Debug, Clone)]
+pub struct OidcProvider {
+    pub name: String,
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub redirect_uri: String,
+    pub scopes: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Discovery {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+struct Metadata {
+    discovery: Discovery,
+    jwks: JwkSet,
+    fetched_at: Instant,
+}
+
+// What we keep from a verified ID token
+#[derive(Debug, Clone)]
+pub struct ExternalIdentity {
+    pub provider: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub preferred_username: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IdTokenClaims {
+    sub: String,
+    nonce: Option<String>,
+    email: Option<String>,
+    // Some providers send "true" as a string
+    email_verified: Option<Value>,
+    preferred_username: Option<String>,
+    name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+// Kept in Redis between /authorize and /callback, keyed by the `state` parameter
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FlowState {
+    pub provider: String,
+    pub verifier: String,
+    pub nonce: String,
+    // Set when a logged-in user is attaching the identity to their account
+    pub link_user_id: Option<String>,
+    // Finish in cookie session mode, see auth::CookieSettings
+    #[serde(default)]
+    pub use_cookies: bool,
+    // Also set in auth::OIDC_FLOW_COOKIE; a callback from another browser
+    // cannot present it
+    pub browser_secret: String,
+}
+
+pub struct OidcClient {
+    providers: HashMap<String, OidcProvider>,
+    http: reqwest::Client,
+    metadata: RwLock<HashMap<String, Metadata>>,
+}
+
+impl OidcClient {
+    pub fn new(providers: Vec<OidcProvider>) -> Self {
+        OidcClient {
+            providers: providers.into_iter().map(|p| (p.name.clone(), p)).collect(),
+            http: reqwest::Client::builder().timeout(HTTP_TIMEOUT).build().expect("HTTP client"),
+            metadata: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn provider(&self, name: &str) -> Option<&OidcProvider> {
+        self.providers.get(name)
+    }
+
+    pub fn provider_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.providers.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    async fn fetch_metadata(&self, provider: &OidcProvider) -> Result<Metadata, String> {
+        let url = format!("{}/.well-known/openid-configuration", provider.issuer.trim_end_matches('/'));
+        let discovery: Discovery = self.http.get(&url).send().await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| format!("Discovery request failed: {}", e))?
+            .json().await
+            .map_err(|e| format!("Invalid discovery document: {}", e))?;
+        // A document for another issuer means a misconfiguration or a spoofed endpoint
+        if discovery.issuer.trim_end_matches('/') != provider.issuer.trim_end_matches('/') {
+            return Err(format!("Discovery issuer '{}' does not match '{}'", discovery.issuer, provider.issuer));
+        }
+
+        let jwks: JwkSet = self.http.get(&discovery.jwks_uri).send().await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| format!("JWKS request failed: {}", e))?
+            .json().await
+            .map_err(|e| format!("Invalid JWKS: {}", e))?;
+
+        Ok(Metadata { discovery, jwks, fetched_at: Instant::now() })
+    }
+
+    async fn discovery(&self, provider: &OidcProvider, refresh: bool) -> Result<(Discovery, JwkSet), String> {
+        if !refresh {
+            if let Some(m) = self.metadata.read().await.get(&provider.name) {
+                if m.fetched_at.elapsed() < METADATA_TTL {
+                    return Ok((m.discovery.clone(), m.jwks.clone()));
+                }
+            }
+        }
+        let fresh = self.fetch_metadata(provider).await?;
+        let result = (fresh.discovery.clone(), fresh.jwks.clone());
+        self.metadata.write().await.insert(provider.name.clone(), fresh);
+        Ok(result)
+    }
+
+    pub async fn authorization_url(&self, provider: &OidcProvider, state: &str, nonce: &str, verifier: &str) -> Result<String, String> {
+        let (discovery, _) = self.discovery(provider, false).await?;
+        let separator = if discovery.authorization_endpoint.contains('?') { '&' } else { '?' };
+        Ok(format!(
+            "{}{}response_type=code&client_id={}&redirect_uri={}&scope={}&state={}&nonce={}&code_challenge={}&code_challenge_method=S256",
+            discovery.authorization_endpoint,
+            separator,
+            urlencoding::encode(&provider.client_id),
+            urlencoding::encode(&provider.redirect_uri),
+            urlencoding::encode(&provider.scopes),
+            urlencoding::encode(state),
+            urlencoding::encode(nonce),
+            pkce_challenge(verifier),
+        ))
+    }
+
+    // Trades the authorization code for an ID token and verifies it.
+    pub async fn exchange_code(&self, provider: &OidcProvider, code: &str, verifier: &str, nonce: &str) -> Result<ExternalIdentity, String> {
+        let (discovery, _) = self.discovery(provider, false).await?;
+        let response: TokenResponse = self.http.post(&discovery.token_endpoint)
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", provider.redirect_uri.as_str()),
+                ("client_id", provider.client_id.as_str()),
+                ("client_secret", provider.client_secret.as_str()),
+                ("code_verifier", verifier),
+            ])
+            .send().await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| format!("Token request failed: {}", e))?
+            .json().await
+            .map_err(|e| format!("Invalid token response: {}", e))?;
+
+        self.verify_id_token(provider, &response.id_token, nonce).await
+    }
+
+    pub async fn verify_id_token(&self, provider: &OidcProvider, id_token: &str, nonce: &str) -> Result<ExternalIdentity, String> {
+        let header = decode_header(id_token).map_err(|e| format!("Invalid ID token: {}", e))?;
+        // Symmetric algorithms would let anyone holding the client secret forge tokens
+        if !matches!(header.alg, Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512 | Algorithm::PS256
+            | Algorithm::PS384 | Algorithm::PS512 | Algorithm::ES256 | Algorithm::ES384 | Algorithm::EdDSA) {
+            return Err(format!("Unsupported ID token algorithm {:?}", header.alg));
+        }
+
+        let (_, mut jwks) = self.discovery(provider, false).await?;
+        let find_key = |jwks: &JwkSet| match &header.kid {
+            Some(kid) => jwks.find(kid).cloned(),
+            None if jwks.keys.len() == 1 => jwks.keys.first().cloned(),
+            None => None,
+        };
+        let jwk = match find_key(&jwks) {
+            Some(k) => k,
+            None => {
+                jwks = self.discovery(provider, true).await?.1;
+                find_key(&jwks).ok_or("ID token signed with an unknown key")?
+            }
+        };
+        let key = DecodingKey::from_jwk(&jwk).map_err(|e| format!("Unusable provider key: {}", e))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[provider.issuer.as_str(), provider.issuer.trim_end_matches('/')]);
+        validation.set_audience(&[&provider.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        validation.leeway = 60;
+
+        let claims = decode::<IdTokenClaims>(id_token, &key, &validation)
+            .map_err(|e| format!("ID token rejected: {}", e))?
+            .claims;
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err("ID token nonce mismatch".to_string());
+        }
+
+        let email_verified = matches!(claims.email_verified, Some(Value::Bool(true)))
+            || matches!(&claims.email_verified, Some(Value::String(s)) if s == "true");
+        Ok(ExternalIdentity {
+            provider: provider.name.clone(),
+            subject: claims.sub,
+            email: claims.email.map(|e| e.trim().to_lowercase()),
+            email_verified,
+            preferred_username: claims.preferred_username.or(claims.name),
+        })
+    }
+}
+
+// 256 random bits, hex encoded. Used for the nonce and the PKCE verifier.
+pub fn random_secret() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+// RFC 7636 S256 code challenge
+pub fn pkce_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LinkError {
+    // The identity already belongs to another account
+    LinkedElsewhere,
+    // The account already has a different identity from this provider
+    ProviderAlreadyLinked,
+    // No linked account, and a local account already uses the email. It must
+    // log in and link the provider itself; matching on email alone would let
+    // a provider that asserts arbitrary addresses take the account over.
+    EmailInUse,
+    SignupClosed,
+    Database(String),
+}
+
+// Finds the local user for an external identity, linking or creating one.
+// `link_to` is set when a logged-in user started the flow. Returns the user id.
+pub async fn resolve_identity(db: &AppState, identity: &ExternalIdentity, link_to: Option<&str>, allow_signup: bool) -> Result<String, LinkError> {
+    let db_err = |e: sqlx::Error| LinkError::Database(e.to_string());
+
+    let linked: Option<(String,)> = sqlx::query_as(&db.sql("SELECT user_id FROM user_identities WHERE provider = ? AND subject = ?"))
+        .bind(&identity.provider)
+        .bind(&identity.subject)
+        .fetch_optional(&db.pool)
+        .await
+        .map_err(db_err)?;
+
+    if let Some((user_id,)) = linked {
+        return match link_to {
+            Some(other) if other != user_id => Err(LinkError::LinkedElsewhere),
+            _ => Ok(user_id),
+        };
+    }
+
+    if let Some(user_id) = link_to {
+        let existing: (i64,) = sqlx::query_as(&db.sql("SELECT COUNT(*) FROM user_identities WHERE user_id = ? AND provider = ?"))
+            .bind(user_id)
+            .bind(&identity.provider)
+            .fetch_one(&db.pool)
+            .await
+            .map_err(db_err)?;
+        if existing.0 > 0 {
+            return Err(LinkError::ProviderAlreadyLinked);
+        }
+        insert_identity(db, identity, user_id).await?;
+        return Ok(user_id.to_string());
+    }
+
+    let verified_email = identity.email.clone().filter(|_| identity.email_verified);
+    if let Some(email) = &identity.email {
+        let taken: (i64,) = sqlx::query_as(&db.sql("SELECT COUNT(*) FROM users WHERE LOWER(email) = ?"))
+            .bind(email)
+            .fetch_one(&db.pool)
+            .await
+            .map_err(db_err)?;
+        if taken.0 > 0 {
+            return Err(LinkError::EmailInUse);
+        }
+    }
+    if !allow_signup {
+        return Err(LinkError::SignupClosed);
+    }
+
+    let username = available_username(db, identity).await?;
+    let user_id = Uuid::new_v4().to_string();
+    let mut tx = db.pool.begin().await.map_err(db_err)?;
+    sqlx::query(&db.sql("INSERT INTO users (id, username, password, role, email, email_verified) VALUES (?, ?, ?, 'user', ?, ?)"))
+        .bind(&user_id)
+        .bind(&username)
+        .bind(NO_PASSWORD)
+        .bind(&verified_email)
+        .bind(verified_email.is_some() as i64)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_err)?;
+    sqlx::query(&db.sql("INSERT INTO user_identities (provider, subject, user_id, email) VALUES (?, ?, ?, ?)"))
+        .bind(&identity.provider)
+        .bind(&identity.subject)
+        .bind(&user_id)
+        .bind(identity.email.as_deref().unwrap_or(""))
+        .execute(&mut *tx)
+        .await
+        .map_err(db_err)?;
+    tx.commit().await.map_err(db_err)?;
+
+    Ok(user_id)
+}
+
+async fn insert_identity(db: &AppState, identity: &ExternalIdentity, user_id: &str) -> Result<(), LinkError> {
+    sqlx::query(&db.sql("INSERT INTO user_identities (provider, subject, user_id, email) VALUES (?, ?, ?, ?)"))
+        .bind(&identity.provider)
+        .bind(&identity.subject)
+        .bind(user_id)
+        .bind(identity.email.as_deref().unwrap_or(""))
+        .execute(&db.pool)
+        .await
+        .map(|_| ())
+        .map_err(|e| LinkError::Database(e.to_string()))
+}
+
+// Derives a free username that passes registration rules from the provider's
+// preferred username or email, adding a random suffix when needed.
+async fn available_username(db: &AppState, identity: &ExternalIdentity) -> Result<String, LinkError> {
+    let hint = identity.preferred_username.clone()
+        .or_else(|| identity.email.as_ref().and_then(|e| e.split('@').next().map(str::to_string)))
+        .unwrap_or_default();
+    let mut base: String = hint.to_lowercase().chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') { c } else { '_' })
+        .skip_while(|c| !c.is_ascii_alphanumeric())
+        .take(24)
+        .collect();
+    if base.len() < 3 {
+        base = "viewer".to_string();
+    }
+
+    for attempt in 0..5 {
+        let candidate = if attempt == 0 {
+            base.clone()
+        } else {
+            format!("{}-{}", base, &Uuid::new_v4().simple().to_string()[..4])
+        };
+        let mut errors = ValidationErrors::default();
+        validate_username(&candidate, &mut errors);
+        if !errors.is_empty() {
+            continue;
+        }
+        let taken: (i64,) = sqlx::query_as(&db.sql("SELECT COUNT(*) FROM users WHERE LOWER(username) = LOWER(?)"))
+            .bind(&candidate)
+            .fetch_one(&db.pool)
+            .await
+            .map_err(|e| LinkError::Database(e.to_string()))?;
+        if taken.0 == 0 {
+            return Ok(candidate);
+        }
+    }
+    Ok(format!("viewer-{}", &Uuid::new_v4().simple().to_string()[..12]))
+}
@@ -0,0 +1,8 @@
+use crate::db::AppState;
+
+// Permissions granted to a role, embedded in the JWT at login/refresh.
+pub async fn permissions_for_role(db: &AppState, role: &str) -> Result<Vec<String>, sqlx::Error> {
+    db.repos.users.role_permissions(role).await
+}
+
+pub const SUPERUSER_ROLE: &str = "superuser";
@@ -1,27 +1,359 @@
+use futures::future::BoxFuture;
+use redis::aio::MultiplexedConnection;
 use redis::AsyncCommands;
-use std::env;
+use std::collections::HashMap;
+use chrono::Utc;
+use sha1::{Digest, Sha1};
+use uuid::Uuid;
+use crate::config::RedisConfig;
+use crate::models::auth::Session;
+use crate::models::TokenClaims;
 
-pub type RedisPool = redis::Client;
+pub type StoreFuture<'a, T> = BoxFuture<'a, Result<T, redis::RedisError>>;
 
-pub async fn init_redis() -> RedisPool {
-    let redis_url = env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_string());
-    redis::Client::open(redis_url).expect("Invalid Redis URL")
+// Short-lived auth state: sessions, access token denylists, invites,
+// one-time tokens and login failure counters. RedisStore in production,
+// memory_store::MemorySessionStore in tests. Handlers take it as
+// `web::Data<dyn SessionStore>`; the free functions below build on the
+// plain key operations.
+pub trait SessionStore: Send + Sync {
+    fn set_ex<'a>(&'a self, key: &'a str, value: &'a str, ttl_seconds: u64) -> StoreFuture<'a, ()>;
+    // One entry per key, None where the key does not exist
+    fn get_many<'a>(&'a self, keys: &'a [String]) -> StoreFuture<'a, Vec<Option<String>>>;
+    fn get_del<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<String>>;
+    fn delete<'a>(&'a self, keys: &'a [String]) -> StoreFuture<'a, ()>;
+    // Seconds until the key expires, None when it does not exist
+    fn ttl<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<u64>>;
+    // Increments a counter; the first increment starts its `window_seconds` expiry
+    fn incr_window<'a>(&'a self, key: &'a str, window_seconds: u64) -> StoreFuture<'a, u64>;
+
+    // Starts a new session and returns (session_id, refresh_token).
+    // `mfa` marks sessions opened with a second factor; refreshed tokens inherit it.
+    fn create_session<'a>(&'a self, user_id: &'a str, device_name: Option<&'a str>, ip: Option<&'a str>, mfa: bool, ttl_seconds: usize) -> StoreFuture<'a, (String, String)>;
+    // Exchanges a refresh token for a new one in the same session (rotation)
+    fn rotate_refresh_token<'a>(&'a self, token: &'a str, ip: Option<&'a str>, ttl_seconds: usize) -> StoreFuture<'a, RefreshOutcome>;
+    // Most recently used first
+    fn list_sessions<'a>(&'a self, user_id: &'a str, current_session: Option<&'a str>) -> StoreFuture<'a, Vec<Session>>;
+    // Revokes one session of the given user. Returns false if it does not belong to them.
+    fn revoke_session<'a>(&'a self, user_id: &'a str, session_id: &'a str) -> StoreFuture<'a, bool>;
+    fn revoke_sessions<'a>(&'a self, user_id: &'a str) -> StoreFuture<'a, ()>;
+}
+
+pub struct RedisStore {
+    client: redis::Client,
+}
+
+// The URL was checked by Config::load, so this only fails on a config built by hand
+pub async fn init_redis(config: &RedisConfig) -> redis::Client {
+    redis::Client::open(config.url.as_str()).expect("Invalid Redis URL")
+}
+
+// Sessions live in `session:{id}` hashes (user_id, device_name, ip, created_at,
+// last_seen, mfa, current). `current` is the only refresh secret accepted for the
+// session; presenting any other secret means a rotated token was replayed.
+fn session_key(session_id: &str) -> String {
+    format!("session:{}", session_id)
+}
+
+fn user_sessions_key(user_id: &str) -> String {
+    format!("user_sessions:{}", user_id)
+}
+
+// Splits "<session_id>.<secret>" into its parts.
+pub fn parse_refresh_token(token: &str) -> Option<(&str, &str)> {
+    let (session_id, secret) = token.split_once('.')?;
+    if session_id.is_empty() || secret.is_empty() {
+        return None;
+    }
+    Some((session_id, secret))
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RefreshOutcome {
+    Rotated { user_id: String, session_id: String, refresh_token: String, mfa: bool },
+    // A previously rotated token was presented; the whole session was revoked
+    Reused { user_id: String, session_id: String },
+    Invalid,
+}
+
+// A fresh refresh secret, sent as "<session_id>.<secret>"
+pub fn new_refresh_secret() -> String {
+    Uuid::new_v4().simple().to_string()
+}
+
+impl RedisStore {
+    pub fn new(client: redis::Client) -> Self {
+        RedisStore { client }
+    }
+
+    async fn connection(&self) -> Result<MultiplexedConnection, redis::RedisError> {
+        self.client.get_multiplexed_async_connection().await
+    }
+}
+
+impl SessionStore for RedisStore {
+    fn set_ex<'a>(&'a self, key: &'a str, value: &'a str, ttl_seconds: u64) -> StoreFuture<'a, ()> {
+        Box::pin(async move { self.connection().await?.set_ex(key, value, ttl_seconds).await })
+    }
+
+    fn get_many<'a>(&'a self, keys: &'a [String]) -> StoreFuture<'a, Vec<Option<String>>> {
+        Box::pin(async move {
+            if keys.is_empty() {
+                return Ok(vec![]);
+            }
+            // MGET always answers with a list, even for a single key
+            redis::cmd("MGET").arg(keys).query_async(&mut self.connection().await?).await
+        })
+    }
+
+    fn get_del<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<String>> {
+        Box::pin(async move { self.connection().await?.get_del(key).await })
+    }
+
+    fn delete<'a>(&'a self, keys: &'a [String]) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            if keys.is_empty() {
+                return Ok(());
+            }
+            self.connection().await?.del(keys).await
+        })
+    }
+
+    fn ttl<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<u64>> {
+        Box::pin(async move {
+            // Negative when the key does not exist or never expires
+            let ttl: i64 = self.connection().await?.ttl(key).await?;
+            Ok((ttl > 0).then_some(ttl as u64))
+        })
+    }
+
+    fn incr_window<'a>(&'a self, key: &'a str, window_seconds: u64) -> StoreFuture<'a, u64> {
+        Box::pin(async move {
+            // One script, so a counter is never left without its expiry
+            let script = redis::Script::new(r#"
+                local current = redis.call("INCR", KEYS[1])
+                if tonumber(current) == 1 then
+                    redis.call("EXPIRE", KEYS[1], ARGV[1])
+                end
+                return current
+            "#);
+            script.key(key).arg(window_seconds).invoke_async(&mut self.connection().await?).await
+        })
+    }
+
+    fn create_session<'a>(&'a self, user_id: &'a str, device_name: Option<&'a str>, ip: Option<&'a str>, mfa: bool, ttl_seconds: usize) -> StoreFuture<'a, (String, String)> {
+        Box::pin(async move {
+            let mut con = self.connection().await?;
+            let session_id = Uuid::new_v4().to_string();
+            let secret = new_refresh_secret();
+            let now = Utc::now().to_rfc3339();
+            let key = session_key(&session_id);
+
+            redis::pipe()
+                .atomic()
+                .hset_multiple(&key, &[
+                    ("user_id", user_id),
+                    ("device_name", device_name.unwrap_or("")),
+                    ("ip", ip.unwrap_or("")),
+                    ("created_at", &now),
+                    ("last_seen", &now),
+                    ("mfa", if mfa { "1" } else { "0" }),
+                    ("current", &secret),
+                ])
+                .expire(&key, ttl_seconds as i64)
+                .sadd(user_sessions_key(user_id), &session_id)
+                .query_async::<()>(&mut con)
+                .await?;
+
+            Ok((session_id.clone(), format!("{}.{}", session_id, secret)))
+        })
+    }
+
+    fn rotate_refresh_token<'a>(&'a self, token: &'a str, ip: Option<&'a str>, ttl_seconds: usize) -> StoreFuture<'a, RefreshOutcome> {
+        Box::pin(async move {
+            let (session_id, secret) = match parse_refresh_token(token) {
+                Some(parts) => parts,
+                None => return Ok(RefreshOutcome::Invalid),
+            };
+
+            let mut con = self.connection().await?;
+            let new_secret = new_refresh_secret();
+
+            // Compare-and-swap in one script so two concurrent refreshes cannot both win
+            let script = redis::Script::new(r#"
+                local s = redis.call("HMGET", KEYS[1], "current", "user_id", "mfa")
+                if not s[1] then
+                    return {0, "", "0"}
+                end
+                if s[1] ~= ARGV[1] then
+                    redis.call("DEL", KEYS[1])
+                    redis.call("SREM", "user_sessions:" .. s[2], ARGV[6])
+                    return {-1, s[2], "0"}
+                end
+                redis.call("HSET", KEYS[1], "current", ARGV[2], "last_seen", ARGV[3], "ip", ARGV[4])
+                redis.call("EXPIRE", KEYS[1], ARGV[5])
+                return {1, s[2], s[3] or "0"}
+            "#);
+
+            let (status, user_id, mfa): (i64, String, String) = script
+                .key(session_key(session_id))
+                .arg(secret)
+                .arg(&new_secret)
+                .arg(Utc::now().to_rfc3339())
+                .arg(ip.unwrap_or(""))
+                .arg(ttl_seconds)
+                .arg(session_id)
+                .invoke_async(&mut con)
+                .await?;
+
+            Ok(match status {
+                1 => RefreshOutcome::Rotated {
+                    user_id,
+                    session_id: session_id.to_string(),
+                    refresh_token: format!("{}.{}", session_id, new_secret),
+                    mfa: mfa == "1",
+                },
+                -1 => RefreshOutcome::Reused { user_id, session_id: session_id.to_string() },
+                _ => RefreshOutcome::Invalid,
+            })
+        })
+    }
+
+    fn list_sessions<'a>(&'a self, user_id: &'a str, current_session: Option<&'a str>) -> StoreFuture<'a, Vec<Session>> {
+        Box::pin(async move {
+            let mut con = self.connection().await?;
+            let ids: Vec<String> = con.smembers(user_sessions_key(user_id)).await?;
+
+            let mut sessions = vec![];
+            for id in ids {
+                let fields: HashMap<String, String> = con.hgetall(session_key(&id)).await?;
+                if fields.is_empty() {
+                    // Expired session, drop the stale index entry
+                    let _: () = con.srem(user_sessions_key(user_id), &id).await?;
+                    continue;
+                }
+                let non_empty = |name: &str| fields.get(name).filter(|v| !v.is_empty()).cloned();
+                sessions.push(Session {
+                    current: current_session == Some(id.as_str()),
+                    user_id: user_id.to_string(),
+                    device_name: non_empty("device_name"),
+                    ip: non_empty("ip"),
+                    created_at: fields.get("created_at").cloned().unwrap_or_default(),
+                    last_seen: fields.get("last_seen").cloned().unwrap_or_default(),
+                    id,
+                });
+            }
+            sessions.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+            Ok(sessions)
+        })
+    }
+
+    fn revoke_session<'a>(&'a self, user_id: &'a str, session_id: &'a str) -> StoreFuture<'a, bool> {
+        Box::pin(async move {
+            let mut con = self.connection().await?;
+            let owner: Option<String> = con.hget(session_key(session_id), "user_id").await?;
+            if owner.as_deref() != Some(user_id) {
+                return Ok(false);
+            }
+            redis::pipe()
+                .atomic()
+                .del(session_key(session_id))
+                .srem(user_sessions_key(user_id), session_id)
+                .query_async::<()>(&mut con)
+                .await?;
+            Ok(true)
+        })
+    }
+
+    fn revoke_sessions<'a>(&'a self, user_id: &'a str) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            let mut con = self.connection().await?;
+            let ids: Vec<String> = con.smembers(user_sessions_key(user_id)).await?;
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            for id in &ids {
+                pipe.del(session_key(id));
+            }
+            pipe.del(user_sessions_key(user_id));
+            pipe.query_async::<()>(&mut con).await
+        })
+    }
+}
+
+// Access tokens are stateless, so revocation is a denylist checked by JwtAuth:
+// `revoked_jti:{jti}` for a single token, `revoked_sid:{sid}` for every token
+// of one session and `revoked_before:{user_id}` for every token of the user
+// issued up to that millisecond. Entries expire once the tokens they cover
+// would have expired anyway.
+pub async fn revoke_access_token(store: &dyn SessionStore, claims: &TokenClaims) -> Result<(), redis::RedisError> {
+    let remaining = claims.exp as i64 - Utc::now().timestamp();
+    if remaining <= 0 || claims.jti.is_empty() {
+        return Ok(());
+    }
+    store.set_ex(&format!("revoked_jti:{}", claims.jti), "1", remaining as u64).await
+}
+
+// For a session ended from another device, whose access token we never see
+pub async fn revoke_session_tokens(store: &dyn SessionStore, session_id: &str, access_ttl_seconds: i64) -> Result<(), redis::RedisError> {
+    store.set_ex(&format!("revoked_sid:{}", session_id), "1", access_ttl_seconds as u64).await
+}
+
+pub async fn revoke_all_access_tokens(store: &dyn SessionStore, user_id: &str, access_ttl_seconds: i64) -> Result<(), redis::RedisError> {
+    let key = format!("revoked_before:{}", user_id);
+    store.set_ex(&key, &Utc::now().timestamp_millis().to_string(), access_ttl_seconds as u64).await
+}
+
+pub async fn is_access_token_revoked(store: &dyn SessionStore, claims: &TokenClaims) -> Result<bool, redis::RedisError> {
+    let mut keys = vec![format!("revoked_jti:{}", claims.jti), format!("revoked_before:{}", claims.sub)];
+    if let Some(sid) = &claims.sid {
+        keys.push(format!("revoked_sid:{}", sid));
+    }
+    let mut values = store.get_many(&keys).await?.into_iter();
+
+    let jti = values.next().flatten();
+    let revoked_before = values.next().flatten().and_then(|ts| ts.parse::<i64>().ok());
+    let sid = values.next().flatten();
+    Ok(jti.is_some() || revoked_before.is_some_and(|ts| claims.iat_ms <= ts) || sid.is_some())
+}
+
+// Ends every session and invalidates every outstanding access token of the user.
+pub async fn revoke_user(store: &dyn SessionStore, user_id: &str, access_ttl_seconds: i64) -> Result<(), redis::RedisError> {
+    store.revoke_sessions(user_id).await?;
+    revoke_all_access_tokens(store, user_id, access_ttl_seconds).await
+}
+
+// Single-use invite codes for REGISTRATION_MODE=invite, `invite:{code}` -> creator id.
+pub async fn create_invite(store: &dyn SessionStore, created_by: &str, ttl_seconds: u64) -> Result<String, redis::RedisError> {
+    let code = Uuid::new_v4().simple().to_string();
+    store.set_ex(&format!("invite:{}", code), created_by, ttl_seconds).await?;
+    Ok(code)
+}
+
+// Atomically claims an invite; false if it does not exist or was already used.
+pub async fn consume_invite(store: &dyn SessionStore, code: &str) -> Result<bool, redis::RedisError> {
+    let creator = store.get_del(&format!("invite:{}", code)).await?;
+    Ok(creator.is_some())
+}
+
+// Single-use tokens for emailed links (purpose "reset" or "verify") and for
+// the second login step (purpose "mfa"). Only a
+// hash is stored, so a Redis dump does not contain usable links.
+fn one_time_key(purpose: &str, token: &str) -> String {
+    format!("{}:{:x}", purpose, Sha1::digest(token.as_bytes()))
 }
 
-pub async fn store_refresh_token(client: &RedisPool, user_id: &str, token: &str, ttl_seconds: usize) -> Result<(), redis::RedisError> {
-    let mut con = client.get_multiplexed_async_connection().await?;
-    let key = format!("refresh:{}", user_id);
-    con.set_ex(key, token, ttl_seconds as u64).await
+pub async fn create_one_time_token(store: &dyn SessionStore, purpose: &str, user_id: &str, ttl_seconds: u64) -> Result<String, redis::RedisError> {
+    let token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    store.set_ex(&one_time_key(purpose, &token), user_id, ttl_seconds).await?;
+    Ok(token)
 }
 
-pub async fn get_refresh_token(client: &RedisPool, user_id: &str) -> Result<String, redis::RedisError> {
-    let mut con = client.get_multiplexed_async_connection().await?;
-    let key = format!("refresh:{}", user_id);
-    con.get(key).await
+// Reads a token without using it up.
+pub async fn peek_one_time_token(store: &dyn SessionStore, purpose: &str, token: &str) -> Result<Option<String>, redis::RedisError> {
+    Ok(store.get_many(&[one_time_key(purpose, token)]).await?.pop().flatten())
 }
 
-pub async fn revoke_token(client: &RedisPool, user_id: &str) -> Result<(), redis::RedisError> {
-    let mut con = client.get_multiplexed_async_connection().await?;
-    let key = format!("refresh:{}", user_id);
-    con.del(key).await
+// Returns the user id and deletes the token in one step.
+pub async fn consume_one_time_token(store: &dyn SessionStore, purpose: &str, token: &str) -> Result<Option<String>, redis::RedisError> {
+    store.get_del(&one_time_key(purpose, token)).await
 }
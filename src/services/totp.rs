@@ -0,0 +1,118 @@
+use bcrypt::{hash, verify, DEFAULT_COST};
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha1::Sha1;
+use crate::db::AppState;
+use uuid::Uuid;
+
+// RFC 6238 with the parameters every authenticator app supports:
+// HMAC-SHA1, 6 digits, 30 second steps.
+const STEP_SECONDS: u64 = 30;
+const DIGITS: u32 = 6;
+// Codes from the previous and next step are accepted to absorb clock drift
+const ALLOWED_DRIFT_STEPS: u64 = 1;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+pub fn generate_secret() -> String {
+    let bytes: [u8; 20] = rand::thread_rng().gen();
+    BASE32_NOPAD.encode(&bytes)
+}
+
+// RFC 4226 dynamic truncation of HMAC(secret, counter)
+pub fn code_at(secret: &[u8], step: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[19] & 0x0f) as usize;
+    let value = u32::from_be_bytes([digest[offset] & 0x7f, digest[offset + 1], digest[offset + 2], digest[offset + 3]]);
+    value % 10u32.pow(DIGITS)
+}
+
+// Returns the time step the code belongs to, so callers can refuse to accept
+// the same code twice.
+pub fn verify_code(secret_b32: &str, code: &str, unix_time: u64) -> Option<u64> {
+    let secret = BASE32_NOPAD.decode(secret_b32.as_bytes()).ok()?;
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+
+    let current = unix_time / STEP_SECONDS;
+    (current.saturating_sub(ALLOWED_DRIFT_STEPS)..=current + ALLOWED_DRIFT_STEPS)
+        .find(|&step| code_at(&secret, step) == code)
+}
+
+// otpauth:// URI that authenticator apps import from a QR code
+pub fn provisioning_uri(issuer: &str, account: &str, secret_b32: &str) -> String {
+    let issuer = urlencoding::encode(issuer);
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        issuer, urlencoding::encode(account), secret_b32, issuer, DIGITS, STEP_SECONDS
+    )
+}
+
+// Replaces the user's recovery codes and returns the new plaintext codes.
+// Only bcrypt hashes are stored.
+pub async fn replace_recovery_codes(db: &AppState, user_id: &str) -> Result<Vec<String>, String> {
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let raw = Uuid::new_v4().simple().to_string();
+            format!("{}-{}", &raw[..5], &raw[5..10])
+        })
+        .collect();
+
+    let mut tx = db.pool.begin().await.map_err(|e| e.to_string())?;
+    sqlx::query(&db.sql("DELETE FROM recovery_codes WHERE user_id = ?"))
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+    for code in &codes {
+        let code_hash = hash(code, DEFAULT_COST).map_err(|e| e.to_string())?;
+        sqlx::query(&db.sql("INSERT INTO recovery_codes (id, user_id, code_hash) VALUES (?, ?, ?)"))
+            .bind(Uuid::new_v4().to_string())
+            .bind(user_id)
+            .bind(code_hash)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    tx.commit().await.map_err(|e| e.to_string())?;
+
+    Ok(codes)
+}
+
+// Burns the matching recovery code. False when none matches.
+pub async fn use_recovery_code(db: &AppState, user_id: &str, code: &str) -> Result<bool, String> {
+    let rows: Vec<(String, String)> = sqlx::query_as(&db.sql("SELECT id, code_hash FROM recovery_codes WHERE user_id = ?"))
+        .bind(user_id)
+        .fetch_all(&db.pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let code = code.trim().to_lowercase();
+    let matched = rows.into_iter().find(|(_, code_hash)| verify(&code, code_hash).unwrap_or(false));
+    let id = match matched {
+        Some((id, _)) => id,
+        None => return Ok(false),
+    };
+
+    // The DELETE decides who wins if the same code is submitted twice at once
+    let result = sqlx::query(&db.sql("DELETE FROM recovery_codes WHERE id = ?"))
+        .bind(id)
+        .execute(&db.pool)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(result.rows_affected() == 1)
+}
+
+pub async fn remaining_recovery_codes(db: &AppState, user_id: &str) -> i64 {
+    sqlx::query_as::<_, (i64,)>(&db.sql("SELECT COUNT(*) FROM recovery_codes WHERE user_id = ?"))
+        .bind(user_id)
+        .fetch_one(&db.pool)
+        .await
+        .map(|r| r.0)
+        .unwrap_or(0)
+}
@@ -1,11 +1,16 @@
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
 use futures::StreamExt;
+use std::path::{Path, PathBuf};
 use uuid::Uuid;
 use actix_multipart::Multipart;
+use crate::error::AppError;
 
-pub async fn save_video(mut payload: Multipart) -> Result<String, actix_web::Error> {
+// Writes into `upload_dir` and returns the public path, `uploads/<name>`,
+// which is where main.rs serves that directory.
+pub async fn save_video(mut payload: Multipart, upload_dir: &str) -> Result<String, AppError> {
     let mut file_path = String::new();
+    let mut disk_path = PathBuf::new();
 
     while let Some(item) = payload.next().await {
         let mut field = item?;
@@ -14,22 +19,22 @@ pub async fn save_video(mut payload: Multipart) -> Result<String, actix_web::Err
 
         let sanitized_filename = sanitize_filename::sanitize(filename);
         let unique_name = format!("{}-{}", Uuid::new_v4(), sanitized_filename);
-        let path = format!("uploads/{}", unique_name);
-        file_path = path.clone();
+        let path = Path::new(upload_dir).join(&unique_name);
+        file_path = format!("uploads/{}", unique_name);
+        disk_path = path.clone();
 
-        let mut f = fs::File::create(&path).await.map_err(|e| actix_web::error::ErrorInternalServerError(e))?;
+        let mut f = fs::File::create(&path).await?;
 
         while let Some(chunk) = field.next().await {
             let data = chunk?;
-            f.write_all(&data).await.map_err(|e| actix_web::error::ErrorInternalServerError(e))?;
+            f.write_all(&data).await?;
         }
     }
 
     // Trigger mocked multi-resolution processing
     // In production, you would run this in a separate thread or job queue
-    let process_path = file_path.clone();
     tokio::spawn(async move {
-        mock_process_video_multi_res(&process_path).await;
+        mock_process_video_multi_res(&disk_path.display().to_string()).await;
     });
 
     Ok(file_path)
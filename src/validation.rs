@@ -0,0 +1,113 @@
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
+use crate::config::AuthConfig;
+
+pub const USERNAME_MIN_LEN: usize = 3;
+pub const USERNAME_MAX_LEN: usize = 32;
+pub const PASSWORD_MIN_LEN: usize = 10;
+// bcrypt ignores everything after 72 bytes
+pub const PASSWORD_MAX_BYTES: usize = 72;
+
+const RESERVED_USERNAMES: &[&str] = &[
+    "admin", "administrator", "root", "superuser", "system", "support",
+    "moderator", "staff", "api", "null", "anonymous",
+];
+
+// Field name -> every rule that field failed. Serialized as the `fields`
+// object of a 422 response.
+#[derive(Debug, Default, Serialize)]
+pub struct ValidationErrors(BTreeMap<String, Vec<String>>);
+
+impl ValidationErrors {
+    pub fn add(&mut self, field: &str, message: impl Into<String>) {
+        self.0.entry(field.to_string()).or_default().push(message.into());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn fields(&self) -> &BTreeMap<String, Vec<String>> {
+        &self.0
+    }
+}
+
+// Format rules plus the reserved-name check applied to public registration.
+pub fn validate_username(username: &str, errors: &mut ValidationErrors) {
+    validate_username_format(username, errors);
+    if RESERVED_USERNAMES.contains(&username.to_lowercase().as_str()) {
+        errors.add("username", "is reserved");
+    }
+}
+
+// ASCII letters, digits, '.', '_' and '-' only: rules out unicode lookalikes
+// ("аdmin" with a Cyrillic a) and invisible characters.
+pub fn validate_username_format(username: &str, errors: &mut ValidationErrors) {
+    let len = username.chars().count();
+    if !(USERNAME_MIN_LEN..=USERNAME_MAX_LEN).contains(&len) {
+        errors.add("username", format!("must be between {} and {} characters", USERNAME_MIN_LEN, USERNAME_MAX_LEN));
+    }
+    if !username.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-')) {
+        errors.add("username", "may only contain letters, digits, '.', '_' and '-'");
+    }
+    if username.starts_with(['.', '_', '-']) {
+        errors.add("username", "must start with a letter or digit");
+    }
+}
+
+// Deliberately loose: the verification email is the real test.
+pub fn validate_email(email: &str, errors: &mut ValidationErrors) {
+    let valid = email.len() <= 254
+        && !email.chars().any(|c| c.is_whitespace())
+        && email.split_once('@').is_some_and(|(local, domain)| {
+            !local.is_empty() && !domain.contains('@') && domain.contains('.')
+                && !domain.starts_with('.') && !domain.ends_with('.')
+        });
+    if !valid {
+        errors.add("email", "is not a valid email address");
+    }
+}
+
+pub fn validate_password(password: &str, username: &str, errors: &mut ValidationErrors) {
+    validate_password_field("password", password, username, errors);
+}
+
+// Same rules for requests that name the field differently (e.g. `new_password`).
+pub fn validate_password_field(field: &str, password: &str, username: &str, errors: &mut ValidationErrors) {
+    if password.chars().count() < PASSWORD_MIN_LEN {
+        errors.add(field, format!("must be at least {} characters", PASSWORD_MIN_LEN));
+    }
+    if password.len() > PASSWORD_MAX_BYTES {
+        errors.add(field, format!("must be at most {} bytes", PASSWORD_MAX_BYTES));
+    }
+    if !username.is_empty() && password.to_lowercase().contains(&username.to_lowercase()) {
+        errors.add(field, "must not contain the username");
+    }
+}
+
+// Looks the password up in a local copy of a breached-password corpus laid out
+// like the Pwned Passwords range API: auth.breached_passwords_dir holds one file
+// per 5-hex-char SHA-1 prefix (e.g. `21BD1`) with `SUFFIX:COUNT` lines. Only the
+// prefix file is read, and the check is skipped when the directory is not set.
+pub async fn is_breached_password(auth: &AuthConfig, password: &str) -> bool {
+    let dir = match auth.breached_passwords_dir.as_deref() {
+        Some(d) => d,
+        None => return false,
+    };
+
+    let digest = format!("{:X}", Sha1::digest(password.as_bytes()));
+    let (prefix, suffix) = digest.split_at(5);
+
+    let contents = match tokio::fs::read_to_string(format!("{}/{}", dir, prefix)).await {
+        Ok(c) => c,
+        Err(_) => match tokio::fs::read_to_string(format!("{}/{}.txt", dir, prefix)).await {
+            Ok(c) => c,
+            Err(_) => return false,
+        },
+    };
+
+    contents.lines().any(|line| {
+        line.split(':').next().is_some_and(|s| s.trim().eq_ignore_ascii_case(suffix))
+    })
+}